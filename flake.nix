@@ -78,10 +78,11 @@
 
             config = lib.mkOption {
               type = lib.types.submodule {
-                options.facilities = lib.mkOption {
-                  type = with lib.types; listOf str;
+                options.metro = lib.mkOption {
+                  type = with lib.types; either str (listOf str);
                   description = lib.mdDoc ''
-                    The facilities the instances are allowed to be created in.
+                    The metro, or metros in order of preference, the instances are
+                    allowed to be created in.
                   '';
                 };
                 options.tags = lib.mkOption {
@@ -118,7 +119,8 @@
                   ${self.packages.${pkgs.stdenv.system}.default}/bin/scale \
                     ${lib.optionalString (cfg.hydraRoot != null) "--hydra-root ${cfg.hydraRoot}"} \
                     ${lib.optionalString (cfg.prometheusRoot != null) "--prometheus-root ${cfg.prometheusRoot}"} \
-                    --config-file ${configFileFormat.generate "config.json" cfg.config}
+                    --config-file ${configFileFormat.generate "config.json" cfg.config} \
+                    scale
                 '';
               };
             };
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::device::Device;
+use crate::outcome;
+
+/// Where `--audit-log` appends a line for every change to the fleet, if anywhere.
+static AUDIT_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Why each device is about to be destroyed, and how many jobs it was running when that was
+/// decided, by device ID.
+static DECISIONS: Mutex<BTreeMap<String, Decision>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Decision {
+    reason: Option<String>,
+    jobs: Option<u64>,
+}
+
+/// One change to the fleet.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Entry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub action: &'static str,
+    pub device_id: String,
+    pub hostname: String,
+    pub plan: String,
+    pub metro: Option<String>,
+    pub reason: Option<String>,
+    /// How many jobs the device was running when it was decided to destroy it.
+    pub jobs: Option<u64>,
+}
+
+/// Append every change to the fleet to `path`, as a line of JSON, from now on.
+pub fn log_to(path: &Path) -> Result<()> {
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+    AUDIT_LOG
+        .set(Mutex::new(file))
+        .map_err(|_| eyre!("the audit log was already set"))
+}
+
+/// Note why the device is about to be destroyed, for its entry.
+pub fn explain(device_id: &str, reason: &str) {
+    DECISIONS
+        .lock()
+        .unwrap()
+        .entry(device_id.to_string())
+        .or_default()
+        .reason = Some(reason.to_string());
+}
+
+/// Note how many jobs the device was running when it was decided to destroy it.
+pub fn observe_jobs(device_id: &str, jobs: Option<u64>) {
+    DECISIONS
+        .lock()
+        .unwrap()
+        .entry(device_id.to_string())
+        .or_default()
+        .jobs = jobs;
+}
+
+/// Record that `action` was done to the device, along with what [`explain`] and
+/// [`observe_jobs`] said about it. The entry is synced to disk before returning, and failing to
+/// write it fails the run partially rather than undoing the action.
+pub fn record(action: &'static str, device: &Device) {
+    let decision = DECISIONS
+        .lock()
+        .unwrap()
+        .remove(&device.id)
+        .unwrap_or_default();
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+
+    let entry = Entry {
+        timestamp: OffsetDateTime::now_utc(),
+        action,
+        device_id: device.id.clone(),
+        hostname: device.hostname.clone(),
+        plan: device.plan.class.clone(),
+        metro: device.metro.as_ref().map(|metro| metro.code.clone()),
+        reason: decision.reason,
+        jobs: decision.jobs,
+    };
+    let mut file = log.lock().unwrap();
+    if let Err(e) = write_entry(&mut *file, &entry).and_then(|()| Ok(file.sync_data()?)) {
+        outcome::warn(format!(
+            "failed to write the audit log entry for {} {}: {:?}",
+            action, device.id, e
+        ));
+    }
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    out.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod audit_tests {
+    use super::*;
+
+    #[test]
+    fn test_decisions() {
+        explain("audit-test", "cycling");
+        observe_jobs("audit-test", Some(0));
+        explain("audit-test", "urgent");
+        assert_eq!(
+            DECISIONS.lock().unwrap().remove("audit-test"),
+            Some(Decision {
+                reason: Some("urgent".into()),
+                jobs: Some(0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_write_entry() {
+        let entry = Entry {
+            timestamp: OffsetDateTime::from_unix_timestamp(1700000000).unwrap(),
+            action: "destroy",
+            device_id: "d".into(),
+            hostname: "c3-small-x86-da-abcdef".into(),
+            plan: "c3.small.x86".into(),
+            metro: Some("da".into()),
+            reason: Some("cycling".into()),
+            jobs: Some(0),
+        };
+
+        let mut out = vec![];
+        write_entry(&mut out, &entry).unwrap();
+        write_entry(&mut out, &entry).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(lines[0]).unwrap(),
+            serde_json::json!({
+                "timestamp": "2023-11-14T22:13:20Z",
+                "action": "destroy",
+                "device_id": "d",
+                "hostname": "c3-small-x86-da-abcdef",
+                "plan": "c3.small.x86",
+                "metro": "da",
+                "reason": "cycling",
+                "jobs": 0,
+            })
+        );
+    }
+}
@@ -1,13 +1,164 @@
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
 
 use eyre::{eyre, Result, WrapErr};
 use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use time::format_description::well_known::{Iso8601, Rfc3339};
 use time::OffsetDateTime;
 
-use crate::hardware::HardwarePlan;
+use crate::hardware::{self, BillingCycle, DesiredDevice};
+use crate::http::{
+    check_error_envelope, redact, send, send_checked_json, send_json, Fetcher, HttpFetcher,
+};
+use crate::provider::{CloudProvider, JobCounter};
+use crate::{audit, outcome};
 
-#[derive(Deserialize, Debug)]
+pub const DEFAULT_API_BASE: &str = "https://api.equinix.com/metal/v1";
+
+/// Where the Equinix Metal API is, if not at [`DEFAULT_API_BASE`].
+static API_BASE: OnceLock<String> = OnceLock::new();
+
+/// Send every Equinix Metal API request to `base` from now on, like for a mock server or
+/// another region's API host.
+pub fn use_api_base(base: &str) -> Result<()> {
+    reqwest::Url::parse(base).wrap_err_with(|| format!("failed to parse {base:?}"))?;
+    API_BASE
+        .set(base.trim_end_matches('/').to_string())
+        .map_err(|_| eyre!("the Equinix Metal API base was already set"))
+}
+
+fn api_base() -> &'static str {
+    API_BASE.get().map_or(DEFAULT_API_BASE, String::as_str)
+}
+
+/// The Equinix Metal projects that devices are managed in.
+pub struct EquinixMetal {
+    pub http_client: reqwest::Client,
+    pub auth_token: String,
+    /// Devices are created in the first, unless their category names another.
+    pub project_ids: Vec<String>,
+    /// See [`render_hostname`].
+    pub hostname_template: String,
+    /// How long after creation Equinix should destroy a device by itself, in case we never do.
+    pub device_ttl: Option<time::Duration>,
+}
+
+impl EquinixMetal {
+    /// The project to create the desired device in.
+    pub fn project_for<'a>(&'a self, desired: &'a DesiredDevice) -> &'a str {
+        desired
+            .project_id
+            .as_deref()
+            .or(self.project_ids.first().map(String::as_str))
+            .unwrap_or_default()
+    }
+}
+
+impl CloudProvider for EquinixMetal {
+    async fn list_devices(&self, tag: Option<&str>) -> Result<Vec<Device>> {
+        let mut devices = vec![];
+        for project_id in self.project_ids.iter() {
+            devices.extend(
+                get_all_devices(&self.http_client, &self.auth_token, project_id, tag).await?,
+            );
+        }
+
+        Ok(devices)
+    }
+
+    async fn get_device(&self, device_id: &str) -> Result<Device> {
+        get_device(&self.http_client, &self.auth_token, device_id).await
+    }
+
+    async fn create_device(&self, desired: DesiredDevice) -> Result<Device> {
+        let project_id = self.project_for(&desired).to_string();
+        let reason = if desired.on_demand {
+            "on-demand fallback"
+        } else {
+            "scale-up"
+        };
+        create_device(
+            &self.http_client,
+            &self.auth_token,
+            &project_id,
+            &self.hostname_template,
+            self.device_ttl,
+            desired,
+        )
+        .await
+        .inspect(|device| {
+            outcome::changed();
+            audit::explain(&device.id, reason);
+            audit::record("create", device);
+        })
+    }
+
+    async fn request_devices(&self, desired: DesiredDevice, count: usize) -> Result<Vec<Device>> {
+        let project_id = self.project_for(&desired).to_string();
+        let request = create_spot_market_request(
+            &self.http_client,
+            &self.auth_token,
+            &project_id,
+            &self.hostname_template,
+            self.device_ttl,
+            desired,
+            count,
+        )
+        .await?;
+        outcome::changed();
+        println!("Created spot market request {}", request.id);
+        for device in request.devices.iter() {
+            audit::explain(&device.id, "scale-up");
+            audit::record("create", device);
+        }
+
+        Ok(request.devices)
+    }
+
+    async fn destroy_device(&self, device: &Device) -> Result<()> {
+        // A device that was already gone wasn't destroyed by this run.
+        if destroy_device(&self.http_client, &self.auth_token, device).await? {
+            outcome::changed();
+            audit::record("destroy", device);
+        }
+
+        Ok(())
+    }
+
+    async fn set_tags(&self, device: &Device, tags: Vec<String>) -> Result<Device> {
+        add_device_tag(&self.http_client, &self.auth_token, device, tags)
+            .await
+            .inspect(|_| outcome::changed())
+            .map(|updated| device.with_project_of(updated))
+    }
+
+    async fn reinstall_device(&self, device: &Device, desired: &DesiredDevice) -> Result<Device> {
+        reinstall_device(&self.http_client, &self.auth_token, device, desired)
+            .await
+            .inspect(|_| {
+                outcome::changed();
+                audit::explain(&device.id, "netboot changed");
+                audit::record("reinstall", device);
+            })
+            .map(|updated| device.with_project_of(updated))
+    }
+
+    async fn reboot_device(&self, device: &Device) -> Result<()> {
+        reboot_device(&self.http_client, &self.auth_token, device)
+            .await
+            .inspect(|_| {
+                outcome::changed();
+                audit::explain(&device.id, "idle");
+                audit::record("reboot", device);
+            })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
 pub struct Plan {
     pub class: String,
 }
@@ -19,7 +170,7 @@ pub enum DeviceType {
     OnDemand,
 }
 
-#[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, Hash, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum DeviceState {
     Provisioning,
@@ -27,7 +178,7 @@ pub enum DeviceState {
     Queued,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct Device {
     pub hostname: String,
     pub id: String,
@@ -37,59 +188,655 @@ pub struct Device {
     pub device_type: DeviceType,
     pub state: DeviceState,
     pub ipxe_script_url: Option<String>,
+    /// Whether Equinix may reclaim the device, which is what the listings go by.
     #[serde(default)]
     pub spot_instance: bool,
+    /// What a spot instance bids, in dollars per hour.
+    #[serde(default)]
+    pub spot_price_max: Option<f64>,
     pub plan: Plan,
+    #[serde(default)]
+    pub metro: Option<Metro>,
     pub tags: Vec<String>,
+    /// The project the device was listed or created in, which the API doesn't say directly.
+    #[serde(skip)]
+    pub project_id: String,
 }
 
 #[derive(Serialize, Debug)]
 struct CreateDeviceRequest {
     always_pxe: bool,
-    facility: Vec<String>,
+    metro: String,
     hostname: String,
-    ipxe_script_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipxe_script_url: Option<String>,
     operating_system: String,
     plan: String,
     spot_instance: bool,
-    spot_price_max: f64,
+    billing_cycle: BillingCycle,
+    /// Only sent for spot instances, since a bid means nothing to an on-demand device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spot_price_max: Option<f64>,
     tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    userdata: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    project_ssh_keys: Vec<String>,
+    /// When Equinix should destroy the device, as RFC 3339.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    termination_time: Option<String>,
+}
+
+pub const CREATED_AT_TAG_PREFIX: &str = "hydra-created-at=";
+pub const SCALER_VERSION_TAG_PREFIX: &str = "hydra-scaler-version=";
+/// Marks on-demand devices we created because spot devices couldn't be, which we're allowed to
+/// manage even though they aren't spot instances.
+pub const ON_DEMAND_TAG: &str = "hydra-on-demand";
+/// Marks devices that `--reboot-before-destroy` has already rebooted once.
+pub const REBOOTED_TAG: &str = "hydra-rebooted";
+
+/// Which devices are ours, judging by their tags.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TagMatch {
+    /// Devices with all of our tags, whatever else they're tagged with.
+    #[default]
+    Superset,
+    /// Devices with all of our tags, and no tags but the config's, the drain tag, and the ones
+    /// added at creation.
+    Exact,
+    /// Devices with any of our tags.
+    AnyOf,
+}
+
+impl TagMatch {
+    /// Whether the device is ours, given the tags every managed device has, and the other tags
+    /// the config or the drain tag might put on one.
+    pub fn matches(self, device: &Device, tags: &[String], known_tags: &BTreeSet<String>) -> bool {
+        let has = |tag: &String| device.tags.contains(tag);
+        match self {
+            TagMatch::Superset => tags.iter().all(has),
+            TagMatch::AnyOf => tags.iter().any(has),
+            TagMatch::Exact => {
+                tags.iter().all(has)
+                    && device.tags.iter().all(|tag| {
+                        tags.contains(tag) || known_tags.contains(tag) || is_creation_tag(tag)
+                    })
+            }
+        }
+    }
+}
+
+/// Whether the tag is one we add to devices ourselves, rather than one from the config.
+fn is_creation_tag(tag: &str) -> bool {
+    [
+        hardware::SYSTEM_TAG_PREFIX,
+        hardware::SIZE_TAG_PREFIX,
+        hardware::CATEGORY_HASH_TAG_PREFIX,
+        CREATED_AT_TAG_PREFIX,
+        SCALER_VERSION_TAG_PREFIX,
+    ]
+    .iter()
+    .any(|prefix| tag.starts_with(prefix))
+        || tag == ON_DEMAND_TAG
+        || tag == REBOOTED_TAG
+}
+
+/// The tags every device we create gets: its category, and when and by what it was created.
+fn creation_tags(desired: &DesiredDevice, now: OffsetDateTime) -> Vec<String> {
+    let mut tags = desired.tags.clone();
+    tags.extend(desired.category_tags());
+    tags.push(format!(
+        "{CREATED_AT_TAG_PREFIX}{}",
+        now.format(&Iso8601::DEFAULT)
+            .expect("formatting a timestamp as ISO 8601 can't fail")
+    ));
+    tags.push(format!(
+        "{SCALER_VERSION_TAG_PREFIX}{}",
+        env!("CARGO_PKG_VERSION")
+    ));
+    if desired.on_demand {
+        tags.push(ON_DEMAND_TAG.to_string());
+    }
+
+    tags
+}
+
+impl Device {
+    /// `updated`, which the API returned for this device, in this device's project.
+    fn with_project_of(&self, updated: Device) -> Device {
+        Device {
+            project_id: self.project_id.clone(),
+            ..updated
+        }
+    }
+
+    /// When our creation tag says this device was created, if it has one.
+    pub fn tagged_created_at(&self) -> Option<OffsetDateTime> {
+        self.tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(CREATED_AT_TAG_PREFIX))
+            .and_then(|created_at| OffsetDateTime::parse(created_at, &Iso8601::DEFAULT).ok())
+    }
+
+    /// What the device's plan currently costs as a spot instance in its metro, if it's a spot
+    /// instance and Equinix reports a price.
+    pub fn spot_price(&self, prices: &SpotPrices) -> Option<f64> {
+        if self.device_type != DeviceType::SpotInstance {
+            return None;
+        }
+
+        prices
+            .get(&self.metro.as_ref()?.code)?
+            .get(&self.plan.class)
+            .copied()
+    }
+}
+
+/// Fill in a hostname template's `{plan}`, `{metro}`, `{system}`, `{timestamp}`, and
+/// `{short_random}` placeholders, then make the result usable as a DNS label.
+pub fn render_hostname(
+    template: &str,
+    desired: &DesiredDevice,
+    metro: &str,
+    now: OffsetDateTime,
+) -> String {
+    let short_random = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let hostname = template
+        .replace("{plan}", &desired.plan.plan)
+        .replace("{metro}", metro)
+        .replace("{system}", &desired.system.0)
+        .replace("{timestamp}", &now.unix_timestamp().to_string())
+        .replace("{short_random}", &short_random[..6]);
+
+    let hostname: String = hostname
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+
+    hostname.trim_matches('-').to_string()
+}
+
+/// A piece of a hostname template, for telling whether a hostname could have been rendered from
+/// it.
+#[derive(Debug, PartialEq)]
+enum HostnamePart {
+    Literal(String),
+    /// `{short_random}`.
+    Random,
+    /// `{timestamp}`.
+    Timestamp,
+    /// `{plan}` or `{metro}`, as one of the plans or metros rendered.
+    OneOf(Vec<String>),
+    /// `{system}`.
+    Word,
+}
+
+/// `name` as [`render_hostname`] puts it in a hostname.
+fn hostname_word(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+fn hostname_parts(
+    template: &str,
+    plans: &BTreeSet<String>,
+    metros: &BTreeSet<String>,
+) -> Vec<HostnamePart> {
+    let one_of = |names: &BTreeSet<String>| {
+        HostnamePart::OneOf(names.iter().map(|name| hostname_word(name)).collect())
+    };
+    let mut parts = vec![];
+    let mut literal = String::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        let placeholder = [
+            ("{short_random}", HostnamePart::Random),
+            ("{timestamp}", HostnamePart::Timestamp),
+            ("{plan}", one_of(plans)),
+            ("{metro}", one_of(metros)),
+            ("{system}", HostnamePart::Word),
+        ]
+        .into_iter()
+        .find(|(name, _)| rest.starts_with(name));
+        match placeholder {
+            Some((name, part)) => {
+                if !literal.is_empty() {
+                    parts.push(HostnamePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                let c = rest.chars().next().unwrap();
+                literal.push_str(&hostname_word(&c.to_string()));
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(HostnamePart::Literal(literal));
+    }
+
+    parts
+}
+
+fn matches_hostname_parts(parts: &[HostnamePart], hostname: &str) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return hostname.is_empty();
+    };
+
+    let allowed = |c: char| match part {
+        HostnamePart::Random => c.is_ascii_hexdigit() && !c.is_ascii_uppercase(),
+        HostnamePart::Timestamp => c.is_ascii_digit(),
+        HostnamePart::Word => c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-',
+        HostnamePart::Literal(_) | HostnamePart::OneOf(_) => false,
+    };
+    match part {
+        HostnamePart::Literal(literal) => hostname
+            .strip_prefix(literal.as_str())
+            .is_some_and(|hostname| matches_hostname_parts(rest, hostname)),
+        HostnamePart::OneOf(words) => words.iter().any(|word| {
+            hostname
+                .strip_prefix(word.as_str())
+                .is_some_and(|hostname| matches_hostname_parts(rest, hostname))
+        }),
+        HostnamePart::Random => hostname.get(..6).is_some_and(|random| {
+            random.chars().all(allowed) && matches_hostname_parts(rest, &hostname[6..])
+        }),
+        HostnamePart::Timestamp | HostnamePart::Word => hostname
+            .char_indices()
+            .take_while(|(_, c)| allowed(*c))
+            .any(|(i, c)| matches_hostname_parts(rest, &hostname[i + c.len_utf8()..])),
+    }
+}
+
+/// Whether [`render_hostname`] could have made `hostname` from `template`, so that devices a
+/// crashed run created but didn't tag can be recognised. `{plan}` and `{metro}` only match one of
+/// `plans` and `metros`. Hostnames cut short at 63 characters never match.
+pub fn matches_hostname_template(
+    template: &str,
+    hostname: &str,
+    plans: &BTreeSet<String>,
+    metros: &BTreeSet<String>,
+) -> bool {
+    let mut parts = hostname_parts(template, plans, metros);
+    if let Some(HostnamePart::Literal(literal)) = parts.first_mut() {
+        *literal = literal.trim_start_matches('-').to_string();
+    }
+    if let Some(HostnamePart::Literal(literal)) = parts.last_mut() {
+        *literal = literal.trim_end_matches('-').to_string();
+    }
+
+    matches_hostname_parts(&parts, hostname)
+}
+
+impl CreateDeviceRequest {
+    fn new(desired: &DesiredDevice, metro: &str, hostname: String, now: OffsetDateTime) -> Self {
+        let tags = creation_tags(desired, now);
+
+        CreateDeviceRequest {
+            always_pxe: desired.plan.always_pxe,
+            hostname,
+            ipxe_script_url: desired.plan.ipxe_script_url(),
+            operating_system: desired.plan.operating_system.clone(),
+            plan: desired.plan.plan.clone(),
+            spot_instance: !desired.on_demand,
+            billing_cycle: if desired.on_demand {
+                desired.billing_cycle
+            } else {
+                BillingCycle::Hourly
+            },
+            spot_price_max: (!desired.on_demand).then_some(desired.plan.bid),
+            tags,
+            metro: metro.to_string(),
+            userdata: desired.plan.userdata.clone(),
+            project_ssh_keys: desired.ssh_key_ids.clone(),
+            termination_time: None,
+        }
+    }
+}
+
+/// Why Equinix won't create a device, as opposed to the request going wrong.
+#[derive(Debug, PartialEq)]
+pub enum Unfillable {
+    /// None of the metros have the plan available.
+    NoCapacity,
+    /// A spot device's bid was too low, with what Equinix said about it.
+    BidRejected(String),
 }
 
+impl std::fmt::Display for Unfillable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unfillable::NoCapacity => write!(f, "no capacity in any metro"),
+            Unfillable::BidRejected(errors) => write!(f, "the bid was rejected: {errors}"),
+        }
+    }
+}
+
+impl std::error::Error for Unfillable {}
+
+/// Whether the API's errors for creating a spot device say its bid was too low.
+fn bid_rejection(raw: &serde_json::Value) -> Option<Unfillable> {
+    let errors = raw.get("errors")?.to_string();
+    let lowercase = errors.to_lowercase();
+    (lowercase.contains("bid") || lowercase.contains("price"))
+        .then_some(Unfillable::BidRejected(errors))
+}
+
+/// Create the device in the first of its metros that accepts it. Fails with [`Unfillable`] if
+/// none would for lack of capacity or a high enough bid.
 pub async fn create_device(
     http_client: &reqwest::Client,
     equinix_auth_token: &str,
     equinix_project_id: &str,
-    plan: HardwarePlan,
-    tags: &[String],
-    facilities: &[String],
+    hostname_template: &str,
+    device_ttl: Option<time::Duration>,
+    desired: DesiredDevice,
 ) -> Result<Device> {
-    let raw = http_client
-        .post(format!(
-            "https://api.equinix.com/metal/v1/projects/{}/devices",
-            equinix_project_id
-        ))
-        .json(&CreateDeviceRequest {
-            always_pxe: true,
-            hostname: plan.plan.clone(),
-            ipxe_script_url: plan.netboot_url,
-            operating_system: "custom_ipxe".into(),
-            plan: plan.plan,
-            spot_instance: true,
-            spot_price_max: plan.bid,
-            tags: tags.to_vec(),
-            facility: facilities.to_vec(),
-        })
-        .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/json")
-        .header("X-Auth-Token", equinix_auth_token)
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
+    let mut last_err = eyre!("no metros configured");
+    for metro in desired.metros.iter() {
+        if !has_capacity(
+            http_client,
+            equinix_auth_token,
+            &desired.plan.plan,
+            metro,
+            1,
+        )
+        .await
+        {
+            println!(
+                "Skipping {} in {}: Equinix reports no capacity",
+                desired.plan.plan, metro
+            );
+            last_err = eyre!(Unfillable::NoCapacity);
+            continue;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let hostname = render_hostname(hostname_template, &desired, metro, now);
+        let request = CreateDeviceRequest {
+            termination_time: termination_time(device_ttl, now)?,
+            ..CreateDeviceRequest::new(&desired, metro, hostname, now)
+        };
+        let raw = match send_json(
+            http_client
+                .post(format!(
+                    "{}/projects/{}/devices",
+                    api_base(),
+                    equinix_project_id
+                ))
+                .json(&request)
+                .header(ACCEPT, "application/json")
+                .header(CONTENT_TYPE, "application/json")
+                .header("X-Auth-Token", equinix_auth_token),
+        )
+        .await
+        {
+            Ok(raw) => raw,
+            Err(e) => {
+                println!(
+                    "WARNING: couldn't create {} in {}, trying the next metro: {:?}",
+                    desired.plan.plan, metro, e
+                );
+                last_err = e;
+                continue;
+            }
+        };
+
+        match serde_json::from_str::<Device>(&raw.to_string()) {
+            Ok(device) => {
+                return Ok(Device {
+                    project_id: equinix_project_id.to_string(),
+                    ..device
+                })
+            }
+            Err(e) => {
+                println!(
+                    "{}",
+                    redact(&format!(
+                        "WARNING: couldn't create {} in {}, trying the next metro: {:#?}",
+                        desired.plan.plan, metro, raw
+                    ))
+                );
+                if let Some(rejection) = bid_rejection(&raw).filter(|_| !desired.on_demand) {
+                    last_err = eyre!(rejection);
+                    continue;
+                }
+                last_err = eyre!(e).wrap_err(redact(&format!(
+                    "failed to parse json, here's the raw content: {:#?}",
+                    raw
+                )));
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+/// When a device created at `now` should be destroyed by Equinix, for `--device-ttl-hours`.
+fn termination_time(
+    device_ttl: Option<time::Duration>,
+    now: OffsetDateTime,
+) -> Result<Option<String>> {
+    Ok(device_ttl
+        .map(|ttl| (now + ttl).format(&Rfc3339))
+        .transpose()?)
+}
+
+#[derive(Serialize, Debug)]
+struct SpotMarketRequestInstanceParameters {
+    always_pxe: bool,
+    hostname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipxe_script_url: Option<String>,
+    operating_system: String,
+    plan: String,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    userdata: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    project_ssh_keys: Vec<String>,
+    /// When Equinix should destroy the devices, as RFC 3339.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    termination_time: Option<String>,
+}
+
+impl SpotMarketRequestInstanceParameters {
+    fn new(
+        desired: &DesiredDevice,
+        hostname: String,
+        tags: Vec<String>,
+        termination_time: Option<String>,
+    ) -> Self {
+        let plan = &desired.plan;
+        SpotMarketRequestInstanceParameters {
+            always_pxe: plan.always_pxe,
+            hostname,
+            ipxe_script_url: plan.ipxe_script_url(),
+            operating_system: plan.operating_system.clone(),
+            plan: plan.plan.clone(),
+            tags,
+            userdata: plan.userdata.clone(),
+            project_ssh_keys: desired.ssh_key_ids.clone(),
+            termination_time,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct CreateSpotMarketRequest {
+    devices_max: usize,
+    devices_min: usize,
+    metro: String,
+    instance_parameters: SpotMarketRequestInstanceParameters,
+    max_bid_price: f64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SpotMarketRequestInstance {
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
 
-    serde_json::from_str(&raw.to_string())
-        .wrap_err_with(|| format!("failed to parse json, here's the raw content: {:#?}", raw))
+#[derive(Deserialize, Debug)]
+pub struct SpotMarketRequest {
+    pub id: String,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub instance_parameters: SpotMarketRequestInstance,
+}
+
+/// Ask Equinix for `count` devices of the desired plan in a single spot market request, in the
+/// first of its metros that accepts it. Equinix names them all alike, so every device but the
+/// first is renamed afterwards, and shares the first's hostname until then.
+pub async fn create_spot_market_request(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    equinix_project_id: &str,
+    hostname_template: &str,
+    device_ttl: Option<time::Duration>,
+    desired: DesiredDevice,
+    count: usize,
+) -> Result<SpotMarketRequest> {
+    let now = OffsetDateTime::now_utc();
+    let tags = creation_tags(&desired, now);
+
+    let mut last_err = eyre!("no metros configured");
+    for metro in desired.metros.iter() {
+        let plan = &desired.plan;
+        if !has_capacity(http_client, equinix_auth_token, &plan.plan, metro, count).await {
+            println!(
+                "Skipping {}x {} in {}: Equinix reports no capacity",
+                count, plan.plan, metro
+            );
+            last_err = eyre!("no capacity for {}x {} in any metro", count, plan.plan);
+            continue;
+        }
+
+        let hostname = render_hostname(hostname_template, &desired, metro, now);
+        let raw = match send_json(
+            http_client
+                .post(format!(
+                    "{}/projects/{}/spot-market-requests",
+                    api_base(),
+                    equinix_project_id
+                ))
+                .query(&[("include", "devices")])
+                .json(&CreateSpotMarketRequest {
+                    devices_max: count,
+                    devices_min: count,
+                    metro: metro.clone(),
+                    instance_parameters: SpotMarketRequestInstanceParameters::new(
+                        &desired,
+                        hostname,
+                        tags.clone(),
+                        termination_time(device_ttl, now)?,
+                    ),
+                    max_bid_price: plan.bid,
+                })
+                .header(ACCEPT, "application/json")
+                .header(CONTENT_TYPE, "application/json")
+                .header("X-Auth-Token", equinix_auth_token),
+        )
+        .await
+        {
+            Ok(raw) => raw,
+            Err(e) => {
+                println!(
+                    "WARNING: couldn't request {} in {}, trying the next metro: {:?}",
+                    plan.plan, metro, e
+                );
+                last_err = e;
+                continue;
+            }
+        };
+
+        match serde_json::from_str::<SpotMarketRequest>(&raw.to_string()) {
+            Ok(mut request) => {
+                // Every device of a request gets its hostname, so all but the first are renamed
+                // to keep them distinguishable.
+                for (idx, device) in request.devices.iter_mut().enumerate() {
+                    device.project_id = equinix_project_id.to_string();
+                    if idx == 0 {
+                        continue;
+                    }
+                    let hostname = render_hostname(hostname_template, &desired, metro, now);
+                    match rename_device(http_client, equinix_auth_token, device, &hostname).await {
+                        Ok(renamed) => *device = device.with_project_of(renamed),
+                        Err(e) => {
+                            outcome::warn(format!(
+                                "failed to rename {} to {}: {:?}",
+                                device.id, hostname, e
+                            ));
+                        }
+                    }
+                }
+                return Ok(request);
+            }
+            Err(e) => {
+                println!(
+                    "{}",
+                    redact(&format!(
+                        "WARNING: couldn't request {} in {}, trying the next metro: {:#?}",
+                        plan.plan, metro, raw
+                    ))
+                );
+                last_err = eyre!(e).wrap_err(redact(&format!(
+                    "failed to parse json, here's the raw content: {:#?}",
+                    raw
+                )));
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotMarketRequestList {
+    spot_market_requests: Vec<SpotMarketRequest>,
+}
+
+/// The project's spot market requests, along with the devices they spawned.
+pub async fn get_spot_market_requests(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    equinix_project_id: &str,
+) -> Result<Vec<SpotMarketRequest>> {
+    let raw = send_json(
+        http_client
+            .get(format!(
+                "{}/projects/{}/spot-market-requests",
+                api_base(),
+                equinix_project_id
+            ))
+            .query(&[("include", "devices")])
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    let requests: SpotMarketRequestList =
+        serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+            redact(&format!(
+                "failed to parse json, here's the raw content: {:#?}",
+                raw
+            ))
+        })?;
+
+    Ok(requests.spot_market_requests)
 }
 
 pub async fn add_device_tag(
@@ -98,94 +845,418 @@ pub async fn add_device_tag(
     device: &Device,
     tags: Vec<String>,
 ) -> Result<Device> {
-    let raw = http_client
-        .put(format!(
-            "https://api.equinix.com/metal/v1/devices/{}",
-            device.id
+    let raw = send_json(
+        http_client
+            .put(format!("{}/devices/{}", api_base(), device.id))
+            .json(&HashMap::from([("tags", tags)]))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
         ))
-        .json(&HashMap::from([("tags", tags)]))
-        .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/json")
-        .header("X-Auth-Token", equinix_auth_token)
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
+    })
+}
 
-    serde_json::from_str(&raw.to_string())
-        .wrap_err_with(|| format!("failed to parse json, here's the raw content: {:#?}", raw))
+pub async fn rename_device(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    device: &Device,
+    hostname: &str,
+) -> Result<Device> {
+    let raw = send_json(
+        http_client
+            .put(format!("{}/devices/{}", api_base(), device.id))
+            .json(&HashMap::from([("hostname", hostname)]))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
+        ))
+    })
 }
 
-pub async fn destroy_device(
+/// Point the device at `desired`'s netboot URL, retag it with `desired`'s category, and reinstall
+/// it, keeping its reservation.
+pub async fn reinstall_device(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    device: &Device,
+    desired: &DesiredDevice,
+) -> Result<Device> {
+    let mut tags: Vec<String> = device
+        .tags
+        .iter()
+        .filter(|tag| !tag.starts_with(crate::hardware::CATEGORY_HASH_TAG_PREFIX))
+        .cloned()
+        .collect();
+    tags.push(format!(
+        "{}{}",
+        crate::hardware::CATEGORY_HASH_TAG_PREFIX,
+        desired.category_hash()
+    ));
+
+    let raw = send_json(
+        http_client
+            .put(format!("{}/devices/{}", api_base(), device.id))
+            .json(&serde_json::json!({
+                "always_pxe": desired.plan.always_pxe,
+                "ipxe_script_url": desired.plan.ipxe_script_url(),
+                "tags": tags,
+            }))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+    check_error_envelope(&raw)?;
+    let updated: Device = serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
+        ))
+    })?;
+
+    let raw = send_json(
+        http_client
+            .post(format!("{}/devices/{}/actions", api_base(), device.id))
+            .json(&serde_json::json!({
+                "type": "reinstall",
+                "operating_system": desired.plan.operating_system,
+            }))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+    check_error_envelope(&raw)?;
+
+    Ok(updated)
+}
+
+/// Power cycle the device.
+pub async fn reboot_device(
     http_client: &reqwest::Client,
     equinix_auth_token: &str,
     device: &Device,
 ) -> Result<()> {
-    let raw = http_client
-        .delete(format!(
-            "https://api.equinix.com/metal/v1/devices/{}",
-            device.id
+    let raw = send_json(
+        http_client
+            .post(format!("{}/devices/{}/actions", api_base(), device.id))
+            .json(&serde_json::json!({ "type": "reboot" }))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+    check_error_envelope(&raw)
+}
+
+/// Whether Equinix says `quantity` more of `plan` fit in `metro`.
+pub async fn check_capacity(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    plan: &str,
+    metro: &str,
+    quantity: usize,
+) -> Result<bool> {
+    let raw = send_json(
+        http_client
+            .post(format!("{}/capacity/metros", api_base()))
+            .json(&serde_json::json!({
+                "servers": [{ "metro": metro, "plan": plan, "quantity": quantity }],
+            }))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    parse_capacity(&raw)
+}
+
+fn parse_capacity(raw: &serde_json::Value) -> Result<bool> {
+    check_error_envelope(raw)?;
+
+    let servers = raw["servers"]
+        .as_array()
+        .ok_or_else(|| eyre!("no servers in the capacity response: {}", raw))?;
+    Ok(servers
+        .iter()
+        .all(|server| server["available"].as_bool().unwrap_or(false)))
+}
+
+/// Like [`check_capacity`], but assuming there's capacity if we can't find out, so that a broken
+/// capacity endpoint doesn't stop us from creating devices.
+async fn has_capacity(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    plan: &str,
+    metro: &str,
+    quantity: usize,
+) -> bool {
+    match check_capacity(http_client, equinix_auth_token, plan, metro, quantity).await {
+        Ok(available) => available,
+        Err(e) => {
+            println!(
+                "WARNING: failed to check capacity for {} in {}, trying anyway: {:?}",
+                plan, metro, e
+            );
+            true
+        }
+    }
+}
+
+pub async fn get_device(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    device_id: &str,
+) -> Result<Device> {
+    let raw = send_json(
+        http_client
+            .get(format!("{}/devices/{}", api_base(), device_id))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
         ))
-        .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/json")
-        .header("X-Auth-Token", equinix_auth_token)
-        .send()
-        .await?;
+    })
+}
 
-    if raw.status().is_success() {
-        Ok(())
-    } else {
-        Err(eyre!(raw.json::<serde_json::Value>().await?))
+/// Destroy the device, returning whether it was there to destroy.
+pub async fn destroy_device(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    device: &Device,
+) -> Result<bool> {
+    let (status, body) = send(
+        http_client
+            .delete(format!("{}/devices/{}", api_base(), device.id))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    parse_destroy_response(status, body)
+}
+
+/// Whether a response to destroying a device means it was destroyed, rather than already gone.
+fn parse_destroy_response(status: StatusCode, body: String) -> Result<bool> {
+    if status.is_success() {
+        return Ok(true);
+    }
+
+    if status == StatusCode::NOT_FOUND {
+        // A 404 means the device is already gone, which is what we wanted anyway.
+        return Ok(false);
     }
+
+    if status == StatusCode::UNPROCESSABLE_ENTITY && body.contains("already") {
+        // Equinix reports devices that are already being torn down as a 422.
+        return Ok(false);
+    }
+
+    Err(eyre!(body))
 }
 
+/// Where and how to ask Prometheus how many jobs a device is running.
+#[derive(Clone)]
+pub struct Prometheus {
+    pub http_client: reqwest::Client,
+    pub root: String,
+    pub bearer_token: Option<String>,
+    /// The query, with `{shortid}` and `{hostname}` placeholders for the device.
+    pub query_template: String,
+    pub aggregation: JobAggregation,
+    /// Samples older than this are treated as unknown.
+    pub max_sample_age: Option<time::Duration>,
+}
+
+impl JobCounter for Prometheus {
+    async fn current_jobs(&self, device: &Device) -> Result<Option<u64>> {
+        get_current_jobs(&self.http_client, device, self).await
+    }
+}
+
+impl Prometheus {
+    /// Check that Prometheus answers the job count query, for `device` if there is one, returning
+    /// how many series it answered with.
+    pub async fn check(&self, device: Option<&Device>) -> Result<usize> {
+        let (short_id, hostname) = device
+            .map(|device| (device.short_id.as_str(), device.hostname.as_str()))
+            .unwrap_or_default();
+        let samples = query_job_counts(&self.http_client, self, short_id, hostname).await?;
+
+        Ok(samples.len())
+    }
+
+    /// The job counts of all of `devices`, queried concurrently.
+    pub async fn current_jobs_bulk(&self, devices: &[Device]) -> Result<Vec<Option<u64>>> {
+        let mut queries = tokio::task::JoinSet::new();
+        for (idx, device) in devices.iter().enumerate() {
+            let prometheus = self.clone();
+            let device = device.clone();
+            queries.spawn(async move { (idx, prometheus.current_jobs(&device).await) });
+        }
+
+        let mut jobs = vec![None; devices.len()];
+        while let Some(query) = queries.join_next().await {
+            let (idx, count) = query?;
+            jobs[idx] = count?;
+        }
+
+        Ok(jobs)
+    }
+}
+
+/// How many jobs the device is running, or `None` if Prometheus' samples are too stale to say.
 pub async fn get_current_jobs(
     http_client: &reqwest::Client,
     device: &Device,
-    prometheus_root: &str,
-) -> Result<u64> {
-    let url = format!(
-        "{prometheus_root}/api/v1/query?query=hydra_machine_current_jobs{{host=%22root@{shortid}.packethost.net%22}}",
-        shortid=device.short_id
-    );
-
-    let raw = http_client
+    prometheus: &Prometheus,
+) -> Result<Option<u64>> {
+    let samples =
+        query_job_counts(http_client, prometheus, &device.short_id, &device.hostname).await?;
+
+    if let Some(max_age) = prometheus.max_sample_age {
+        let now = OffsetDateTime::now_utc().unix_timestamp() as f64;
+        if let Some(stale) = samples
+            .iter()
+            .find(|sample| now - sample.timestamp > max_age.as_seconds_f64())
+        {
+            println!(
+                "WARNING: {}'s job count is {:.0}s old, treating it as unknown",
+                device.short_id,
+                now - stale.timestamp
+            );
+            return Ok(None);
+        }
+    }
+
+    let counts: Vec<u64> = samples.iter().map(|sample| sample.jobs).collect();
+    if counts.len() > 1 {
+        println!(
+            "WARNING: {} has {} job count series, taking the {:?}: {:?}",
+            device.short_id,
+            counts.len(),
+            prometheus.aggregation,
+            counts
+        );
+    }
+
+    Ok(Some(prometheus.aggregation.apply(&counts)))
+}
+
+/// Every series Prometheus returns for the job count query of a device with this short ID and
+/// hostname.
+async fn query_job_counts(
+    http_client: &reqwest::Client,
+    prometheus: &Prometheus,
+    short_id: &str,
+    hostname: &str,
+) -> Result<Vec<JobCountSample>> {
+    let url = format!("{}/api/v1/query", prometheus.root);
+    let query = prometheus
+        .query_template
+        .replace("{shortid}", short_id)
+        .replace("{hostname}", hostname);
+
+    let mut request = http_client
         .get(&url)
+        .query(&[("query", &query)])
         .header(ACCEPT, "application/json")
-        .header(CONTENT_TYPE, "application/json")
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(token) = &prometheus.bearer_token {
+        request = request.bearer_auth(token);
+    }
+
+    let raw = send_checked_json(request)
+        .await
+        .wrap_err_with(|| format!("failed to query Prometheus at {}", prometheus.root))?;
 
-    let getval = |resp: &serde_json::value::Value| -> Result<u64> {
-        let result = resp
-            .get("data")
-            .ok_or(eyre!("no .data"))?
-            .get("result")
-            .ok_or(eyre!("no .data.result"))?;
+    parse_job_counts(&raw).wrap_err_with(|| {
+        format!(
+            "failed to parse json from {} for {}, here's the raw content: {:#?}",
+            url, query, raw
+        )
+    })
+}
 
-        if let Some(measurement) = result.get(0) {
-            measurement
+/// How to combine job counts when Prometheus returns more than one series for a device.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobAggregation {
+    Sum,
+    Max,
+}
+
+impl JobAggregation {
+    fn apply(self, counts: &[u64]) -> u64 {
+        match self {
+            JobAggregation::Sum => counts.iter().sum(),
+            JobAggregation::Max => counts.iter().copied().max().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct JobCountSample {
+    /// When the sample was taken, in seconds since the epoch.
+    timestamp: f64,
+    jobs: u64,
+}
+
+/// The job count from each series in a Prometheus instant query response. A device with no series
+/// at all has no jobs.
+fn parse_job_counts(resp: &serde_json::Value) -> Result<Vec<JobCountSample>> {
+    let result = resp
+        .get("data")
+        .ok_or(eyre!("no .data"))?
+        .get("result")
+        .ok_or(eyre!("no .data.result"))?
+        .as_array()
+        .ok_or(eyre!("not an array: .data.result"))?;
+
+    result
+        .iter()
+        .enumerate()
+        .map(|(i, measurement)| {
+            let value = measurement
                 .get("value")
-                .ok_or(eyre!("no .data.result[0].value"))?
+                .ok_or(eyre!("no .data.result[{i}].value"))?;
+            let timestamp = value
+                .get(0)
+                .ok_or(eyre!("no .data.result[{i}].value.0"))?
+                .as_f64()
+                .ok_or(eyre!("not a number: .data.result[{i}].value.0"))?;
+            let jobs = value
                 .get(1)
-                .ok_or(eyre!("no .data.result[0].value.1"))?
+                .ok_or(eyre!("no .data.result[{i}].value.1"))?
                 .as_str()
-                .ok_or(eyre!("not a string: .data.result[0].value.1"))?
+                .ok_or(eyre!("not a string: .data.result[{i}].value.1"))?
                 .parse()
-                .wrap_err("couldn't convert .data.result[0].value.1 to a u64")
-        } else {
-            Ok(0)
-        }
-    };
+                .wrap_err_with(|| format!("couldn't convert .data.result[{i}].value.1 to a u64"))?;
 
-    getval(&raw).wrap_err_with(|| {
-        format!(
-            "failed to parse json from {}, here's the raw content: {:#?}",
-            url, raw
-        )
-    })
+            Ok(JobCountSample { timestamp, jobs })
+        })
+        .collect()
 }
 
 #[derive(Deserialize, Debug)]
@@ -208,34 +1279,553 @@ pub async fn get_all_devices(
     http_client: &reqwest::Client,
     equinix_auth_token: &str,
     equinix_project_id: &str,
+    tag: Option<&str>,
+) -> Result<Vec<Device>> {
+    list_all_devices(
+        &Fetcher::equinix(http_client, equinix_auth_token)?,
+        equinix_project_id,
+        tag,
+    )
+    .await
+}
+
+/// Every device in the project, or just those Equinix finds tagged `tag`, following the API's
+/// pagination.
+async fn list_all_devices(
+    fetcher: &impl HttpFetcher,
+    equinix_project_id: &str,
+    tag: Option<&str>,
 ) -> Result<Vec<Device>> {
     let mut all_devices: Vec<Device> = vec![];
 
-    let mut next_url = Some(format!(
-        "https://api.equinix.com/metal/v1/projects/{}/devices",
-        equinix_project_id
-    ));
+    let url = format!("{}/projects/{}/devices", api_base(), equinix_project_id);
+    let mut next_url = Some(match tag {
+        Some(tag) => reqwest::Url::parse_with_params(&url, [("tag", tag)])?.to_string(),
+        None => url,
+    });
     while let Some(url) = next_url {
-        let raw = http_client
-            .get(url)
-            .header(ACCEPT, "application/json")
-            .header(CONTENT_TYPE, "application/json")
-            .header("X-Auth-Token", equinix_auth_token)
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?;
+        let raw = fetcher.get_json(&url).await?;
+        check_error_envelope(&raw)?;
 
         let devices: DeviceList = serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
-            format!("failed to parse json, here's the raw content: {:#?}", raw)
+            redact(&format!(
+                "failed to parse json, here's the raw content: {:#?}",
+                raw
+            ))
         })?;
 
         next_url = devices
             .meta
             .next
-            .map(|respref| format!("https://api.equinix.com/metal/v1{}", respref.href));
-        all_devices.extend(devices.devices);
+            .map(|respref| format!("{}{}", api_base(), respref.href));
+        all_devices.extend(devices.devices.into_iter().map(|device| Device {
+            project_id: equinix_project_id.to_string(),
+            ..device
+        }));
     }
 
     Ok(all_devices)
 }
+
+#[derive(Deserialize, Debug)]
+struct SpotPrice {
+    price: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotMarketPrices {
+    spot_market_prices: HashMap<String, HashMap<String, SpotPrice>>,
+}
+
+/// The current spot prices in dollars per hour, by metro and then by plan.
+pub type SpotPrices = HashMap<String, HashMap<String, f64>>;
+
+async fn fetch_spot_prices(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    query: &[(&str, &str)],
+) -> Result<SpotPrices> {
+    let raw = send_json(
+        http_client
+            .get(format!("{}/market/spot/prices/metros", api_base()))
+            .query(query)
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    let prices: SpotMarketPrices = serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
+        ))
+    })?;
+
+    Ok(prices
+        .spot_market_prices
+        .into_iter()
+        .map(|(metro, plans)| {
+            let plans = plans
+                .into_iter()
+                .map(|(plan, price)| (plan, price.price))
+                .collect();
+            (metro, plans)
+        })
+        .collect())
+}
+
+/// The highest current spot price for the plan across the given metros, if Equinix reports one.
+pub async fn get_spot_price(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    plan: &str,
+    metros: &[String],
+) -> Result<Option<f64>> {
+    let prices = fetch_spot_prices(http_client, equinix_auth_token, &[("plan", plan)]).await?;
+
+    Ok(prices
+        .iter()
+        .filter(|(metro, _)| metros.contains(metro))
+        .filter_map(|(_, plans)| plans.get(plan))
+        .copied()
+        .max_by(f64::total_cmp))
+}
+
+/// The current spot prices of every plan in every metro.
+pub async fn get_spot_prices(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+) -> Result<SpotPrices> {
+    fetch_spot_prices(http_client, equinix_auth_token, &[]).await
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Metro {
+    pub code: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MetroList {
+    metros: Vec<Metro>,
+}
+
+/// The project's name, which fails if the token can't see the project.
+pub async fn get_project_name(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+    equinix_project_id: &str,
+) -> Result<String> {
+    let raw = send_checked_json(
+        http_client
+            .get(format!("{}/projects/{}", api_base(), equinix_project_id))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    raw.get("name")
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| {
+            eyre!(redact(&format!(
+                "the project has no name, here's the raw content: {:#?}",
+                raw
+            )))
+        })
+}
+
+pub async fn get_metros(
+    http_client: &reqwest::Client,
+    equinix_auth_token: &str,
+) -> Result<Vec<Metro>> {
+    let raw = send_json(
+        http_client
+            .get(format!("{}/metros", api_base()))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Auth-Token", equinix_auth_token),
+    )
+    .await?;
+
+    let metros: MetroList = serde_json::from_str(&raw.to_string()).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json, here's the raw content: {:#?}",
+            raw
+        ))
+    })?;
+
+    Ok(metros.metros)
+}
+
+#[cfg(test)]
+pub mod device_tests {
+    use super::*;
+    use crate::provider::mock::desired;
+
+    #[test]
+    fn test_job_counts_empty_result() {
+        let resp = serde_json::json!({ "data": { "result": [] } });
+        assert_eq!(parse_job_counts(&resp).unwrap(), vec![]);
+        assert_eq!(JobAggregation::Sum.apply(&[]), 0);
+        assert_eq!(JobAggregation::Max.apply(&[]), 0);
+    }
+
+    #[test]
+    fn test_job_counts_missing_result() {
+        let resp = serde_json::json!({ "data": {} });
+        assert!(parse_job_counts(&resp).is_err());
+    }
+
+    #[test]
+    fn test_job_counts_multiple_series() {
+        let resp = serde_json::json!({ "data": { "result": [
+            { "value": [1700000000.0, "2"] },
+            { "value": [1700000000.0, "3"] },
+        ] } });
+        let counts: Vec<u64> = parse_job_counts(&resp)
+            .unwrap()
+            .iter()
+            .map(|sample| sample.jobs)
+            .collect();
+        assert_eq!(counts, vec![2, 3]);
+        assert_eq!(JobAggregation::Sum.apply(&counts), 5);
+        assert_eq!(JobAggregation::Max.apply(&counts), 3);
+    }
+
+    fn device_json(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "hostname": "c3.small.x86",
+            "id": id,
+            "short_id": id,
+            "created_at": "2023-01-01T00:00:00Z",
+            "device_type": "spot_instance",
+            "state": "active",
+            "ipxe_script_url": null,
+            "plan": { "class": "c3.small.x86" },
+            "metro": { "code": "da", "name": "Dallas" },
+            "tags": [],
+        })
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let mut json = device_json("a");
+        json["spot_price_max"] = serde_json::json!(0.5);
+        let mut device: Device = serde_json::from_value(json).unwrap();
+        assert_eq!(device.spot_price_max, Some(0.5));
+
+        let prices = SpotPrices::from([(
+            "da".to_string(),
+            HashMap::from([("c3.small.x86".to_string(), 0.2)]),
+        )]);
+        assert_eq!(device.spot_price(&prices), Some(0.2));
+        device.metro = None;
+        assert_eq!(device.spot_price(&prices), None);
+    }
+
+    #[test]
+    fn test_tag_match() {
+        let device = |tags: &[&str]| -> Device {
+            let mut json = device_json("a");
+            json["tags"] = serde_json::json!(tags);
+            serde_json::from_value(json).unwrap()
+        };
+        let tags = vec!["hydra".to_string(), "builder".to_string()];
+        let known_tags = BTreeSet::from(["big".to_string(), "skip-hydra".to_string()]);
+        let ours = device(&["hydra", "builder", "big", "skip-hydra", "hydra-size=Small"]);
+        let shared = device(&["hydra", "builder", "other-tool"]);
+        let partial = device(&["hydra"]);
+
+        let matching = |mode: TagMatch| {
+            [&ours, &shared, &partial]
+                .iter()
+                .map(|device| mode.matches(device, &tags, &known_tags))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(TagMatch::Superset), vec![true, true, false]);
+        assert_eq!(matching(TagMatch::Exact), vec![true, false, false]);
+        assert_eq!(matching(TagMatch::AnyOf), vec![true, true, true]);
+    }
+
+    #[tokio::test]
+    async fn test_list_all_devices_paginates() {
+        let base = "https://api.equinix.com/metal/v1";
+        let responses = HashMap::from([
+            (
+                format!("{base}/projects/p/devices"),
+                serde_json::json!({
+                    "devices": [device_json("a")],
+                    "meta": { "next": { "href": "/projects/p/devices?page=2" } },
+                }),
+            ),
+            (
+                format!("{base}/projects/p/devices?page=2"),
+                serde_json::json!({
+                    "devices": [device_json("b")],
+                    "meta": { "next": null },
+                }),
+            ),
+        ]);
+
+        let devices = list_all_devices(&responses, "p", None).await.unwrap();
+        let ids: Vec<&str> = devices.iter().map(|device| device.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(devices[0].metro.as_ref().unwrap().code, "da");
+        assert!(devices.iter().all(|device| device.project_id == "p"));
+    }
+
+    #[tokio::test]
+    async fn test_list_all_devices_by_tag() {
+        let responses = HashMap::from([(
+            "https://api.equinix.com/metal/v1/projects/p/devices?tag=hydra%3Dyes".to_string(),
+            serde_json::json!({
+                "devices": [device_json("a")],
+                "meta": { "next": null },
+            }),
+        )]);
+
+        let devices = list_all_devices(&responses, "p", Some("hydra=yes"))
+            .await
+            .unwrap();
+        assert_eq!(devices[0].id, "a");
+    }
+
+    #[tokio::test]
+    async fn test_list_all_devices_error_envelope() {
+        let responses = HashMap::from([(
+            "https://api.equinix.com/metal/v1/projects/p/devices".to_string(),
+            serde_json::json!({ "errors": ["Invalid authentication token"] }),
+        )]);
+
+        let err = list_all_devices(&responses, "p", None).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid authentication token"));
+    }
+
+    #[test]
+    fn test_create_device_request_body() {
+        let desired = DesiredDevice {
+            system: crate::machine_type::System("aarch64-linux".into()),
+            size: crate::machine_type::JobSize::BigParallel,
+            plan: crate::hardware::HardwarePlan {
+                bid: 2.0,
+                netboot_url: "https://netboot.example/c3-large-arm--big-parallel".into(),
+                ..desired("c3.large.arm64").plan
+            },
+            ..desired("c3.large.arm64")
+        };
+
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let hostname = render_hostname("{plan}-{metro}-{timestamp}", &desired, "da", now);
+        let body =
+            serde_json::to_value(CreateDeviceRequest::new(&desired, "da", hostname, now)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "always_pxe": true,
+                "billing_cycle": "hourly",
+                "hostname": "c3-large-arm64-da-1700000000",
+                "ipxe_script_url": "https://netboot.example/c3-large-arm--big-parallel",
+                "metro": "da",
+                "operating_system": "custom_ipxe",
+                "plan": "c3.large.arm64",
+                "spot_instance": true,
+                "spot_price_max": 2.0,
+                "tags": [
+                    "hydra",
+                    "hydra-system=aarch64-linux",
+                    "hydra-size=BigParallel",
+                    format!("hydra-category-hash={}", desired.category_hash()),
+                    "hydra-created-at=2023-11-14T22:13:20.000000000Z",
+                    format!("hydra-scaler-version={}", env!("CARGO_PKG_VERSION")),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_create_device_request_without_ipxe() {
+        let plan: crate::hardware::HardwarePlan = serde_json::from_value(serde_json::json!({
+            "bid": 1.0,
+            "plan": "m3.large.x86",
+            "always_pxe": false,
+            "operating_system": "nixos_23_05",
+        }))
+        .unwrap();
+        let desired = DesiredDevice {
+            plan,
+            ..desired("m3.large.x86")
+        };
+
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let body = serde_json::to_value(CreateDeviceRequest::new(&desired, "da", "h".into(), now))
+            .unwrap();
+        assert_eq!(body["always_pxe"], false);
+        assert_eq!(body["operating_system"], "nixos_23_05");
+        assert!(body.get("ipxe_script_url").is_none());
+        assert!(body.get("termination_time").is_none());
+        assert!(body.get("userdata").is_none());
+        assert!(body.get("project_ssh_keys").is_none());
+    }
+
+    #[test]
+    fn test_termination_time() {
+        let desired = desired("m3.large.x86");
+
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        assert_eq!(termination_time(None, now).unwrap(), None);
+        let ttl = termination_time(Some(time::Duration::HOUR), now).unwrap();
+        assert_eq!(ttl.as_deref(), Some("2023-11-14T23:13:20Z"));
+
+        let body = serde_json::to_value(CreateDeviceRequest {
+            termination_time: ttl.clone(),
+            ..CreateDeviceRequest::new(&desired, "da", "h".into(), now)
+        })
+        .unwrap();
+        assert_eq!(body["termination_time"], "2023-11-14T23:13:20Z");
+        let body = serde_json::to_value(SpotMarketRequestInstanceParameters::new(
+            &desired,
+            "h".into(),
+            vec![],
+            ttl,
+        ))
+        .unwrap();
+        assert_eq!(body["termination_time"], "2023-11-14T23:13:20Z");
+    }
+
+    #[test]
+    fn test_bid_rejection() {
+        let raw = serde_json::json!({ "errors": ["Spot price max is below the market price"] });
+        assert!(matches!(
+            bid_rejection(&raw),
+            Some(Unfillable::BidRejected(_))
+        ));
+        assert_eq!(
+            bid_rejection(&serde_json::json!({ "errors": ["Plan is not valid"] })),
+            None
+        );
+        assert_eq!(bid_rejection(&serde_json::json!({ "id": "d" })), None);
+    }
+
+    #[test]
+    fn test_parse_capacity() {
+        let response = |available: bool| {
+            serde_json::json!({
+                "servers": [{
+                    "metro": "da",
+                    "plan": "c3.small.x86",
+                    "quantity": 1,
+                    "available": available,
+                }],
+            })
+        };
+        assert!(parse_capacity(&response(true)).unwrap());
+        assert!(!parse_capacity(&response(false)).unwrap());
+        assert!(parse_capacity(&serde_json::json!({ "errors": ["nope"] })).is_err());
+    }
+
+    #[test]
+    fn test_create_on_demand_device_request() {
+        let desired = DesiredDevice {
+            plan: serde_json::from_value(serde_json::json!({
+                "bid": 1.0,
+                "plan": "m3.large.x86",
+                "userdata": "#cloud-config\n",
+            }))
+            .unwrap(),
+            allow_on_demand: true,
+            on_demand: true,
+            ssh_key_ids: vec!["key-1".into()],
+            billing_cycle: BillingCycle::Monthly,
+            ..desired("m3.large.x86")
+        };
+
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let body = serde_json::to_value(CreateDeviceRequest::new(&desired, "da", "h".into(), now))
+            .unwrap();
+        assert_eq!(body["spot_instance"], false);
+        assert_eq!(body["billing_cycle"], "monthly");
+        assert!(body.get("spot_price_max").is_none());
+        assert_eq!(body["userdata"], "#cloud-config\n");
+        assert_eq!(body["project_ssh_keys"], serde_json::json!(["key-1"]));
+        assert!(body["tags"]
+            .as_array()
+            .unwrap()
+            .contains(&ON_DEMAND_TAG.into()));
+    }
+
+    #[test]
+    fn test_render_hostname() {
+        let desired = desired("C3.Medium.x86");
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+
+        let hostname = render_hostname("{plan}-{metro}-{short_random}", &desired, "ny", now);
+        assert!(hostname.starts_with("c3-medium-x86-ny-"));
+        assert_eq!(hostname.len(), "c3-medium-x86-ny-".len() + 6);
+
+        let plans = BTreeSet::from(["C3.Medium.x86".to_string()]);
+        let metros = BTreeSet::from(["ny".to_string(), "da".to_string()]);
+        let matches = |template: &str, hostname: &str| {
+            matches_hostname_template(template, hostname, &plans, &metros)
+        };
+        assert!(matches("{plan}-{metro}-{short_random}", &hostname));
+        assert!(matches(
+            "Hydra.{plan}.{timestamp}",
+            &render_hostname("Hydra.{plan}.{timestamp}", &desired, "ny", now)
+        ));
+        assert!(!matches(
+            "{plan}-{metro}-{short_random}",
+            "c3-medium-x86-ny-zzzzzz"
+        ));
+        assert!(!matches(
+            "hydra-{plan}-{short_random}",
+            "builder-c3-medium-x86-abcdef"
+        ));
+        // Another deployment's plans and metros aren't ours.
+        assert!(!matches(
+            "{plan}-{metro}-{short_random}",
+            "m3-large-x86-ny-abcdef"
+        ));
+        assert!(!matches(
+            "{plan}-{metro}-{short_random}",
+            "c3-medium-x86-sv-abcdef"
+        ));
+
+        let long = render_hostname(&"{system}".repeat(10), &desired, "ny", now);
+        assert_eq!(long.len(), 63);
+        assert!(long
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+    }
+
+    #[test]
+    fn test_destroy_response() {
+        assert!(parse_destroy_response(StatusCode::NO_CONTENT, String::new()).unwrap());
+        assert!(!parse_destroy_response(StatusCode::NOT_FOUND, "not found".into()).unwrap());
+        assert!(!parse_destroy_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            r#"{"errors":["Device is already being deprovisioned"]}"#.into()
+        )
+        .unwrap());
+        assert!(parse_destroy_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            r#"{"errors":["Device is locked"]}"#.into()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_job_counts_timestamp() {
+        let resp = serde_json::json!({ "data": { "result": [
+            { "value": [1700000000.5, "1"] },
+        ] } });
+        assert_eq!(
+            parse_job_counts(&resp).unwrap(),
+            vec![JobCountSample {
+                timestamp: 1700000000.5,
+                jobs: 1
+            }]
+        );
+    }
+}
@@ -1,10 +1,16 @@
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
 
-use eyre::Result;
-use reqwest::header::ACCEPT;
-use serde::Deserialize;
+use eyre::{eyre, Result, WrapErr};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::device::Device;
+use crate::http::{retry, send, snippet, Fetcher, HttpFetcher};
+use crate::provider::Drainer;
+use crate::schedule::TimeWindow;
 
 use crate::machine_type::{JobSize, MachineType, System};
 
@@ -16,62 +22,1052 @@ pub struct MachineTypeStatus {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct QueueRunnerStatus {
+    /// Missing if Hydra has never seen a machine type, which means there's nothing to do.
+    #[serde(default)]
     machine_types: HashMap<MachineType, MachineTypeStatus>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(from = "RawHardwarePlan")]
 pub struct HardwarePlan {
     pub bid: f64,
+    /// The class to create devices of.
     pub plan: String,
+    /// Other classes that devices of this plan may have and still be kept, like a class's
+    /// successor.
+    pub equivalent_classes: Vec<String>,
     pub netboot_url: String,
+    pub always_pxe: bool,
+    pub operating_system: String,
+    pub weight: usize,
+    pub userdata: Option<String>,
+    pub slots: usize,
+}
+
+/// A plan for a category's devices, as written in the config.
+#[derive(Deserialize, JsonSchema)]
+struct RawHardwarePlan {
+    bid: f64,
+    /// The device class, or a list of classes that are equally acceptable, like a class and its
+    /// successor. Devices are created with the first, and kept if they have any of them.
+    plan: PlanClasses,
+    /// The iPXE script to boot, for plans using the `custom_ipxe` operating system.
+    #[serde(default)]
+    netboot_url: String,
+    #[serde(default = "default_always_pxe")]
+    always_pxe: bool,
+    #[serde(default = "default_operating_system")]
+    operating_system: String,
+    /// How many of this plan to create relative to the category's other plans.
+    #[serde(default = "default_weight")]
+    weight: usize,
+    /// Userdata, like a cloud-init config, for devices of this plan.
+    #[serde(default)]
+    userdata: Option<String>,
+    /// How much of the category's capacity one device of this plan provides, like its number of
+    /// build slots. A category's `divisor`, `minimum`, and `maximum` then size it in slots, so
+    /// that plans of different sizes can share a category.
+    #[serde(default = "default_slots")]
+    slots: usize,
+}
+
+/// One device class, or a list of equally acceptable ones.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum PlanClasses {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<RawHardwarePlan> for HardwarePlan {
+    fn from(raw: RawHardwarePlan) -> Self {
+        let mut classes = match raw.plan {
+            PlanClasses::One(class) => vec![class],
+            PlanClasses::Many(classes) => classes,
+        };
+        // An empty list is reported by `Config::problems`.
+        let plan = if classes.is_empty() {
+            String::new()
+        } else {
+            classes.remove(0)
+        };
+
+        HardwarePlan {
+            bid: raw.bid,
+            plan,
+            equivalent_classes: classes,
+            netboot_url: raw.netboot_url,
+            always_pxe: raw.always_pxe,
+            operating_system: raw.operating_system,
+            weight: raw.weight,
+            userdata: raw.userdata,
+            slots: raw.slots,
+        }
+    }
+}
+
+fn default_weight() -> usize {
+    1
 }
 
-#[derive(Clone, Debug, Deserialize)]
+fn default_slots() -> usize {
+    1
+}
+
+fn default_always_pxe() -> bool {
+    true
+}
+
+fn default_operating_system() -> String {
+    "custom_ipxe".into()
+}
+
+impl HardwarePlan {
+    /// Whether a device's plan class is this plan or one of its equivalent classes. Equinix
+    /// hasn't always been consistent about case, so this ignores case and surrounding whitespace.
+    pub fn is_class(&self, class: &str) -> bool {
+        std::iter::once(&self.plan)
+            .chain(self.equivalent_classes.iter())
+            .any(|plan| plan.trim().eq_ignore_ascii_case(class.trim()))
+    }
+
+    /// Whether devices of this plan boot its `netboot_url`.
+    pub fn uses_ipxe(&self) -> bool {
+        self.operating_system == "custom_ipxe"
+    }
+
+    /// The iPXE script URL to create devices of this plan with, if they netboot.
+    pub fn ipxe_script_url(&self) -> Option<String> {
+        self.uses_ipxe().then(|| self.netboot_url.clone())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct HardwareCategory {
     pub divisor: usize,
     pub minimum: usize,
     pub maximum: usize,
     pub plans: Vec<HardwarePlan>,
+    /// Metros for this category's devices, used instead of the global `metro`.
+    #[serde(default)]
+    pub metro: Option<Metros>,
+    /// Extra tags for this category's devices, added to the global `tags`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Create on-demand devices when spot devices can't be created, replacing them with spot
+    /// devices once spot capacity is back.
+    #[serde(default)]
+    pub allow_on_demand: bool,
+    /// Different sizing for certain times, like more capacity during working hours. The first
+    /// profile whose window is active wins.
+    #[serde(default)]
+    pub schedules: Vec<CategoryProfile>,
+    /// How to round `runnable / divisor`, before clamping it between `minimum` and `maximum`.
+    #[serde(default)]
+    pub rounding: Rounding,
+    /// When not every create can be made, categories with a higher priority get theirs first.
+    #[serde(default)]
+    pub priority: i64,
+    /// The Equinix Metal project to create this category's devices in, which must be one of
+    /// `--project-id`. Defaults to the first of them.
+    #[serde(default)]
+    pub project: Option<String>,
+    /// How Equinix bills this category's on-demand devices. Spot instances are always hourly.
+    #[serde(default)]
+    pub billing_cycle: BillingCycle,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BillingCycle {
+    #[default]
+    Hourly,
+    Daily,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Rounding {
+    #[default]
+    Floor,
+    /// Any runnable job gets a device, even with a `minimum` of 0.
+    Ceil,
+    Round,
+}
+
+impl Rounding {
+    /// `runnable / divisor`, rounded, or `None` for a `divisor` of 0, which
+    /// [`Config::problems`] reports.
+    pub fn divide(self, runnable: usize, divisor: usize) -> Option<usize> {
+        if divisor == 0 {
+            return None;
+        }
+
+        Some(match self {
+            Rounding::Floor => runnable / divisor,
+            Rounding::Ceil => runnable.div_ceil(divisor),
+            Rounding::Round => (runnable + divisor / 2) / divisor,
+        })
+    }
+}
+
+/// Overrides for a category's sizing during a time window.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct CategoryProfile {
+    #[serde(flatten)]
+    pub window: TimeWindow,
+    pub divisor: Option<usize>,
+    pub minimum: Option<usize>,
+    pub maximum: Option<usize>,
+}
+
+impl HardwareCategory {
+    /// This category, sized by whichever of its schedules is active at `now`.
+    fn at(&self, now: OffsetDateTime) -> HardwareCategory {
+        let mut category = self.clone();
+        if let Some(profile) = self
+            .schedules
+            .iter()
+            .find(|profile| profile.window.contains(now))
+        {
+            category.divisor = profile.divisor.unwrap_or(category.divisor);
+            category.minimum = profile.minimum.unwrap_or(category.minimum);
+            category.maximum = profile.maximum.unwrap_or(category.maximum);
+        }
+
+        category
+    }
+
+    /// How many slots this category wants for `runnable` jobs. With a divisor of 0, that's just
+    /// the minimum.
+    pub fn wanted(&self, runnable: usize) -> usize {
+        let divided = self.rounding.divide(runnable, self.divisor).unwrap_or(0);
+        min(self.maximum, max(self.minimum, divided))
+    }
+
+    /// How many devices it takes to provide this category's minimum slots.
+    pub fn minimum_devices(&self) -> usize {
+        if self.plans.iter().all(|plan| plan.weight == 0) {
+            return self.minimum;
+        }
+
+        plans_for_slots(&self.plans, self.minimum).count()
+    }
+}
+
+/// How a category was sized this run, for reporting whether its divisor and limits are tuned
+/// right.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CategorySizing {
+    /// Estimated work rather than jobs, if [`Config::job_weights`] are set.
+    pub runnable: usize,
+    pub divisor: usize,
+    pub minimum: usize,
+    pub maximum: usize,
+    /// `runnable` divided by `divisor`, between `minimum` and `maximum`. Like them, it's in
+    /// slots, which are devices unless plans set [`HardwarePlan::slots`].
+    pub desired: usize,
+}
+
+/// How each of `categories` is sized for the runnable jobs in `buckets`, by [`category_name`].
+pub fn category_sizing(
+    buckets: &Buckets,
+    categories: &CategoryMap,
+) -> BTreeMap<String, CategorySizing> {
+    categories
+        .iter()
+        .flat_map(|(system, sizes)| {
+            sizes.iter().map(move |(size, category)| {
+                let runnable = buckets
+                    .get(system)
+                    .and_then(|sizes| sizes.get(size))
+                    .copied()
+                    .unwrap_or(0);
+                let sizing = CategorySizing {
+                    runnable,
+                    divisor: category.divisor,
+                    minimum: category.minimum,
+                    maximum: category.maximum,
+                    desired: category.wanted(runnable),
+                };
+                (category_name(system, size), sizing)
+            })
+        })
+        .collect()
+}
+
+/// A device we want to be running, and the category it was chosen to serve.
+#[derive(Clone, Debug)]
+pub struct DesiredDevice {
+    pub system: System,
+    pub size: JobSize,
+    pub plan: HardwarePlan,
+    /// The tags to create the device with, besides its category tags.
+    pub tags: Vec<String>,
+    /// The metros to create the device in, in order of preference.
+    pub metros: Vec<String>,
+    /// Whether an on-demand device may be created if a spot device can't be.
+    pub allow_on_demand: bool,
+    /// Whether to create an on-demand device instead of a spot device.
+    pub on_demand: bool,
+    /// See [`Config::ssh_key_ids`].
+    pub ssh_key_ids: Vec<String>,
+    /// See [`HardwareCategory::project`].
+    pub project_id: Option<String>,
+    /// See [`HardwareCategory::billing_cycle`].
+    pub billing_cycle: BillingCycle,
+}
+
+pub const SYSTEM_TAG_PREFIX: &str = "hydra-system=";
+pub const SIZE_TAG_PREFIX: &str = "hydra-size=";
+pub const CATEGORY_HASH_TAG_PREFIX: &str = "hydra-category-hash=";
+
+impl DesiredDevice {
+    /// A stable identifier for this device's category and plan, so that devices can be matched
+    /// back to what they were created for without comparing netboot URLs and plan classes.
+    pub fn category_hash(&self) -> String {
+        category_hash(&self.system, &self.size, &self.plan)
+    }
+
+    /// Whether `hash` is this device's [`category_hash`](Self::category_hash), or would be if
+    /// one of its plan's equivalent classes were the one created with, so that reordering a
+    /// plan's classes doesn't replace its devices.
+    pub fn has_category_hash(&self, hash: &str) -> bool {
+        category_hashes(&self.system, &self.size, &self.plan).any(|ours| ours == hash)
+    }
+
+    /// The tags describing this device's category.
+    pub fn category_tags(&self) -> Vec<String> {
+        vec![
+            format!("{SYSTEM_TAG_PREFIX}{}", self.system.0),
+            format!("{SIZE_TAG_PREFIX}{:?}", self.size),
+            format!("{CATEGORY_HASH_TAG_PREFIX}{}", self.category_hash()),
+        ]
+    }
+}
+
+/// The tags sorted, ignoring case, with duplicates removed, so that the same tags always come out
+/// the same. Tags differing only in case are duplicates, and the first spelling is kept.
+pub fn normalize_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags.into_iter().collect();
+    tags.sort_by_key(|tag| tag.to_lowercase());
+    tags.dedup_by(|a, b| a.to_lowercase() == b.to_lowercase());
+    tags
+}
+
+/// A system and size, like `x86_64-linux/BigParallel`.
+pub fn category_name(system: &System, size: &JobSize) -> String {
+    format!("{}/{:?}", system.0, size)
+}
+
+/// The system and size a device was created for, from its category tags, in the same form as
+/// [`category_name`].
+pub fn device_category_name(device: &Device) -> String {
+    let tag = |prefix: &str| {
+        device
+            .tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(prefix))
+            .unwrap_or("unknown")
+            .to_string()
+    };
+
+    format!("{}/{}", tag(SYSTEM_TAG_PREFIX), tag(SIZE_TAG_PREFIX))
+}
+
+fn category_hash(system: &System, size: &JobSize, plan: &HardwarePlan) -> String {
+    class_category_hash(system, size, &plan.plan, &plan.netboot_url)
+}
+
+/// The category hashes a plan's devices may have, one for each of its classes.
+fn category_hashes<'a>(
+    system: &'a System,
+    size: &'a JobSize,
+    plan: &'a HardwarePlan,
+) -> impl Iterator<Item = String> + 'a {
+    std::iter::once(&plan.plan)
+        .chain(plan.equivalent_classes.iter())
+        .map(|class| class_category_hash(system, size, class, &plan.netboot_url))
+}
+
+fn class_category_hash(system: &System, size: &JobSize, class: &str, netboot_url: &str) -> String {
+    let key = format!("{}\0{:?}\0{}\0{}", system.0, size, class, netboot_url);
+
+    // FNV-1a, since std's hashers aren't guaranteed to be stable across releases.
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{hash:016x}")
 }
 
 type CategoryMap = HashMap<System, HashMap<JobSize, HardwareCategory>>;
 
-#[derive(Deserialize)]
+/// One metro, or a list of metros to try in order.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Metros {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Metros {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Metros::One(metro) => vec![metro],
+            Metros::Many(metros) => metros,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
 pub struct Config {
     categories: CategoryMap,
     tags: Vec<String>,
-    facilities: Vec<String>,
+    /// Configs from before metros were supported list `facilities` instead, which are taken as
+    /// metros.
+    #[serde(alias = "facilities")]
+    metro: Metros,
+    /// IDs, short IDs, or hostnames of devices never to drain or destroy.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// When to leave the fleet alone: `scale` and `gc` only say what they would do.
+    #[serde(default)]
+    pub maintenance_windows: Vec<TimeWindow>,
+    /// IDs of project SSH keys to install on new devices, so they can be logged into for
+    /// debugging.
+    #[serde(default)]
+    ssh_key_ids: Vec<String>,
+    /// Destroy devices past the urgent termination date even if they're running jobs, instead of
+    /// waiting for them to be idle.
+    #[serde(default)]
+    pub allow_urgent_termination: bool,
+    /// How long jobs needing each feature take, relative to a job needing none, like
+    /// `{"big-parallel": 8}`. If any are set, demand is measured in estimated work rather than
+    /// in jobs, so divisors count the work of a job needing no features. A job needing several
+    /// features weighs as much as the heaviest of them.
+    #[serde(default)]
+    pub job_weights: BTreeMap<String, f64>,
+}
+
+impl Config {
+    /// The tags every managed device has.
+    pub fn tags(&self) -> Vec<String> {
+        normalize_tags(self.tags.iter().cloned())
+    }
+
+    pub fn in_maintenance(&self, now: OffsetDateTime) -> bool {
+        self.maintenance_windows
+            .iter()
+            .any(|window| window.contains(now))
+    }
+
+    /// The categories, sized for `now`.
+    pub fn categories_at(&self, now: OffsetDateTime) -> CategoryMap {
+        self.categories
+            .iter()
+            .map(|(system, sizes)| {
+                let sizes = sizes
+                    .iter()
+                    .map(|(size, category)| (size.clone(), category.at(now)))
+                    .collect();
+                (system.clone(), sizes)
+            })
+            .collect()
+    }
+
+    /// Every metro the config may create devices in, in any category.
+    pub fn all_metros(&self) -> BTreeSet<String> {
+        self.metro
+            .clone()
+            .into_vec()
+            .into_iter()
+            .chain(
+                self.categories
+                    .values()
+                    .flat_map(|sizes| sizes.values())
+                    .filter_map(|category| category.metro.clone())
+                    .flat_map(|metro| metro.into_vec()),
+            )
+            .collect()
+    }
+
+    /// Every plan the config may create devices with, in any category.
+    pub fn all_plans(&self) -> BTreeSet<String> {
+        self.categories
+            .values()
+            .flat_map(|sizes| sizes.values())
+            .flat_map(|category| category.plans.iter())
+            .map(|plan| plan.plan.clone())
+            .collect()
+    }
+
+    /// Every tag the config gives devices, in any category.
+    pub fn known_tags(&self) -> BTreeSet<String> {
+        self.tags
+            .iter()
+            .chain(
+                self.categories
+                    .values()
+                    .flat_map(|sizes| sizes.values())
+                    .flat_map(|category| category.tags.iter()),
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// The category hash of every plan in the config, whether or not there's demand for it.
+    pub fn category_hashes(&self) -> BTreeSet<String> {
+        let mut hashes = BTreeSet::new();
+        for (system, sizes) in self.categories.iter() {
+            for (size, category) in sizes.iter() {
+                for plan in category.plans.iter() {
+                    hashes.extend(category_hashes(system, size, plan));
+                }
+            }
+        }
+
+        hashes
+    }
+
+    /// Everything wrong with the config that parsing it doesn't catch.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.metro.clone().into_vec().is_empty() {
+            problems.push("metro: no metros".to_string());
+        }
+        for (feature, weight) in self.job_weights.iter() {
+            if !(weight.is_finite() && *weight > 0.0) {
+                problems.push(format!("job_weights {feature}: must be positive"));
+            }
+        }
+
+        for (system, sizes) in self.categories.iter() {
+            for (size, category) in sizes.iter() {
+                let name = category_name(system, size);
+                let mut sizing = vec![(
+                    name.clone(),
+                    category.divisor,
+                    category.minimum,
+                    category.maximum,
+                )];
+                for (idx, profile) in category.schedules.iter().enumerate() {
+                    sizing.push((
+                        format!("{name} schedule {idx}"),
+                        profile.divisor.unwrap_or(category.divisor),
+                        profile.minimum.unwrap_or(category.minimum),
+                        profile.maximum.unwrap_or(category.maximum),
+                    ));
+                }
+                for (what, divisor, minimum, maximum) in sizing {
+                    if divisor == 0 {
+                        problems.push(format!("{what}: divisor is 0"));
+                    }
+                    if minimum > maximum {
+                        problems.push(format!(
+                            "{what}: minimum {minimum} is above maximum {maximum}"
+                        ));
+                    }
+                }
+
+                if category.plans.iter().all(|plan| plan.weight == 0) {
+                    problems.push(format!("{name}: no plans with a weight"));
+                }
+                if category
+                    .metro
+                    .as_ref()
+                    .is_some_and(|metro| metro.clone().into_vec().is_empty())
+                {
+                    problems.push(format!("{name}: no metros"));
+                }
+                for plan in category.plans.iter() {
+                    if plan.plan.is_empty() {
+                        problems.push(format!("{name}: a plan has no classes"));
+                    }
+                    if plan.bid <= 0.0 {
+                        problems.push(format!("{name} {}: bid must be positive", plan.plan));
+                    }
+                    if plan.slots == 0 {
+                        problems.push(format!("{name} {}: slots must be positive", plan.plan));
+                    }
+                    if plan.uses_ipxe() && plan.netboot_url.is_empty() {
+                        problems.push(format!(
+                            "{name} {}: custom_ipxe needs a netboot_url",
+                            plan.plan
+                        ));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
 }
 
 pub struct DesiredHardwareConfig {
-    pub plans: Vec<HardwarePlan>,
+    pub plans: Vec<DesiredDevice>,
+    /// The tags every managed device has.
     pub tags: Vec<String>,
-    pub facilities: Vec<String>,
+    /// Every metro that devices may be created in.
+    pub metros: Vec<String>,
+    /// See [`Config::known_tags`].
+    pub known_tags: BTreeSet<String>,
+    /// See [`Config::all_plans`].
+    pub known_plans: BTreeSet<String>,
+    /// See [`Config::all_metros`].
+    pub known_metros: BTreeSet<String>,
+    /// See [`Config::exclude`].
+    pub exclude: Vec<String>,
+    /// How many devices each category's current minimum takes, by [`category_name`].
+    pub minimums: HashMap<String, usize>,
+    /// Each category's [`HardwareCategory::priority`], by [`category_name`].
+    pub priorities: HashMap<String, i64>,
+    /// How each category was sized, by [`category_name`].
+    pub sizing: BTreeMap<String, CategorySizing>,
+}
+
+fn priority(priorities: &HashMap<String, i64>, desired: &DesiredDevice) -> i64 {
+    priorities
+        .get(&category_name(&desired.system, &desired.size))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Put the creates of higher priority categories first, so that they're made before capacity
+/// or a cap runs out.
+pub fn sort_by_priority(plans: &mut [DesiredDevice], priorities: &HashMap<String, i64>) {
+    plans.sort_by_key(|desired| std::cmp::Reverse(priority(priorities, desired)));
+}
+
+/// Drop creates beyond `budget`, satisfying higher priority categories first, then the
+/// categories asking for the fewest devices. Returns how many creates each starved category
+/// lost.
+pub fn trim_to_budget(
+    plans: &mut Vec<DesiredDevice>,
+    budget: usize,
+    priorities: &HashMap<String, i64>,
+) -> HashMap<(System, JobSize), usize> {
+    let mut starved: HashMap<(System, JobSize), usize> = HashMap::new();
+    if plans.len() <= budget {
+        return starved;
+    }
+
+    let mut wanted: HashMap<(System, JobSize), usize> = HashMap::new();
+    for desired in plans.iter() {
+        *wanted
+            .entry((desired.system.clone(), desired.size.clone()))
+            .or_default() += 1;
+    }
+
+    plans.sort_by_key(|desired| {
+        (
+            std::cmp::Reverse(priority(priorities, desired)),
+            wanted[&(desired.system.clone(), desired.size.clone())],
+        )
+    });
+    for desired in plans.drain(budget..) {
+        *starved.entry((desired.system, desired.size)).or_default() += 1;
+    }
+
+    starved
+}
+
+/// Drop the lowest priority categories' most expensive creates until the creates' bids fit in
+/// `budget` dollars per hour. Returns the dropped creates.
+pub fn trim_to_spend(
+    plans: &mut Vec<DesiredDevice>,
+    budget: f64,
+    priorities: &HashMap<String, i64>,
+) -> Vec<DesiredDevice> {
+    let mut dropped = vec![];
+    let mut spend: f64 = plans.iter().map(|desired| desired.plan.bid).sum();
+    while spend > budget {
+        let Some((idx, _)) = plans.iter().enumerate().max_by(|(_, a), (_, b)| {
+            priority(priorities, b)
+                .cmp(&priority(priorities, a))
+                .then(a.plan.bid.total_cmp(&b.plan.bid))
+        }) else {
+            break;
+        };
+
+        let desired = plans.remove(idx);
+        spend -= desired.plan.bid;
+        dropped.push(desired);
+    }
+
+    dropped
 }
 
+/// Parse the config as YAML if its extension is `.yaml` or `.yml`, and as JSON otherwise. `${VAR}`
+/// in a string is replaced with the environment variable `VAR`, or with `default` for
+/// `${VAR:-default}` if `VAR` isn't set.
 pub fn parse_config_file(file: &Path) -> Result<Config> {
-    let json_str = std::fs::read_to_string(file)?;
-    let config: Config = serde_json::from_str(&json_str)?;
+    let contents = std::fs::read_to_string(file)
+        .wrap_err_with(|| format!("failed to read {}", file.display()))?;
+    let yaml = matches!(
+        file.extension().and_then(|extension| extension.to_str()),
+        Some("yaml" | "yml")
+    );
 
-    Ok(config)
+    parse_config(&contents, yaml).wrap_err_with(|| format!("failed to parse {}", file.display()))
 }
 
+/// [`parse_config_file`], failing if the config has any of [`Config::problems`].
+pub fn parse_valid_config_file(file: &Path) -> Result<Config> {
+    let config = parse_config_file(file)?;
+    let problems = config.problems();
+    if problems.is_empty() {
+        Ok(config)
+    } else {
+        Err(eyre!(
+            "{} is invalid: {}",
+            file.display(),
+            problems.join("; ")
+        ))
+    }
+}
+
+fn parse_config(contents: &str, yaml: bool) -> Result<Config> {
+    let mut config: serde_json::Value = if yaml {
+        serde_yaml::from_str(contents)?
+    } else {
+        serde_json::from_str(contents)?
+    };
+    interpolate_strings(&mut config, &|var| std::env::var(var).ok())?;
+
+    Ok(serde_json::from_value(config)?)
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in every string in `value`.
+fn interpolate_strings(
+    value: &mut serde_json::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        serde_json::Value::String(string) => *string = interpolate(string, lookup)?,
+        serde_json::Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate_strings(value, lookup)?;
+            }
+        }
+        serde_json::Value::Object(values) => {
+            for value in values.values_mut() {
+                interpolate_strings(value, lookup)?;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn interpolate(string: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut interpolated = String::new();
+    let mut rest = string;
+    while let Some(start) = rest.find("${") {
+        interpolated.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("unterminated ${{ in {:?}", string))?;
+        let reference = &rest[start + 2..start + end];
+        let (var, default) = match reference.split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (reference, None),
+        };
+
+        match (lookup(var), default) {
+            (Some(value), _) => interpolated.push_str(&value),
+            (None, Some(default)) => interpolated.push_str(default),
+            (None, None) => return Err(eyre!("{} isn't set, but {:?} uses it", var, string)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+/// The hardware to run the jobs queued across `hydra_roots`. If some of them can't be reached,
+/// the rest are used unless `require_all_hydras` is set.
 pub async fn get_desired_hardware(
     http_client: &reqwest::Client,
-    hydra_root: &str,
+    hydra_roots: &[String],
+    hydra_bearer_token: Option<&str>,
+    require_all_hydras: bool,
     config_file: &Path,
+    smoothing_alpha: Option<f64>,
+    averages: &mut RunnableAverages,
 ) -> Result<DesiredHardwareConfig> {
-    let config = parse_config_file(config_file)?;
-    let categories = config.categories;
-    let status = http_client
-        .get(format!("{hydra_root}/queue-runner-status"))
-        .header(ACCEPT, "application/json")
-        .send()
-        .await?
-        .json::<QueueRunnerStatus>()
+    let config = parse_valid_config_file(config_file)?;
+    let mut statuses = vec![];
+    for hydra_root in hydra_roots.iter() {
+        statuses.push((
+            hydra_root.as_str(),
+            get_queue_runner_status(http_client, hydra_root, hydra_bearer_token).await,
+        ));
+    }
+    let status = combine_queue_runner_statuses(statuses, require_all_hydras)?;
+    let mut buckets = bucket_runnable(&status);
+
+    let unknown = unknown_systems(&status, &buckets);
+    if !unknown.is_empty() {
+        println!(
+            "WARNING: ignoring {} runnable jobs for {} systems with no hardware: {}",
+            unknown.values().sum::<usize>(),
+            unknown.len(),
+            unknown
+                .iter()
+                .map(|(system, runnable)| format!("{system} ({runnable})"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    print_buckets("Work summary", &buckets);
+
+    if !config.job_weights.is_empty() {
+        buckets = bucket_work(&status, &config.job_weights);
+        print_buckets("Weighted work summary", &buckets);
+    }
+
+    if let Some(alpha) = smoothing_alpha {
+        buckets = smooth_runnable(&buckets, averages, alpha);
+        print_buckets("Smoothed work summary", &buckets);
+    }
+
+    let tags = config.tags();
+    let mut metros = config.metro.clone().into_vec();
+    metros.dedup();
+
+    let categories = config.categories_at(OffsetDateTime::now_utc());
+    let mut desired_hardware = compute_desired(&buckets, &categories, &tags, &metros);
+    let sizing = category_sizing(&buckets, &categories);
+    let minimums = categories
+        .iter()
+        .flat_map(|(system, sizes)| {
+            sizes.iter().map(move |(size, category)| {
+                (category_name(system, size), category.minimum_devices())
+            })
+        })
+        .collect();
+    let priorities = categories
+        .iter()
+        .flat_map(|(system, sizes)| {
+            sizes
+                .iter()
+                .map(move |(size, category)| (category_name(system, size), category.priority))
+        })
+        .collect();
+    for desired in desired_hardware.iter_mut() {
+        desired.ssh_key_ids = config.ssh_key_ids.clone();
+    }
+
+    let mut all_metros = metros;
+    for metro in desired_hardware
+        .iter()
+        .flat_map(|desired| desired.metros.iter())
+    {
+        if !all_metros.contains(metro) {
+            all_metros.push(metro.clone());
+        }
+    }
+
+    Ok(DesiredHardwareConfig {
+        plans: desired_hardware,
+        tags,
+        metros: all_metros,
+        known_tags: config.known_tags(),
+        known_plans: config.all_plans(),
+        known_metros: config.all_metros(),
+        exclude: config.exclude,
+        minimums,
+        priorities,
+        sizing,
+    })
+}
+
+/// An endpoint in front of Hydra that stops it from scheduling steps on a machine, for
+/// `--hydra-drain`. Hydra itself has no such API, so this is whatever the deployment provides.
+pub struct HydraDrain {
+    pub http_client: reqwest::Client,
+    /// The URL to POST to, with `{hostname}` and `{shortid}` placeholders for the device.
+    pub url_template: String,
+    pub bearer_token: Option<String>,
+}
+
+impl Drainer for HydraDrain {
+    async fn drain(&self, device: &Device) -> Result<()> {
+        let url = self
+            .url_template
+            .replace("{hostname}", &device.hostname)
+            .replace("{shortid}", &device.short_id);
+
+        let mut request = self.http_client.post(&url);
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let (status, body) = send(request).await?;
+        if !status.is_success() {
+            return Err(eyre!(
+                "failed to drain {} through {}: {} {}",
+                device.id,
+                url,
+                status,
+                snippet(&body)
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The distinct netboot URLs of `desired` that don't answer a `HEAD`, or a `GET` for servers that
+/// don't support `HEAD`, with success, and what went wrong with each.
+pub async fn unreachable_netboot_urls(
+    http_client: &reqwest::Client,
+    desired: &[DesiredDevice],
+) -> BTreeMap<String, String> {
+    let urls: BTreeSet<String> = desired
+        .iter()
+        .filter_map(|desired| desired.plan.ipxe_script_url())
+        .collect();
+
+    let mut unreachable = BTreeMap::new();
+    for url in urls {
+        let status = match send(http_client.head(&url)).await {
+            Ok((reqwest::StatusCode::METHOD_NOT_ALLOWED, _)) => {
+                send(http_client.get(&url)).await.map(|(status, _)| status)
+            }
+            result => result.map(|(status, _)| status),
+        };
+
+        match status {
+            Ok(status) if status.is_success() => (),
+            Ok(status) => {
+                unreachable.insert(url, format!("it returned {status}"));
+            }
+            Err(e) => {
+                unreachable.insert(url, format!("{e:#}"));
+            }
+        }
+    }
+
+    unreachable
+}
+
+/// Print the runnable jobs for each system and size as a table, dimming the idle ones.
+fn print_buckets(title: &str, buckets: &Buckets) {
+    let bold = anstyle::Style::new().bold();
+    let dim = anstyle::Style::new().dimmed();
+
+    let mut rows: Vec<(&System, &JobSize, usize)> = buckets
+        .iter()
+        .flat_map(|(system, sizes)| {
+            sizes
+                .iter()
+                .map(move |(size, runnable)| (system, size, *runnable))
+        })
+        .collect();
+    rows.sort_by_key(|(system, size, _)| (system.0.clone(), format!("{size:?}")));
+
+    anstream::println!("{bold}{title}:{bold:#}");
+    for (system, size, runnable) in rows {
+        let style = if runnable == 0 {
+            dim
+        } else {
+            anstyle::Style::new()
+        };
+        anstream::println!(
+            "{style}{:<16} {:<12} {:>6}{style:#}",
+            system.0,
+            format!("{size:?}"),
+            runnable
+        );
+    }
+}
+
+pub async fn get_queue_runner_status(
+    http_client: &reqwest::Client,
+    hydra_root: &str,
+    hydra_bearer_token: Option<&str>,
+) -> Result<QueueRunnerStatus> {
+    let fetcher = Fetcher::bearer(http_client, hydra_bearer_token)?;
+
+    // Every scaling decision hinges on this, and Hydra is often briefly unavailable after a
+    // restart, so try for a while before giving up on the run.
+    retry(
+        &format!("fetch {hydra_root}'s queue runner status"),
+        5,
+        std::time::Duration::from_secs(30),
+        std::time::Duration::from_secs(2),
+        || fetch_queue_runner_status(&fetcher, hydra_root),
+    )
+    .await
+}
+
+/// Sum the runnable jobs of each machine type across the Hydras' statuses. Hydras whose status
+/// couldn't be fetched are skipped with a warning, unless `require_all` is set, but at least one
+/// must have succeeded.
+pub fn combine_queue_runner_statuses(
+    statuses: Vec<(&str, Result<QueueRunnerStatus>)>,
+    require_all: bool,
+) -> Result<QueueRunnerStatus> {
+    let mut combined: Option<QueueRunnerStatus> = None;
+    for (hydra_root, status) in statuses {
+        let status = match status {
+            Ok(status) => status,
+            Err(e) if require_all => {
+                return Err(e.wrap_err(format!("failed to get {hydra_root}'s queue")));
+            }
+            Err(e) => {
+                crate::outcome::warn(format!(
+                    "ignoring {}'s queue, it couldn't be fetched: {:?}",
+                    hydra_root, e
+                ));
+                continue;
+            }
+        };
+
+        match combined.as_mut() {
+            Some(combined) => {
+                for (machine_type, status) in status.machine_types {
+                    combined
+                        .machine_types
+                        .entry(machine_type)
+                        .or_insert(MachineTypeStatus { runnable: 0 })
+                        .runnable += status.runnable;
+                }
+            }
+            None => combined = Some(status),
+        }
+    }
+
+    combined.ok_or(eyre!("no Hydra's queue could be fetched"))
+}
+
+pub async fn fetch_queue_runner_status(
+    fetcher: &impl HttpFetcher,
+    hydra_root: &str,
+) -> Result<QueueRunnerStatus> {
+    let raw = fetcher
+        .get_json(&format!("{hydra_root}/queue-runner-status"))
         .await?;
 
-    let mut buckets: HashMap<System, HashMap<JobSize, usize>> = HashMap::from([
+    serde_json::from_value(raw.clone()).wrap_err_with(|| {
+        format!(
+            "failed to parse the queue runner status, here's the start of it: {}",
+            snippet(&raw.to_string())
+        )
+    })
+}
+
+/// The number of runnable jobs Hydra has for each system and job size.
+pub type Buckets = HashMap<System, HashMap<JobSize, usize>>;
+
+pub fn bucket_runnable(status: &QueueRunnerStatus) -> Buckets {
+    let mut buckets: Buckets = HashMap::from([
         (System("aarch64-linux".into()), HashMap::new()),
         (System("x86_64-linux".into()), HashMap::new()),
     ]);
@@ -82,49 +1078,796 @@ pub async fn get_desired_hardware(
         }
     }
 
-    println!("Work summary:");
+    buckets
+}
+
+/// Like [`bucket_runnable`], but with each machine type's jobs weighed by how long they're
+/// estimated to take, per [`Config::job_weights`].
+pub fn bucket_work(status: &QueueRunnerStatus, weights: &BTreeMap<String, f64>) -> Buckets {
+    let mut work: HashMap<System, HashMap<JobSize, f64>> = HashMap::from([
+        (System("aarch64-linux".into()), HashMap::new()),
+        (System("x86_64-linux".into()), HashMap::new()),
+    ]);
+
+    for (key, status) in status.machine_types.iter() {
+        if let Some(bucket) = work.get_mut(&key.system()) {
+            *bucket.entry(key.get_job_size()).or_default() +=
+                status.runnable as f64 * key.weight(weights);
+        }
+    }
+
+    work.into_iter()
+        .map(|(system, sizes)| {
+            let sizes = sizes
+                .into_iter()
+                .map(|(size, work)| (size, work.round() as usize))
+                .collect();
+            (system, sizes)
+        })
+        .collect()
+}
+
+/// Runnable jobs for each system that `bucket_runnable` has no bucket for, so their demand
+/// would otherwise vanish without a trace.
+pub fn unknown_systems(status: &QueueRunnerStatus, buckets: &Buckets) -> BTreeMap<String, usize> {
+    let mut unknown: BTreeMap<String, usize> = BTreeMap::new();
+    for (key, status) in status.machine_types.iter() {
+        let system = key.system();
+        if !buckets.contains_key(&system) && status.runnable > 0 {
+            *unknown.entry(system.0).or_default() += status.runnable;
+        }
+    }
+
+    unknown
+}
+
+/// The exponential moving average of each system and job size's runnable count.
+pub type RunnableAverages = HashMap<System, HashMap<JobSize, f64>>;
+
+/// Blend `buckets` into `averages`, giving the new sample a weight of `alpha`, and return the
+/// averages as buckets. Sizes missing from `buckets` count as nothing runnable, so they decay.
+pub fn smooth_runnable(buckets: &Buckets, averages: &mut RunnableAverages, alpha: f64) -> Buckets {
+    let mut smoothed: Buckets = HashMap::new();
     for (system, sizes) in buckets.iter() {
-        for (size, runnable) in sizes.iter() {
-            println!("{:?} {:?} = {}", system, size, runnable);
+        let system_averages = averages.entry(system.clone()).or_default();
+        for size in sizes.keys() {
+            system_averages
+                .entry(size.clone())
+                .or_insert(sizes[size] as f64);
+        }
+
+        for (size, average) in system_averages.iter_mut() {
+            let runnable = sizes.get(size).copied().unwrap_or(0) as f64;
+            *average = alpha * runnable + (1.0 - alpha) * *average;
+            smoothed
+                .entry(system.clone())
+                .or_default()
+                .insert(size.clone(), average.round() as usize);
         }
     }
 
-    // Decide how many machines we need to make
-    let mut desired_hardware: Vec<HardwarePlan> = vec![];
+    smoothed
+}
+
+/// Pick plans in proportion to their weights, interleaving them so that any prefix of the picks
+/// is spread as evenly as possible.
+fn weighted_cycle(plans: &[HardwarePlan]) -> impl Iterator<Item = &HardwarePlan> {
+    let total: usize = plans.iter().map(|plan| plan.weight).sum();
+    let mut current: Vec<isize> = vec![0; plans.len()];
+
+    // Smooth weighted round-robin: every pick, each plan gains its weight, and the plan furthest
+    // ahead is picked and set back by the total.
+    std::iter::repeat(()).map(move |()| {
+        for (current, plan) in current.iter_mut().zip(plans) {
+            *current += plan.weight as isize;
+        }
+        let (idx, _) = current
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, current)| **current)
+            .expect("categories with no plans are skipped");
+        current[idx] -= total as isize;
+
+        &plans[idx]
+    })
+}
+
+/// Pick plans with [`weighted_cycle`] until their devices provide `slots` between them.
+fn plans_for_slots(plans: &[HardwarePlan], slots: usize) -> impl Iterator<Item = &HardwarePlan> {
+    let mut provided = 0;
+    weighted_cycle(plans).take_while(move |plan| {
+        let needed = provided < slots;
+        // A plan with no slots is reported by `Config::problems`, and mustn't pick forever.
+        provided += max(plan.slots, 1);
+        needed
+    })
+}
+
+/// Decide how many machines we need to make for the runnable jobs in each bucket, cycling through
+/// each category's plans until their slots add up to what the category wants. Every category gets
+/// at least its minimum, even if Hydra has nothing queued for it, so that the minimum is a warm
+/// pool.
+pub fn compute_desired(
+    buckets: &Buckets,
+    categories: &CategoryMap,
+    tags: &[String],
+    metros: &[String],
+) -> Vec<DesiredDevice> {
     for (system, sizes) in buckets.iter() {
-        for (size, runnable) in sizes.iter() {
-            if let Some(category) = categories.get(system).and_then(|e| e.get(size)) {
-                let wanted = min(
-                    category.maximum,
-                    max(category.minimum, runnable / category.divisor),
+        for size in sizes.keys() {
+            if categories.get(system).and_then(|e| e.get(size)).is_none() {
+                println!(
+                    "WARNING: {:?}/{:?} has no hardwarecategory in the hardware map",
+                    system, size
                 );
-                if category.plans.is_empty() {
-                    println!(
-                        "WARNING: {:?}/{:?}'s hardwarecategory has no plans",
-                        system, size
-                    );
+            }
+        }
+    }
 
-                    continue;
-                }
+    let mut desired_hardware: Vec<DesiredDevice> = vec![];
+    for (system, sizes) in categories.iter() {
+        for (size, category) in sizes.iter() {
+            let runnable = buckets
+                .get(system)
+                .and_then(|sizes| sizes.get(size))
+                .copied()
+                .unwrap_or(0);
+            let wanted = category.wanted(runnable);
+            if category.plans.is_empty() {
+                println!(
+                    "WARNING: {:?}/{:?}'s hardwarecategory has no plans",
+                    system, size
+                );
+
+                continue;
+            }
+
+            let category_tags = normalize_tags(tags.iter().chain(category.tags.iter()).cloned());
+            let category_metros = match &category.metro {
+                Some(metro) => metro.clone().into_vec(),
+                None => metros.to_vec(),
+            };
 
-                desired_hardware.extend(category.plans.iter().cycle().take(wanted).cloned());
-            } else {
+            if category.plans.iter().all(|plan| plan.weight == 0) {
                 println!(
-                    "WARNING: {:?}/{:?} has no hardwarecategory in the hardware map",
+                    "WARNING: {:?}/{:?}'s hardwarecategory has no plans with a weight",
                     system, size
                 );
+
+                continue;
             }
+
+            desired_hardware.extend(plans_for_slots(&category.plans, wanted).map(|plan| {
+                DesiredDevice {
+                    system: system.clone(),
+                    size: size.clone(),
+                    plan: plan.clone(),
+                    tags: category_tags.clone(),
+                    metros: category_metros.clone(),
+                    allow_on_demand: category.allow_on_demand,
+                    on_demand: false,
+                    ssh_key_ids: vec![],
+                    project_id: category.project.clone(),
+                    billing_cycle: category.billing_cycle,
+                }
+            }));
         }
     }
 
-    let mut tags = config.tags;
-    tags.dedup();
-    let mut facilities = config.facilities;
-    facilities.dedup();
+    desired_hardware
+}
 
-    Ok(DesiredHardwareConfig {
-        plans: desired_hardware,
-        tags,
-        facilities,
-    })
+#[cfg(test)]
+pub mod hardware_tests {
+    use super::*;
+
+    fn categories(json: serde_json::Value) -> CategoryMap {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn buckets(system: &str, size: JobSize, runnable: usize) -> Buckets {
+        HashMap::from([(System(system.into()), HashMap::from([(size, runnable)]))])
+    }
+
+    fn small_x86(plans: serde_json::Value) -> CategoryMap {
+        categories(serde_json::json!({
+            "x86_64-linux": {
+                "small": { "divisor": 10, "minimum": 1, "maximum": 4, "plans": plans }
+            }
+        }))
+    }
+
+    fn plan(name: &str) -> serde_json::Value {
+        serde_json::json!({ "bid": 1.0, "plan": name, "netboot_url": format!("https://netboot/{name}") })
+    }
+
+    fn plan_names(desired: &[DesiredDevice]) -> Vec<&str> {
+        desired.iter().map(|d| d.plan.plan.as_str()).collect()
+    }
+
+    #[test]
+    fn test_below_minimum() {
+        let categories = small_x86(serde_json::json!([plan("a")]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 5),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(plan_names(&desired), vec!["a"]);
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(Rounding::Floor.divide(9, 10), Some(0));
+        assert_eq!(Rounding::Ceil.divide(1, 10), Some(1));
+        assert_eq!(Rounding::Ceil.divide(20, 10), Some(2));
+        assert_eq!(Rounding::Round.divide(14, 10), Some(1));
+        assert_eq!(Rounding::Round.divide(15, 10), Some(2));
+        assert_eq!(Rounding::Ceil.divide(15, 0), None);
+
+        // Rounding happens before clamping, so the maximum still wins.
+        let categories = categories(serde_json::json!({
+            "x86_64-linux": {
+                "small": {
+                    "divisor": 10, "minimum": 0, "maximum": 2, "rounding": "ceil",
+                    "plans": [plan("a")],
+                }
+            }
+        }));
+        let desired = |runnable| {
+            compute_desired(
+                &buckets("x86_64-linux", JobSize::Small, runnable),
+                &categories,
+                &[],
+                &[],
+            )
+            .len()
+        };
+        assert_eq!(desired(0), 0);
+        assert_eq!(desired(1), 1);
+        assert_eq!(desired(1000), 2);
+    }
+
+    #[test]
+    fn test_above_maximum() {
+        let categories = small_x86(serde_json::json!([plan("a")]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 1000),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(desired.len(), 4);
+    }
+
+    #[test]
+    fn test_empty_plans() {
+        let categories = small_x86(serde_json::json!([]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 1000),
+            &categories,
+            &[],
+            &[],
+        );
+        assert!(desired.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_category() {
+        // Only the small x86_64 category's minimum is wanted: the demand is for other categories.
+        let categories = small_x86(serde_json::json!([plan("a")]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::BigParallel, 1000),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(plan_names(&desired), vec!["a"]);
+
+        let desired = compute_desired(
+            &buckets("aarch64-linux", JobSize::Small, 1000),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(plan_names(&desired), vec!["a"]);
+    }
+
+    #[test]
+    fn test_priority_trimming() {
+        let categories = categories(serde_json::json!({
+            "x86_64-linux": {
+                "small": { "divisor": 1, "minimum": 0, "maximum": 10, "priority": 1, "plans": [plan("x86")] },
+            },
+            "aarch64-linux": {
+                "small": { "divisor": 1, "minimum": 0, "maximum": 10, "plans": [plan("arm")] },
+            },
+        }));
+        let mut buckets = buckets("x86_64-linux", JobSize::Small, 3);
+        buckets.insert(
+            System("aarch64-linux".into()),
+            HashMap::from([(JobSize::Small, 2)]),
+        );
+        let priorities = HashMap::from([("x86_64-linux/Small".to_string(), 1)]);
+
+        let mut desired = compute_desired(&buckets, &categories, &[], &[]);
+        let starved = trim_to_budget(&mut desired, 4, &priorities);
+        assert_eq!(plan_names(&desired), vec!["x86", "x86", "x86", "arm"]);
+        assert_eq!(
+            starved,
+            HashMap::from([((System("aarch64-linux".into()), JobSize::Small), 1)])
+        );
+
+        let dropped = trim_to_spend(&mut desired, 3.0, &priorities);
+        assert_eq!(plan_names(&dropped), vec!["arm"]);
+        assert_eq!(plan_names(&desired), vec!["x86", "x86", "x86"]);
+    }
+
+    #[test]
+    fn test_warm_pool_without_demand() {
+        let categories = small_x86(serde_json::json!([plan("a"), plan("b")]));
+        let status: QueueRunnerStatus = serde_json::from_value(serde_json::json!({})).unwrap();
+        let desired = compute_desired(&bucket_runnable(&status), &categories, &[], &[]);
+        assert_eq!(plan_names(&desired), vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_queue_runner_status() {
+        let responses = HashMap::from([(
+            "https://hydra/queue-runner-status".to_string(),
+            serde_json::json!({
+                "machineTypes": {
+                    "x86_64-linux": { "runnable": 3 },
+                    "x86_64-linux:big-parallel,kvm": { "runnable": 5 },
+                    "aarch64-linux:kvm": { "runnable": 7 },
+                    "armv7l-linux": { "runnable": 11 },
+                }
+            }),
+        )]);
+
+        let status = fetch_queue_runner_status(&responses, "https://hydra")
+            .await
+            .unwrap();
+        let buckets = bucket_runnable(&status);
+        let x86 = &buckets[&System("x86_64-linux".into())];
+        assert_eq!(x86[&JobSize::Small], 3);
+        assert_eq!(x86[&JobSize::BigParallel], 5);
+        assert_eq!(buckets[&System("aarch64-linux".into())][&JobSize::Small], 7);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(
+            unknown_systems(&status, &buckets),
+            BTreeMap::from([("armv7l-linux".to_string(), 11)])
+        );
+
+        let weights = BTreeMap::from([("big-parallel".to_string(), 8.0), ("kvm".to_string(), 1.5)]);
+        let work = bucket_work(&status, &weights);
+        let x86 = &work[&System("x86_64-linux".into())];
+        assert_eq!(x86[&JobSize::Small], 3);
+        assert_eq!(x86[&JobSize::BigParallel], 40);
+        assert_eq!(work[&System("aarch64-linux".into())][&JobSize::Small], 11);
+        assert_eq!(work.len(), 2);
+    }
+
+    #[test]
+    fn test_combine_queue_runner_statuses() {
+        let status = |runnable: usize| -> Result<QueueRunnerStatus> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "machineTypes": {
+                    "x86_64-linux": { "runnable": runnable },
+                    format!("aarch64-linux:{runnable}"): { "runnable": 1 },
+                }
+            }))
+            .unwrap())
+        };
+
+        let combined = combine_queue_runner_statuses(
+            vec![
+                ("https://one", status(3)),
+                ("https://down", Err(eyre!("connection refused"))),
+                ("https://two", status(4)),
+            ],
+            false,
+        )
+        .unwrap();
+        let buckets = bucket_runnable(&combined);
+        assert_eq!(buckets[&System("x86_64-linux".into())][&JobSize::Small], 7);
+        assert_eq!(buckets[&System("aarch64-linux".into())][&JobSize::Small], 2);
+
+        assert!(combine_queue_runner_statuses(
+            vec![
+                ("https://one", status(3)),
+                ("https://down", Err(eyre!("oops")))
+            ],
+            true,
+        )
+        .is_err());
+        assert!(
+            combine_queue_runner_statuses(vec![("https://down", Err(eyre!("oops")))], false)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_queue_runner_status() {
+        let responses = HashMap::from([
+            (
+                "https://empty/queue-runner-status".to_string(),
+                serde_json::json!({}),
+            ),
+            (
+                "https://wrong/queue-runner-status".to_string(),
+                serde_json::json!({ "machineTypes": ["x86_64-linux"] }),
+            ),
+        ]);
+
+        let status = fetch_queue_runner_status(&responses, "https://empty")
+            .await
+            .unwrap();
+        assert!(status.machine_types.is_empty());
+
+        let err = fetch_queue_runner_status(&responses, "https://wrong")
+            .await
+            .unwrap_err();
+        assert!(format!("{err:?}").contains(r#"{"machineTypes":["x86_64-linux"]}"#));
+    }
+
+    #[test]
+    fn test_yaml_config() {
+        let yaml = parse_config(
+            r#"
+# Comments are the point.
+tags: [hydra]
+metro: [da, sv]
+categories:
+  x86_64-linux:
+    small:
+      divisor: 10
+      minimum: 1
+      maximum: 4
+      plans:
+        - bid: 1.0
+          plan: c3.small.x86
+          netboot_url: https://netboot/small
+      schedules:
+        - days: [mon]
+          start: "09:00"
+          end: "17:00"
+          minimum: 2
+"#,
+            true,
+        )
+        .unwrap();
+        let json = parse_config(
+            &serde_json::json!({
+                "tags": ["hydra"],
+                "metro": ["da", "sv"],
+                "categories": {
+                    "x86_64-linux": {
+                        "small": {
+                            "divisor": 10, "minimum": 1, "maximum": 4,
+                            "plans": [
+                                { "bid": 1.0, "plan": "c3.small.x86", "netboot_url": "https://netboot/small" }
+                            ],
+                            "schedules": [
+                                { "days": ["mon"], "start": "09:00", "end": "17:00", "minimum": 2 }
+                            ],
+                        }
+                    }
+                }
+            })
+            .to_string(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(yaml.category_hashes(), json.category_hashes());
+        assert_eq!(yaml.tags(), json.tags());
+        let small = &yaml.categories[&System("x86_64-linux".into())][&JobSize::Small];
+        assert_eq!(small.schedules[0].minimum, Some(2));
+    }
+
+    #[test]
+    fn test_facilities_alias() {
+        let config = parse_config(
+            &serde_json::json!({
+                "tags": ["hydra"],
+                "facilities": ["da", "sv"],
+                "categories": {},
+            })
+            .to_string(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.all_metros(),
+            BTreeSet::from(["da".to_string(), "sv".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |var: &str| (var == "METRO").then(|| "da".to_string());
+        let mut config = serde_json::json!({
+            "metro": "${METRO}",
+            "tags": ["hydra-${ENV:-staging}", "$5 ${METRO}${METRO}"],
+            "divisor": 10,
+        });
+        interpolate_strings(&mut config, &lookup).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "metro": "da",
+                "tags": ["hydra-staging", "$5 dada"],
+                "divisor": 10,
+            })
+        );
+
+        assert!(interpolate("${UNSET}", &lookup).is_err());
+        assert!(interpolate("${METRO", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config_file_is_refused() {
+        let path = std::env::temp_dir().join(format!("scale-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            serde_json::json!({ "tags": [], "metro": [], "categories": {} }).to_string(),
+        )
+        .unwrap();
+
+        assert!(parse_config_file(&path).is_ok());
+        let err = parse_valid_config_file(&path).err().unwrap();
+        assert!(err.to_string().ends_with("is invalid: metro: no metros"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_config_problems() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "tags": [],
+            "metro": [],
+            "job_weights": { "big-parallel": 0.0 },
+            "categories": {
+                "x86_64-linux": {
+                    "small": {
+                        "divisor": 0, "minimum": 1, "maximum": 4,
+                        "plans": [{ "bid": 1.0, "plan": "c3.small.x86" }],
+                        "schedules": [{ "start": "09:00", "end": "17:00", "minimum": 5 }],
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        let mut problems = config.problems();
+        problems.sort();
+        assert_eq!(
+            problems,
+            vec![
+                "job_weights big-parallel: must be positive",
+                "metro: no metros",
+                "x86_64-linux/Small c3.small.x86: custom_ipxe needs a netboot_url",
+                "x86_64-linux/Small schedule 0: divisor is 0",
+                "x86_64-linux/Small schedule 0: minimum 5 is above maximum 4",
+                "x86_64-linux/Small: divisor is 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_category_hashes() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "categories": {
+                "x86_64-linux": {
+                    "small": { "divisor": 10, "minimum": 1, "maximum": 4, "plans": [plan("a"), plan("b")] }
+                }
+            },
+            "tags": ["hydra", "x", "Hydra", "builder", "hydra"],
+            "metro": "da",
+        }))
+        .unwrap();
+        assert_eq!(config.tags(), vec!["builder", "hydra", "x"]);
+
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 1000),
+            &config.categories,
+            &[],
+            &[],
+        );
+        let hashes = config.category_hashes();
+        assert_eq!(hashes.len(), 2);
+        assert!(desired.iter().all(|d| hashes.contains(&d.category_hash())));
+    }
+
+    #[test]
+    fn test_plan_classes() {
+        let mut successor = plan("a");
+        successor["plan"] = serde_json::json!(["c4.large.arm64", "C3.large.arm64"]);
+        let successor: HardwarePlan = serde_json::from_value(successor).unwrap();
+        assert_eq!(successor.plan, "c4.large.arm64");
+        assert!(successor.is_class("c3.large.arm64"));
+        assert!(!successor.is_class("m3.large.x86"));
+
+        let mut empty = plan("a");
+        empty["plan"] = serde_json::json!([]);
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "categories": { "x86_64-linux": { "small": {
+                "divisor": 10, "minimum": 1, "maximum": 4, "plans": [empty],
+            } } },
+            "tags": [],
+            "metro": "da",
+        }))
+        .unwrap();
+        assert_eq!(
+            config.problems(),
+            vec!["x86_64-linux/Small: a plan has no classes"]
+        );
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut cheap = plan("a");
+        cheap["weight"] = 3.into();
+        let mut never = plan("c");
+        never["weight"] = 0.into();
+        let categories = small_x86(serde_json::json!([cheap, plan("b"), never]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 40),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(plan_names(&desired), vec!["a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn test_smooth_runnable() {
+        let mut averages = RunnableAverages::new();
+        let smoothed = smooth_runnable(
+            &buckets("x86_64-linux", JobSize::Small, 100),
+            &mut averages,
+            0.5,
+        );
+        let x86 = System("x86_64-linux".into());
+        assert_eq!(smoothed[&x86][&JobSize::Small], 100);
+
+        let spike = HashMap::from([(
+            x86.clone(),
+            HashMap::from([(JobSize::Small, 300), (JobSize::BigParallel, 8)]),
+        )]);
+        let smoothed = smooth_runnable(&spike, &mut averages, 0.5);
+        assert_eq!(smoothed[&x86][&JobSize::Small], 200);
+        assert_eq!(smoothed[&x86][&JobSize::BigParallel], 8);
+
+        let smoothed = smooth_runnable(
+            &buckets("x86_64-linux", JobSize::Small, 200),
+            &mut averages,
+            0.5,
+        );
+        assert_eq!(smoothed[&x86][&JobSize::Small], 200);
+        assert_eq!(smoothed[&x86][&JobSize::BigParallel], 4);
+    }
+
+    #[test]
+    fn test_scheduled_profile() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "categories": {
+                "x86_64-linux": {
+                    "small": {
+                        "divisor": 10, "minimum": 1, "maximum": 4, "plans": [plan("a")],
+                        "schedules": [
+                            { "days": ["sat", "sun"], "start": "00:00", "end": "23:59", "maximum": 1 },
+                            { "start": "09:00", "end": "17:00", "minimum": 3 },
+                        ],
+                    }
+                }
+            },
+            "tags": [],
+            "metro": "da",
+        }))
+        .unwrap();
+        let wanted = |at: &str| {
+            let now =
+                OffsetDateTime::parse(at, &time::format_description::well_known::Rfc3339).unwrap();
+            compute_desired(
+                &buckets("x86_64-linux", JobSize::Small, 20),
+                &config.categories_at(now),
+                &[],
+                &[],
+            )
+            .len()
+        };
+
+        // 2023-11-13 is a Monday.
+        assert_eq!(wanted("2023-11-13T03:00:00Z"), 2);
+        assert_eq!(wanted("2023-11-13T10:00:00Z"), 3);
+        assert_eq!(wanted("2023-11-18T10:00:00Z"), 1);
+    }
+
+    #[test]
+    fn test_scheduled_profile_across_dst() {
+        let config = parse_config(
+            r#"
+tags: []
+metro: da
+categories:
+  x86_64-linux:
+    small:
+      divisor: 10
+      minimum: 1
+      maximum: 4
+      plans:
+        - bid: 1.0
+          plan: a
+          netboot_url: https://netboot/a
+      schedules:
+        - start: "09:00"
+          end: "17:00"
+          timezone: America/New_York
+          minimum: 3
+"#,
+            true,
+        )
+        .unwrap();
+        let wanted = |at: &str| {
+            let now =
+                OffsetDateTime::parse(at, &time::format_description::well_known::Rfc3339).unwrap();
+            compute_desired(
+                &buckets("x86_64-linux", JobSize::Small, 20),
+                &config.categories_at(now),
+                &[],
+                &[],
+            )
+            .len()
+        };
+
+        // New York's clocks went forward on 2023-03-12, so 13:30 UTC went from 08:30 to 09:30.
+        assert_eq!(wanted("2023-03-10T13:30:00Z"), 2);
+        assert_eq!(wanted("2023-03-13T13:30:00Z"), 3);
+        assert_eq!(wanted("2023-03-10T21:30:00Z"), 3);
+        assert_eq!(wanted("2023-03-13T21:30:00Z"), 2);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let categories = small_x86(serde_json::json!([plan("a"), plan("b")]));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 30),
+            &categories,
+            &[],
+            &[],
+        );
+        assert_eq!(plan_names(&desired), vec!["a", "b", "a"]);
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut large = plan("large");
+        large["slots"] = 4.into();
+        let categories = categories(serde_json::json!({
+            "x86_64-linux": {
+                "small": { "divisor": 10, "minimum": 2, "maximum": 12, "plans": [large, plan("a")] }
+            }
+        }));
+        let desired = compute_desired(
+            &buckets("x86_64-linux", JobSize::Small, 110),
+            &categories,
+            &[],
+            &[],
+        );
+        // 11 slots: 4 + 1 + 4 + 1 falls short, so one more large device makes up the rest.
+        assert_eq!(
+            plan_names(&desired),
+            vec!["large", "a", "large", "a", "large"]
+        );
+
+        let desired = compute_desired(&HashMap::new(), &categories, &[], &[]);
+        assert_eq!(plan_names(&desired), vec!["large"]);
+        let category = &categories[&System("x86_64-linux".into())][&JobSize::Small];
+        assert_eq!(category.minimum_devices(), 1);
+    }
 }
@@ -0,0 +1,553 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use eyre::{eyre, Result, WrapErr};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Serialize;
+
+/// Something that can GET a URL and hand back its JSON body, so that the code interpreting API
+/// responses can be tested without the network.
+pub trait HttpFetcher {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value>;
+}
+
+/// A reqwest client, along with the headers a particular API wants on every request.
+pub struct Fetcher<'a> {
+    pub http_client: &'a reqwest::Client,
+    pub headers: HeaderMap,
+}
+
+impl<'a> Fetcher<'a> {
+    /// A fetcher for the Equinix Metal API.
+    pub fn equinix(http_client: &'a reqwest::Client, equinix_auth_token: &str) -> Result<Self> {
+        let mut headers = json_headers();
+        headers.insert("X-Auth-Token", HeaderValue::from_str(equinix_auth_token)?);
+
+        Ok(Fetcher {
+            http_client,
+            headers,
+        })
+    }
+
+    /// A fetcher for a JSON API, optionally authenticated with a bearer token.
+    pub fn bearer(http_client: &'a reqwest::Client, bearer_token: Option<&str>) -> Result<Self> {
+        let mut headers = json_headers();
+        if let Some(token) = bearer_token {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}"))?,
+            );
+        }
+
+        Ok(Fetcher {
+            http_client,
+            headers,
+        })
+    }
+}
+
+fn json_headers() -> HeaderMap {
+    HeaderMap::from_iter([
+        (ACCEPT, HeaderValue::from_static("application/json")),
+        (CONTENT_TYPE, HeaderValue::from_static("application/json")),
+    ])
+}
+
+impl HttpFetcher for Fetcher<'_> {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value> {
+        send_checked_json(self.http_client.get(url).headers(self.headers.clone())).await
+    }
+}
+
+/// Where `--api-log-dir` records API traffic, if anywhere.
+static API_LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
+static API_LOG_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Record every request made through [`send`] and its response to a file in `dir`.
+pub fn log_api_to(dir: PathBuf) -> Result<()> {
+    std::fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+    API_LOG_DIR
+        .set(dir)
+        .map_err(|_| eyre!("the API log directory was already set"))
+}
+
+/// Credentials that must never appear in anything we print or log.
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Have [`redact`] hide `secret` from now on.
+pub fn register_secret(secret: &str) {
+    if !secret.is_empty() {
+        SECRETS.lock().unwrap().push(secret.to_string());
+    }
+}
+
+/// The text, with every registered secret, like the auth token or project ID, replaced by `***`.
+pub fn redact(text: &str) -> String {
+    SECRETS
+        .lock()
+        .unwrap()
+        .iter()
+        .fold(text.to_string(), |text, secret| text.replace(secret, "***"))
+}
+
+/// How many requests went to a host, and how long they took.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct CallStats {
+    pub calls: usize,
+    /// Requests that got no response, or an unsuccessful one.
+    pub failures: usize,
+    pub total_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+}
+
+impl CallStats {
+    fn record(&mut self, elapsed: Duration, succeeded: bool) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        self.min_ms = if self.calls == 0 {
+            ms
+        } else {
+            self.min_ms.min(ms)
+        };
+        self.max_ms = self.max_ms.max(ms);
+        self.total_ms += ms;
+        self.calls += 1;
+        if !succeeded {
+            self.failures += 1;
+        }
+    }
+
+    pub fn avg_ms(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.total_ms / self.calls as f64
+        }
+    }
+}
+
+static CALL_STATS: Mutex<BTreeMap<String, CallStats>> = Mutex::new(BTreeMap::new());
+
+/// The requests made through [`send`] so far, by host.
+pub fn call_stats() -> BTreeMap<String, CallStats> {
+    CALL_STATS.lock().unwrap().clone()
+}
+
+pub fn print_call_stats() {
+    let stats = call_stats();
+    if stats.is_empty() {
+        return;
+    }
+
+    println!("API calls:");
+    for (host, stats) in stats.iter() {
+        println!(
+            "{}: {} calls, {} failed, {:.0}/{:.0}/{:.0}ms min/avg/max",
+            host,
+            stats.calls,
+            stats.failures,
+            stats.min_ms,
+            stats.avg_ms(),
+            stats.max_ms
+        );
+    }
+}
+
+/// Send the request, returning the response's status and body.
+pub async fn send(request: RequestBuilder) -> Result<(StatusCode, String)> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let host = request.url().host_str().unwrap_or("unknown").to_string();
+    let origin = request.url().origin().ascii_serialization();
+    let logged_request = API_LOG_DIR.get().map(|_| {
+        serde_json::json!({
+            "method": request.method().as_str(),
+            "url": request.url().as_str(),
+            "headers": redacted_headers(request.headers()),
+            "body": request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| body_json(&String::from_utf8_lossy(body))),
+        })
+    });
+
+    let started = std::time::Instant::now();
+    let response = match client.execute(request).await {
+        Ok(response) => {
+            let status = response.status();
+            response.text().await.map(|body| (status, body))
+        }
+        Err(e) => Err(e),
+    };
+    CALL_STATS.lock().unwrap().entry(host).or_default().record(
+        started.elapsed(),
+        matches!(&response, Ok((status, _)) if status.is_success()),
+    );
+
+    if let (Some(dir), Some(logged_request)) = (API_LOG_DIR.get(), logged_request) {
+        let method = logged_request["method"]
+            .as_str()
+            .unwrap_or("REQUEST")
+            .to_string();
+        let logged_response = match &response {
+            Ok((status, body)) => {
+                serde_json::json!({ "status": status.as_u16(), "body": body_json(body) })
+            }
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+        let exchange = serde_json::json!({
+            "request": logged_request,
+            "response": logged_response,
+        });
+        let path = dir.join(format!(
+            "{}-{:05}-{}.json",
+            time::OffsetDateTime::now_utc().unix_timestamp(),
+            API_LOG_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            method
+        ));
+        if let Err(e) = std::fs::write(&path, redact(&serde_json::to_string_pretty(&exchange)?)) {
+            println!("WARNING: failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    response.map_err(|e| {
+        let cause = describe_failure(&origin, &e);
+        // The error quotes the full URL, which can hold the project ID, so it's redacted rather
+        // than kept as the source.
+        eyre!(redact(&error_chain(&e))).wrap_err(cause)
+    })
+}
+
+/// The error and each of its sources, joined like `{:#}` does for eyre reports.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(err) = source {
+        chain.push_str(": ");
+        chain.push_str(&err.to_string());
+        source = err.source();
+    }
+
+    chain
+}
+
+/// Why a request to `origin` got no response, in terms of what to go and check.
+fn describe_failure(origin: &str, e: &reqwest::Error) -> String {
+    let mut chain = String::new();
+    let mut source: Option<&dyn std::error::Error> = Some(e);
+    while let Some(err) = source {
+        chain.push_str(&err.to_string().to_lowercase());
+        chain.push('\n');
+        source = err.source();
+    }
+
+    if e.is_timeout() {
+        format!("{origin} timed out, it may be overloaded or unreachable from here")
+    } else if chain.contains("dns error") || chain.contains("failed to lookup address") {
+        format!("couldn't resolve {origin}, check its hostname and DNS records")
+    } else if chain.contains("certificate") || chain.contains("tls") || chain.contains("handshake")
+    {
+        format!(
+            "TLS with {origin} failed, its certificate may be expired, self-signed, or for \
+             another hostname"
+        )
+    } else if e.is_connect() {
+        format!(
+            "couldn't connect to {origin}, check that it's up and its port is reachable from here"
+        )
+    } else {
+        format!("the request to {origin} failed")
+    }
+}
+
+/// Fail with the status, and a hint of what to check, unless the response was successful.
+pub fn check_status(url: &str, status: StatusCode, body: &str) -> Result<()> {
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let hint = match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ", check its token",
+        StatusCode::NOT_FOUND => ", check the URL",
+        _ if status.is_server_error() => ", it may be down or restarting",
+        _ => "",
+    };
+    Err(eyre!(redact(&format!(
+        "{} responded {}{}, here's the start of it: {:?}",
+        url,
+        status,
+        hint,
+        snippet(body)
+    ))))
+}
+
+/// Like [`send`], but parsing the response as JSON. An empty response is `null`.
+pub async fn send_json(request: RequestBuilder) -> Result<serde_json::Value> {
+    let (status, body) = send(request).await?;
+    if body.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+
+    serde_json::from_str(&body).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json from a {} response, here's the start of it: {:?}",
+            status,
+            snippet(&body)
+        ))
+    })
+}
+
+/// Like [`send_json`], but failing unless the response was successful.
+pub async fn send_checked_json(request: RequestBuilder) -> Result<serde_json::Value> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let mut url = request.url().clone();
+    url.set_query(None);
+
+    let (status, body) = send(RequestBuilder::from_parts(client, request)).await?;
+    check_status(url.as_str(), status, &body)?;
+    if body.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+
+    serde_json::from_str(&body).wrap_err_with(|| {
+        redact(&format!(
+            "failed to parse json from {}, here's the start of it: {:?}",
+            url,
+            snippet(&body)
+        ))
+    })
+}
+
+/// Call `f` until it succeeds, up to `attempts` times. Each attempt gets `timeout`, and the wait
+/// between attempts starts at `backoff` and doubles.
+pub async fn retry<T, F, Fut>(
+    what: &str,
+    attempts: usize,
+    timeout: Duration,
+    mut backoff: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = match tokio::time::timeout(timeout, f()).await {
+            Ok(result) => result,
+            Err(_) => Err(eyre!("timed out after {:?}", timeout)),
+        };
+
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= attempts => {
+                return Err(e.wrap_err(format!("failed to {what} after {attempts} attempts")))
+            }
+            Err(e) => {
+                println!(
+                    "WARNING: failed to {} (attempt {}/{}), retrying in {:?}: {:?}",
+                    what, attempt, attempts, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// The start of a response body, short enough to quote in an error message.
+pub fn snippet(body: &str) -> String {
+    const MAX_CHARS: usize = 500;
+
+    match body.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+/// The headers, with anything that could authenticate as us blanked out.
+fn redacted_headers(headers: &HeaderMap) -> serde_json::Map<String, serde_json::Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION || name.as_str() == "x-auth-token" {
+                "REDACTED".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+            (name.to_string(), value.into())
+        })
+        .collect()
+}
+
+/// The body as JSON if it is JSON, so that it's pretty-printed in the log, or as a string.
+fn body_json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_else(|_| body.into())
+}
+
+/// Canned responses by URL.
+#[cfg(test)]
+impl HttpFetcher for std::collections::HashMap<String, serde_json::Value> {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value> {
+        self.get(url)
+            .cloned()
+            .ok_or(eyre!("no response for {}", url))
+    }
+}
+
+/// Equinix reports failures as `{"errors": [...]}`, which should be surfaced as-is rather than as
+/// a confusing parse failure.
+pub fn check_error_envelope(raw: &serde_json::Value) -> Result<()> {
+    match raw.get("errors") {
+        Some(errors) => Err(eyre!("the API returned errors: {}", errors)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+pub mod http_tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = Fetcher::equinix(&reqwest::Client::new(), "secret")
+            .unwrap()
+            .headers;
+        headers.extend(
+            Fetcher::bearer(&reqwest::Client::new(), Some("hunter2"))
+                .unwrap()
+                .headers,
+        );
+
+        let redacted = redacted_headers(&headers);
+        assert_eq!(redacted["x-auth-token"], "REDACTED");
+        assert_eq!(redacted["authorization"], "REDACTED");
+        assert_eq!(redacted["accept"], "application/json");
+        assert!(!serde_json::to_string(&redacted).unwrap().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let calls = AtomicUsize::new(0);
+        let flaky = || async {
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(eyre!("hiccup")),
+                1 => std::future::pending().await,
+                n => Ok(n),
+            }
+        };
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(
+            retry("be flaky", 3, timeout, Duration::ZERO, flaky)
+                .await
+                .unwrap(),
+            2
+        );
+
+        calls.store(0, Ordering::Relaxed);
+        assert!(retry("be flaky", 2, timeout, Duration::ZERO, flaky)
+            .await
+            .is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_call_stats() {
+        let mut stats = CallStats::default();
+        assert_eq!(stats.avg_ms(), 0.0);
+
+        stats.record(Duration::from_millis(30), true);
+        stats.record(Duration::from_millis(10), false);
+        stats.record(Duration::from_millis(20), true);
+        assert_eq!(stats.calls, 3);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.min_ms, 10.0);
+        assert_eq!(stats.max_ms, 30.0);
+        assert_eq!(stats.avg_ms(), 20.0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_failures_are_described() {
+        // Nothing listens on the port once the listener's dropped.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{port}/queue-runner-status");
+        let err = send(reqwest::Client::new().get(&url)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("couldn't connect to http://127.0.0.1:{port}, check that it's up and its port is reachable from here")
+        );
+
+        // Accepts connections, but never responds.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = send(client.get(&url)).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("timed out, it may be overloaded or unreachable from here"));
+    }
+
+    #[tokio::test]
+    async fn test_transport_errors_are_redacted() {
+        register_secret("project-5678");
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{port}/metal/v1/projects/project-5678/devices");
+        let err = send(reqwest::Client::new().get(&url)).await.unwrap_err();
+        let shown = format!("{err:#} {err:?}");
+        assert!(shown.contains("/projects/***/devices"), "{shown}");
+        assert!(!shown.contains("project-5678"), "{shown}");
+    }
+
+    #[test]
+    fn test_check_status() {
+        assert!(check_status("https://hydra", StatusCode::OK, "").is_ok());
+        assert_eq!(
+            check_status("https://hydra", StatusCode::FORBIDDEN, "go away")
+                .unwrap_err()
+                .to_string(),
+            "https://hydra responded 403 Forbidden, check its token, here's the start of it: \"go away\""
+        );
+        assert!(check_status("https://hydra", StatusCode::BAD_GATEWAY, "")
+            .unwrap_err()
+            .to_string()
+            .contains("it may be down or restarting"));
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("short"), "short");
+        let long = "é".repeat(600);
+        assert_eq!(snippet(&long), format!("{}...", "é".repeat(500)));
+    }
+
+    #[test]
+    fn test_redact() {
+        register_secret("");
+        register_secret("project-1234");
+        assert_eq!(
+            redact("https://api.equinix.com/metal/v1/projects/project-1234/devices"),
+            "https://api.equinix.com/metal/v1/projects/***/devices"
+        );
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+    }
+}
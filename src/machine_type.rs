@@ -1,35 +1,50 @@
-use serde::Deserialize;
+use std::collections::BTreeMap;
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize)]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum JobSize {
     Small,
     BigParallel,
 }
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize)]
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(transparent)]
 pub struct System(pub String);
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Feature(String);
 
+/// A Hydra machine type, like `x86_64-linux:big-parallel,kvm`: a system, then optionally a colon
+/// and a comma-separated list of features. Any further colons separate features too, so
+/// `x86_64-linux:kvm:big-parallel` has both features. Features are trimmed and lowercased, since
+/// Hydra doesn't care about either.
 #[derive(Deserialize, Debug, Hash, Eq, PartialEq)]
 pub struct MachineType(String);
 
 impl MachineType {
     pub fn system(&self) -> System {
-        System(self.0.split(':').next().unwrap().to_string())
+        let system = match self.0.split_once(':') {
+            Some((system, _)) => system,
+            None => &self.0,
+        };
+
+        System(system.trim().to_string())
     }
 
     pub fn features(&self) -> Vec<Feature> {
-        self.0
-            .split(':')
-            .nth(1)
-            .unwrap_or("")
-            .split(',')
+        let features = match self.0.split_once(':') {
+            Some((_, features)) => features,
+            None => "",
+        };
+
+        features
+            .split([',', ':'])
+            .map(|x| x.trim())
             .filter(|x| !x.is_empty())
-            .map(|x| x.to_string())
+            .map(|x| x.to_lowercase())
             .map(Feature)
             .collect()
     }
@@ -41,6 +56,19 @@ impl MachineType {
             JobSize::Small
         }
     }
+
+    /// How much work one of this machine type's jobs is, given an estimate for each feature
+    /// relative to a job needing none: the largest estimate of its features, or 1 if none of
+    /// them have one.
+    pub fn weight(&self, weights: &BTreeMap<String, f64>) -> f64 {
+        let features = self.features();
+        weights
+            .iter()
+            .filter(|(feature, _)| features.contains(&Feature(feature.trim().to_lowercase())))
+            .map(|(_, weight)| *weight)
+            .reduce(f64::max)
+            .unwrap_or(1.0)
+    }
 }
 
 #[cfg(test)]
@@ -53,4 +81,50 @@ pub mod machinetype_tests {
         assert_eq!(mt.system(), System("".to_string()));
         assert_eq!(mt.features(), vec![]);
     }
+
+    #[test]
+    fn test_no_features() {
+        let mt = MachineType(" x86_64-linux ".to_string());
+        assert_eq!(mt.system(), System("x86_64-linux".to_string()));
+        assert_eq!(mt.features(), vec![]);
+
+        let mt = MachineType("x86_64-linux:".to_string());
+        assert_eq!(mt.system(), System("x86_64-linux".to_string()));
+        assert_eq!(mt.features(), vec![]);
+    }
+
+    #[test]
+    fn test_extra_colons() {
+        let mt = MachineType("x86_64-linux:kvm:big-parallel".to_string());
+        assert_eq!(mt.system(), System("x86_64-linux".to_string()));
+        assert_eq!(
+            mt.features(),
+            vec![Feature("kvm".into()), Feature("big-parallel".into())]
+        );
+        assert_eq!(mt.get_job_size(), JobSize::BigParallel);
+
+        let mt = MachineType(":kvm".to_string());
+        assert_eq!(mt.system(), System("".to_string()));
+        assert_eq!(mt.features(), vec![Feature("kvm".into())]);
+    }
+
+    #[test]
+    fn test_spaced_features() {
+        let mt = MachineType("x86_64-linux: Big-Parallel , kvm,,".to_string());
+        assert_eq!(
+            mt.features(),
+            vec![Feature("big-parallel".into()), Feature("kvm".into())]
+        );
+        assert_eq!(mt.get_job_size(), JobSize::BigParallel);
+    }
+
+    #[test]
+    fn test_weight() {
+        let weights = BTreeMap::from([("Big-Parallel".to_string(), 8.0), ("kvm".to_string(), 2.0)]);
+        let weight = |mt: &str| MachineType(mt.to_string()).weight(&weights);
+        assert_eq!(weight("x86_64-linux"), 1.0);
+        assert_eq!(weight("x86_64-linux:kvm"), 2.0);
+        assert_eq!(weight("x86_64-linux:kvm,big-parallel"), 8.0);
+        assert_eq!(weight("x86_64-linux:benchmark"), 1.0);
+    }
 }
@@ -1,168 +1,1884 @@
-use std::{collections::BTreeSet, path::PathBuf};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use eyre::Result;
+use eyre::{eyre, Result, WrapErr};
+use provider::{CloudProvider, JobCounter};
 use time::OffsetDateTime;
 
+mod audit;
 mod device;
 mod hardware;
+mod http;
 mod machine_type;
+mod notify;
+mod outcome;
+mod provider;
+mod reconcile;
+mod schedule;
+mod shutdown;
+mod state;
 
 /// A tool for providing autoscaling for a Hydra instance via Equinix Metal.
 #[derive(Parser, Debug)]
 #[clap(author, version)]
 struct Cli {
-    /// The root of the Hydra instance used as a basis for autoscaling.
+    /// The root of the Hydra instance used as a basis for autoscaling. Repeat it to scale for the
+    /// combined queues of several Hydras sharing the fleet.
     #[clap(long, default_value = "https://hydra.nixos.org")]
-    hydra_root: String,
+    hydra_root: Vec<String>,
+
+    /// Fail if any --hydra-root can't be reached, rather than scaling for the rest.
+    #[clap(long)]
+    require_all_hydras: bool,
 
     /// The root of the Prometheus server that contains information about Hydra machines.
     #[clap(long, default_value = "https://status.nixos.org/prometheus")]
     prometheus_root: String,
 
-    /// A JSON description of machines and their Nix system types and job sizes, and the tags and
-    /// facilities with which to create the machines.
-    #[clap(long, required = true)]
-    config_file: PathBuf,
+    /// The Prometheus query for how many jobs a machine is running. `{shortid}` and `{hostname}`
+    /// are replaced with the device's short ID and hostname.
+    #[clap(
+        long,
+        default_value = r#"hydra_machine_current_jobs{host="root@{shortid}.packethost.net"}"#
+    )]
+    prometheus_query_template: String,
+
+    /// How to combine a machine's job counts when Prometheus has more than one series for it.
+    #[clap(long, value_enum, default_value_t = device::JobAggregation::Sum)]
+    prometheus_aggregation: device::JobAggregation,
+
+    /// Treat Prometheus job counts older than this many seconds as unknown, and don't destroy
+    /// their devices.
+    #[clap(long)]
+    max_sample_age_secs: Option<i64>,
+
+    /// A bearer token to authenticate to Prometheus with.
+    #[clap(long, conflicts_with = "prometheus_bearer_token_file")]
+    prometheus_bearer_token: Option<String>,
+
+    /// A file containing a bearer token to authenticate to Prometheus with.
+    #[clap(long)]
+    prometheus_bearer_token_file: Option<PathBuf>,
+
+    /// A bearer token to authenticate to Hydra with.
+    #[clap(long, conflicts_with = "hydra_bearer_token_file")]
+    hydra_bearer_token: Option<String>,
+
+    /// A file containing a bearer token to authenticate to Hydra with.
+    #[clap(long)]
+    hydra_bearer_token_file: Option<PathBuf>,
+
+    /// A URL to POST to when draining a device, so that Hydra stops scheduling steps on it
+    /// rather than relying on the drain tag alone. `{hostname}` and `{shortid}` are replaced with
+    /// the device's hostname and short ID, and the Hydra bearer token is sent if given.
+    #[clap(long)]
+    hydra_drain: Option<String>,
+
+    /// A PEM certificate to trust in addition to the system's roots, for Hydra or Prometheus
+    /// instances behind a private CA.
+    #[clap(long)]
+    extra_ca_cert: Option<PathBuf>,
+
+    /// A proxy to send every request through. Without it, the standard HTTPS_PROXY, HTTP_PROXY,
+    /// and NO_PROXY environment variables are honored.
+    #[clap(long)]
+    https_proxy: Option<String>,
+
+    /// A comma-separated list of hosts, domains, and IP ranges to reach directly rather than
+    /// through --https-proxy.
+    #[clap(long, requires = "https_proxy")]
+    no_proxy: Option<String>,
+
+    /// The User-Agent to send on every request, so the scaler's traffic is easy to pick out of
+    /// API logs.
+    #[clap(long, default_value = concat!("hydra-scale-equinix-metal/", env!("CARGO_PKG_VERSION")))]
+    user_agent: String,
+
+    /// Write every API request and its raw response to a file in this directory, with
+    /// credentials redacted, for debugging.
+    #[clap(long)]
+    api_log_dir: Option<PathBuf>,
+
+    /// Append a line of JSON to this file for every device created, destroyed, reinstalled, or
+    /// rebooted, saying when and why, synced to disk as it's written.
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// A JSON (or, with a `.yaml` or `.yml` extension, YAML) description of machines and their Nix
+    /// system types and job sizes, and the tags and metros with which to create the machines.
+    /// Required by every subcommand but `schema`.
+    #[clap(long)]
+    config_file: Option<PathBuf>,
+
+    /// A file containing the Equinix Metal auth token, used instead of METAL_AUTH_TOKEN.
+    #[clap(long, env = "METAL_AUTH_TOKEN_FILE")]
+    auth_token_file: Option<PathBuf>,
+
+    /// A file containing the Equinix Metal project ID, used instead of METAL_PROJECT_ID.
+    #[clap(long, env = "METAL_PROJECT_ID_FILE")]
+    project_id_file: Option<PathBuf>,
+
+    /// An Equinix Metal project to manage devices in, instead of METAL_PROJECT_ID. Repeat it to
+    /// manage a fleet split across projects: devices are created in the first, unless their
+    /// category names another.
+    #[clap(long = "project-id")]
+    project_ids: Vec<String>,
+
+    /// The Equinix Metal API to talk to, like a mock server for testing.
+    #[clap(long, default_value = device::DEFAULT_API_BASE)]
+    equinix_api_base: String,
+
+    /// The hostname for created devices. `{plan}`, `{metro}`, `{system}`, `{timestamp}`, and
+    /// `{short_random}` are filled in, and the result is made DNS-safe. With
+    /// --spot-market-requests it must have `{short_random}`, since a request's devices are only
+    /// told apart by renaming them once they exist.
+    #[clap(long, default_value = "{plan}-{metro}-{short_random}")]
+    hostname_template: String,
+
+    /// The tag given to devices that should stop receiving new jobs from Hydra before they are
+    /// destroyed.
+    #[clap(long, default_value = "skip-hydra")]
+    drain_tag: String,
+
+    /// Which devices to manage, by how their tags compare to the config's.
+    #[clap(long, value_enum, default_value_t)]
+    tag_match_mode: device::TagMatch,
+
+    /// A URL to POST a JSON summary to after each `scale` run that changed something or failed.
+    #[clap(long)]
+    notify_webhook: Option<String>,
+
+    /// Send the --notify-webhook summary as a Slack message.
+    #[clap(long, requires = "notify_webhook")]
+    notify_slack: bool,
+
+    /// A device ID, short ID, or hostname never to drain or destroy, in addition to the config's
+    /// `exclude` list. Can be given more than once.
+    #[clap(long = "exclude-device")]
+    exclude_devices: Vec<String>,
+
+    /// Don't drain or destroy devices younger than this, unless they're past the urgent
+    /// termination date, so that a brief dip in demand doesn't waste their minimum billing.
+    #[clap(long, default_value_t = 0)]
+    min_device_lifetime_mins: i64,
+
+    /// The most device creates, tags, or destroys to have in flight at once.
+    #[clap(long, default_value_t = reconcile::DEFAULT_MAX_CONCURRENCY)]
+    max_concurrency: usize,
+
+    /// The most devices past the urgent termination date to destroy in one run while they're
+    /// still running jobs. The rest wait until they're idle.
+    #[clap(long)]
+    max_urgent_destroys_per_run: Option<usize>,
+
+    /// Have Equinix destroy devices this many hours after creating them, as a backstop against
+    /// the scaler leaking devices while it isn't running.
+    #[clap(long)]
+    device_ttl_hours: Option<i64>,
+
+    /// Stop starting device operations once the run has taken this long, and fail the run, so
+    /// that a slow run can't overlap the next one. Anything still going a minute later is
+    /// abandoned.
+    #[clap(long)]
+    run_deadline_secs: Option<u64>,
+
+    /// Wait a random time of up to this long before starting, so that scalers started together
+    /// don't all hit the APIs at once. The wait doesn't count towards --run-deadline-secs.
+    #[clap(long)]
+    start_jitter_secs: Option<u64>,
+
+    /// A JSON file to remember things between runs in, like recent runnable counts and how long
+    /// each device has been idle. A missing or corrupt file is treated like a first run.
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Create and destroy devices to match Hydra's queue.
+    Scale(ScaleArgs),
+    /// Print what `scale` would do, without changing anything.
+    Plan(PlanArgs),
+    /// Print the managed devices and how many jobs they're running.
+    #[clap(alias = "status")]
+    List(ListArgs),
+    /// Destroy managed devices that are stuck provisioning or no longer in the config.
+    Gc(GcArgs),
+    /// Drain every managed device and destroy the idle ones, ignoring the config's categories
+    /// and maintenance windows. Run it again to destroy the rest once they're idle.
+    ScaleToZero(ScaleToZeroArgs),
+    /// Print a JSON Schema for the config file, for editors to complete and check it with.
+    Schema,
+    /// Check the config file for mistakes, without talking to any API.
+    Validate,
+    /// Check that the credentials, projects, metros, Hydra, and Prometheus all work, without
+    /// changing anything.
+    Preflight,
+}
+
+#[derive(clap::Args, Debug)]
+struct PlanArgs {
+    /// Scale off an exponential moving average of runnable counts across runs instead of the
+    /// latest count, giving the latest count this weight from 0 to 1. Needs --state-file.
+    #[clap(long)]
+    smoothing_alpha: Option<f64>,
+
+    /// Create devices through Equinix spot market requests, asking for all identical devices in
+    /// one request instead of creating them one by one.
+    #[clap(long)]
+    spot_market_requests: bool,
+
+    /// How to choose the bid for created devices: the configured bid, or the current spot market
+    /// price scaled by --bid-multiplier and capped at the configured bid.
+    #[clap(long, value_enum, default_value_t = BidStrategy::Static)]
+    bid_strategy: BidStrategy,
+
+    /// How far above the current spot market price to bid, with --bid-strategy=market.
+    #[clap(long, default_value_t = 1.5)]
+    bid_multiplier: f64,
+
+    /// The most devices to run at once, across all categories.
+    #[clap(long)]
+    max_total_devices: Option<usize>,
+
+    /// The most to bid for the whole fleet, in dollars per hour. Creates are skipped,
+    /// most expensive first, to stay under it.
+    #[clap(long)]
+    max_hourly_spend: Option<f64>,
+
+    /// The most devices to create in a single run.
+    #[clap(long)]
+    max_creates_per_run: Option<usize>,
+
+    /// The most devices to drain or destroy in a single run.
+    #[clap(long)]
+    max_destroys_per_run: Option<usize>,
+
+    /// Which devices to drain and destroy first, which decides what's spared by
+    /// `--max-destroys-per-run`.
+    #[clap(long, value_enum, default_value_t = TeardownOrder::Unordered)]
+    teardown_order: TeardownOrder,
+
+    /// What to do with managed devices that Equinix reports no iPXE script URL for, when their
+    /// plan boots one.
+    #[clap(long, value_enum, default_value_t = reconcile::MissingIpxeUrl::Replace)]
+    missing_ipxe_url: reconcile::MissingIpxeUrl,
+
+    /// How to print the summary of the plan's changes: as text, or as JSON for review tooling.
+    #[clap(long, value_enum, default_value_t = Output::Table)]
+    diff_output: Output,
+
+    /// Print why each managed device is kept or deleted: its age against the cutoffs, what it
+    /// matched, its job count, and whether it's draining.
+    #[clap(long)]
+    explain: bool,
 }
 
+#[derive(clap::Args, Debug)]
+struct ScaleArgs {
+    #[clap(flatten)]
+    plan: PlanArgs,
+
+    /// Only cycle out a category's old devices once the replacements created for it are active,
+    /// so that capacity doesn't dip while they provision.
+    #[clap(long)]
+    rolling: bool,
+
+    /// Reinstall idle devices whose netboot URL has changed, rather than replacing them, so that
+    /// they keep their spot reservation.
+    #[clap(long)]
+    reinstall_drifted: bool,
+
+    /// Before destroying an active device that has been idle for the last few runs, reboot it
+    /// once in case it's wedged, and check it again next run. Needs --state-file.
+    #[clap(long)]
+    reboot_before_destroy: bool,
+
+    /// Only drain devices, never destroy them, leaving that to --device-ttl-hours or to an
+    /// operator.
+    #[clap(long)]
+    no_destroy: bool,
+
+    /// Check that each netboot URL to create devices with responds successfully first, and warn
+    /// about or refuse to create the devices whose URL doesn't.
+    #[clap(long, value_enum)]
+    verify_netboot: Option<VerifyNetboot>,
+
+    /// Don't ask for confirmation before creating, tagging, or destroying devices.
+    #[clap(long)]
+    yes: bool,
+
+    /// Stop creating a category's devices after this many creates in a row fail, across runs
+    /// with --state-file.
+    #[clap(long, default_value_t = 10)]
+    halt_creates_after_failures: usize,
+
+    /// How long a category's creates stay halted before one is tried again.
+    #[clap(long, default_value_t = 60)]
+    create_halt_cooldown_mins: i64,
+
+    /// At the end of the run, print each category's runnable jobs, sizing, and devices had,
+    /// created, kept, drained, and destroyed.
+    #[clap(long, value_enum)]
+    category_report: Option<Output>,
+
+    /// After applying the plan once, keep running and print what a plan would change every
+    /// --watch-interval-secs, without changing anything until restarted.
+    #[clap(long)]
+    watch_only_after_apply: bool,
+
+    /// How often to plan with --watch-only-after-apply.
+    #[clap(long, default_value_t = 300)]
+    watch_interval_secs: u64,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum VerifyNetboot {
+    Warn,
+    Refuse,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    #[clap(long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+
+    /// List the spot instances that aren't managed instead, like ones created by hand or by a
+    /// run that crashed before tagging them.
+    #[clap(long)]
+    untagged: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Table,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+struct GcArgs {
+    /// How long a device may stay provisioning or queued before it's considered stuck.
+    #[clap(long, default_value_t = 60)]
+    stuck_after_mins: i64,
+
+    /// Give the spot instances that aren't managed the config's tags, so that `scale` manages
+    /// them from now on. See `list --untagged`.
+    #[clap(long)]
+    adopt: bool,
+
+    /// Don't ask for confirmation before tagging or destroying devices.
+    #[clap(long)]
+    yes: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScaleToZeroArgs {
+    /// Destroy devices even if they're running jobs.
+    #[clap(long)]
+    force: bool,
+
+    /// Don't ask for confirmation before tagging or destroying devices.
+    #[clap(long)]
+    yes: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum BidStrategy {
+    Static,
+    Market,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TeardownOrder {
+    /// Whatever order reconciliation produced.
+    Unordered,
+    /// The longest-running devices first.
+    Oldest,
+    /// The devices running the fewest jobs first, with devices whose jobs are unknown last.
+    LeastLoaded,
+}
+
+/// How long past `--run-deadline-secs` the operation in progress gets to finish.
+const DEADLINE_GRACE: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long `--rolling` waits for replacement devices to become active.
+const ROLLING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How many runs in a row a device must have been idle for `--reboot-before-destroy` to suspect
+/// it's wedged.
+const WEDGED_AFTER_IDLE_RUNS: u32 = 3;
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> std::process::ExitCode {
+    let result = try_main().await;
+    if let Err(e) = &result {
+        eprintln!("Error: {e:?}");
+    }
+
+    std::process::ExitCode::from(outcome::exit_code(&result))
+}
+
+async fn try_main() -> Result<()> {
     let args = Cli::parse();
 
-    let equinix_auth_token =
-        std::env::var("METAL_AUTH_TOKEN").expect("Please set METAL_AUTH_TOKEN");
-    let equinix_project_id =
-        std::env::var("METAL_PROJECT_ID").expect("Please set METAL_PROJECT_ID");
-
-    real_main(
-        equinix_auth_token,
-        equinix_project_id,
-        args.hydra_root,
-        args.prometheus_root,
-        args.config_file,
-    )
-    .await
+    // These only look at the config, so they don't need credentials.
+    match &args.command {
+        Command::Schema => {
+            let schema = schemars::schema_for!(hardware::Config);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Command::Validate => return validate(&required_config_file(args.config_file)?),
+        _ => (),
+    }
+
+    let equinix_auth_token = read_secret(args.auth_token_file.as_deref(), "METAL_AUTH_TOKEN")?;
+    let equinix_project_ids = if args.project_ids.is_empty() {
+        vec![read_secret(
+            args.project_id_file.as_deref(),
+            "METAL_PROJECT_ID",
+        )?]
+    } else {
+        args.project_ids.clone()
+    };
+
+    http::register_secret(&equinix_auth_token);
+    for project_id in equinix_project_ids.iter() {
+        http::register_secret(project_id);
+    }
+
+    // Errors can quote URLs and responses, so keep the credentials out of them.
+    real_main(equinix_auth_token, equinix_project_ids, args)
+        .await
+        .map_err(|e| eyre!(http::redact(&format!("{e:?}"))))
+}
+
+/// Everything the subcommands share, built from the top-level flags.
+struct Context {
+    http_client: reqwest::Client,
+    prometheus: device::Prometheus,
+    provider: device::EquinixMetal,
+    hydra_roots: Vec<String>,
+    hydra_bearer_token: Option<String>,
+    require_all_hydras: bool,
+    hydra_drain: Option<hardware::HydraDrain>,
+    notify_webhook: Option<notify::Webhook>,
+    config_file: PathBuf,
+    drain_tag: String,
+    tag_match_mode: device::TagMatch,
+    exclude_devices: Vec<String>,
+    state_file: Option<PathBuf>,
+    /// Devices created before this are cycled out.
+    older_than: OffsetDateTime,
+    /// Devices created before this are destroyed even if they're running jobs, when the config
+    /// allows it.
+    urgently_terminate: OffsetDateTime,
+    max_urgent_destroys: Option<usize>,
+    /// Devices created after this are too young to destroy.
+    young_after: OffsetDateTime,
 }
 
 async fn real_main(
     equinix_auth_token: String,
-    equinix_project_id: String,
-    hydra_root: String,
-    prometheus_root: String,
-    config_file: PathBuf,
+    equinix_project_ids: Vec<String>,
+    args: Cli,
 ) -> Result<()> {
+    let Cli {
+        hydra_root,
+        require_all_hydras,
+        prometheus_root,
+        prometheus_query_template,
+        prometheus_aggregation,
+        max_sample_age_secs,
+        prometheus_bearer_token,
+        prometheus_bearer_token_file,
+        hydra_bearer_token,
+        hydra_bearer_token_file,
+        hydra_drain,
+        extra_ca_cert,
+        https_proxy,
+        no_proxy,
+        user_agent,
+        api_log_dir,
+        audit_log,
+        config_file,
+        hostname_template,
+        drain_tag,
+        tag_match_mode,
+        notify_webhook,
+        notify_slack,
+        exclude_devices,
+        min_device_lifetime_mins,
+        max_urgent_destroys_per_run,
+        max_concurrency,
+        device_ttl_hours,
+        run_deadline_secs,
+        start_jitter_secs,
+        equinix_api_base,
+        state_file,
+        command,
+        ..
+    } = args;
+
+    if let Some(max) = start_jitter_secs.filter(|max| *max > 0) {
+        let jitter = std::time::Duration::from_millis(
+            RandomState::new().build_hasher().finish() % (max * 1000),
+        );
+        println!("Waiting {jitter:?} before starting");
+        tokio::time::sleep(jitter).await;
+    }
+
     let older_than = OffsetDateTime::now_utc() - time::Duration::DAY;
     let urgently_terminate = older_than - time::Duration::DAY;
+    let young_after = OffsetDateTime::now_utc() - time::Duration::minutes(min_device_lifetime_mins);
 
-    let http_client = reqwest::Client::new();
-    /*
-        curl \
-        --header 'Accept: application/json' \
-        --header 'Content-Type: application/json' \
-        --header "X-Auth-Token: $PACKET_AUTH_TOKEN" \
-        --fail \
-        "https://api.equinix.com/metal/v1/spot-market-requests/${1}?include=devices" \
-        | jq -c '.devices[] | { id, short_id }'
-    */
-
-    let mut desired_hardware =
-        hardware::get_desired_hardware(&http_client, &hydra_root, &config_file).await?;
-    let desired_tags = BTreeSet::from_iter(desired_hardware.tags.iter());
+    let hydra_bearer_token =
+        optional_secret(hydra_bearer_token, hydra_bearer_token_file.as_deref())?;
+    if let Some(token) = &hydra_bearer_token {
+        http::register_secret(token);
+    }
 
-    let mut all_devices: Vec<device::Device> =
-        device::get_all_devices(&http_client, &equinix_auth_token, &equinix_project_id)
-            .await?
-            .into_iter()
-            .filter(|device| {
-                let device_tags = BTreeSet::from_iter(device.tags.iter());
-                device_tags.is_superset(&desired_tags)
-            })
-            .filter(|device| device.device_type == device::DeviceType::SpotInstance)
-            .collect();
+    if let Some(dir) = api_log_dir {
+        http::log_api_to(dir)?;
+    }
+    if let Some(path) = audit_log {
+        audit::log_to(&path)?;
+    }
+    device::use_api_base(&equinix_api_base)?;
+    reconcile::limit_concurrency(max_concurrency);
 
-    let mut to_delete: Vec<device::Device>;
+    // Device lists for big projects are large, and compress well.
+    let mut http_client = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .gzip(true)
+        .deflate(true);
+    if let Some(path) = extra_ca_cert {
+        let pem =
+            std::fs::read(&path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        http_client = http_client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    if let Some(url) = https_proxy {
+        let parsed = reqwest::Url::parse(&url).wrap_err("failed to parse --https-proxy")?;
+        if let Some(password) = parsed.password() {
+            http::register_secret(password);
+        }
+        let proxy = reqwest::Proxy::all(parsed)?
+            .no_proxy(no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+        http_client = http_client.proxy(proxy);
+    }
+    let http_client = http_client.build()?;
+    let prometheus_bearer_token = optional_secret(
+        prometheus_bearer_token,
+        prometheus_bearer_token_file.as_deref(),
+    )?;
+    if let Some(token) = &prometheus_bearer_token {
+        http::register_secret(token);
+    }
+    let prometheus = device::Prometheus {
+        http_client: http_client.clone(),
+        root: prometheus_root,
+        bearer_token: prometheus_bearer_token,
+        query_template: prometheus_query_template,
+        aggregation: prometheus_aggregation,
+        max_sample_age: max_sample_age_secs.map(time::Duration::seconds),
+    };
+    let provider = device::EquinixMetal {
+        http_client: http_client.clone(),
+        auth_token: equinix_auth_token,
+        project_ids: equinix_project_ids,
+        hostname_template,
+        device_ttl: device_ttl_hours.map(time::Duration::hours),
+    };
 
-    // Take out all the old devices that we want to cycle out anyway,
-    // and devices which are already in drain
-    (to_delete, all_devices) = all_devices.into_iter().partition(|device| {
-        (device.created_at < older_than) || device.tags.contains(&"skip-hydra".to_string())
+    // Webhook URLs, like Slack's, are credentials themselves.
+    if let Some(url) = &notify_webhook {
+        http::register_secret(url);
+    }
+    let notify_webhook = notify_webhook.map(|url| notify::Webhook {
+        http_client: http_client.clone(),
+        url,
+        slack: notify_slack,
+    });
+    let hydra_drain = hydra_drain.map(|url_template| hardware::HydraDrain {
+        http_client: http_client.clone(),
+        url_template,
+        bearer_token: hydra_bearer_token.clone(),
     });
 
-    let mut to_keep: Vec<device::Device> = vec![];
-    for device in all_devices.into_iter() {
-        // See if desired_hardware has a matching device
-        if let Some(idx) = desired_hardware.plans.iter().position(|desired| {
-            Some(&desired.netboot_url) == device.ipxe_script_url.as_ref()
-                && desired.plan == device.plan.class
-        }) {
-            desired_hardware.plans.swap_remove(idx);
-            to_keep.push(device);
-        } else {
-            to_delete.push(device);
+    let ctx = Context {
+        http_client,
+        prometheus,
+        provider,
+        hydra_roots: hydra_root,
+        hydra_bearer_token,
+        require_all_hydras,
+        hydra_drain,
+        notify_webhook,
+        config_file: required_config_file(config_file)?,
+        drain_tag,
+        tag_match_mode,
+        exclude_devices,
+        state_file,
+        older_than,
+        urgently_terminate,
+        max_urgent_destroys: max_urgent_destroys_per_run,
+        young_after,
+    };
+
+    let result = match run_deadline_secs.map(std::time::Duration::from_secs) {
+        Some(deadline) => {
+            shutdown::stop_after(deadline);
+            tokio::time::timeout(deadline + DEADLINE_GRACE, run(&ctx, command))
+                .await
+                .unwrap_or_else(|_| Err(eyre!("the run deadline passed, abandoning the run")))
+        }
+        None => run(&ctx, command).await,
+    };
+    http::print_call_stats();
+
+    result
+}
+
+async fn run(ctx: &Context, command: Command) -> Result<()> {
+    match command {
+        Command::Scale(args) => {
+            let mut summary = notify::Summary::default();
+            let result = scale(ctx, &args, &mut summary)
+                .await
+                .and_then(|()| shutdown::check_deadline());
+            summary.errors.extend(
+                outcome::warnings()
+                    .iter()
+                    .map(|warning| http::redact(warning)),
+            );
+            if let Err(e) = &result {
+                summary.errors.push(http::redact(&format!("{e:#}")));
+            }
+            summary.api_calls = http::call_stats();
+
+            if let Some(webhook) = &ctx.notify_webhook {
+                if summary.is_eventful() {
+                    if let Err(e) = webhook.send(&summary).await {
+                        outcome::warn(format!("failed to send the run summary: {:?}", e));
+                    }
+                }
+            }
+
+            if args.watch_only_after_apply && result.is_ok() {
+                watch(
+                    ctx,
+                    &args.plan,
+                    std::time::Duration::from_secs(args.watch_interval_secs),
+                )
+                .await?;
+            }
+            result
+        }
+        Command::Plan(args) => {
+            // Planning doesn't change anything, including the state file.
+            let mut state = ctx.load_state()?;
+            let plan = plan(ctx, &args, &mut state).await?;
+            print_report(ctx, &plan, &mut state).await?;
+            print_diff(ctx, &plan, args.diff_output)
+        }
+        Command::List(args) => list(ctx, args).await,
+        Command::Preflight => preflight(ctx).await,
+        Command::Gc(args) => gc(ctx, args)
+            .await
+            .and_then(|()| shutdown::check_deadline()),
+        Command::ScaleToZero(args) => scale_to_zero(ctx, args)
+            .await
+            .and_then(|()| shutdown::check_deadline()),
+        Command::Schema | Command::Validate => unreachable!("handled before reading credentials"),
+    }
+}
+
+fn required_config_file(config_file: Option<PathBuf>) -> Result<PathBuf> {
+    config_file.ok_or_else(|| eyre!("--config-file is required"))
+}
+
+fn validate(config_file: &Path) -> Result<()> {
+    let config = hardware::parse_config_file(config_file)?;
+    let problems = config.problems();
+    for problem in problems.iter() {
+        println!("{problem}");
+    }
+
+    if problems.is_empty() {
+        println!("{} is valid", config_file.display());
+        Ok(())
+    } else {
+        Err(eyre!(
+            "{} has {} problems",
+            config_file.display(),
+            problems.len()
+        ))
+    }
+}
+
+/// Run every read-only check of what `scale` depends on, printing whether each passed.
+async fn preflight(ctx: &Context) -> Result<()> {
+    let http_client = &ctx.http_client;
+    let auth_token = &ctx.provider.auth_token;
+    let mut checks: Vec<(String, Result<String>)> = vec![];
+
+    let config = hardware::parse_config_file(&ctx.config_file);
+    checks.push((
+        "config".to_string(),
+        match &config {
+            Ok(config) if config.problems().is_empty() => Ok(ctx.config_file.display().to_string()),
+            Ok(config) => Err(eyre!("{}", config.problems().join("; "))),
+            Err(e) => Err(eyre!("{e:#}")),
+        },
+    ));
+
+    for (idx, project_id) in ctx.provider.project_ids.iter().enumerate() {
+        checks.push((
+            format!("Equinix project {}", idx + 1),
+            device::get_project_name(http_client, auth_token, project_id).await,
+        ));
+    }
+
+    let metros = device::get_metros(http_client, auth_token)
+        .await
+        .and_then(|known| {
+            let known: BTreeSet<String> = known.into_iter().map(|metro| metro.code).collect();
+            let wanted = config
+                .as_ref()
+                .map(|config| config.all_metros())
+                .unwrap_or_default();
+            let unknown: Vec<&String> = wanted
+                .iter()
+                .filter(|metro| *metro != "any" && !known.contains(*metro))
+                .collect();
+            if unknown.is_empty() {
+                Ok(format!("{} metros configured", wanted.len()))
+            } else {
+                Err(eyre!("unknown metros {:?}", unknown))
+            }
+        });
+    checks.push(("Equinix metros".to_string(), metros));
+
+    let fetcher = http::Fetcher::bearer(http_client, ctx.hydra_bearer_token.as_deref())?;
+    for hydra_root in ctx.hydra_roots.iter() {
+        checks.push((
+            format!("Hydra {hydra_root}"),
+            hardware::fetch_queue_runner_status(&fetcher, hydra_root)
+                .await
+                .map(|status| {
+                    let runnable: usize = hardware::bucket_runnable(&status)
+                        .values()
+                        .flat_map(|sizes| sizes.values())
+                        .sum();
+                    format!("{runnable} runnable jobs")
+                }),
+        ));
+    }
+
+    let devices = match &config {
+        Ok(config) => {
+            managed_devices(ctx, &config.tags(), &config.known_tags(), &BTreeSet::new()).await
+        }
+        Err(_) => Err(eyre!("needs the config")),
+    };
+    let some_device = devices.as_ref().ok().and_then(|devices| devices.first());
+    checks.push((
+        format!("Prometheus {}", ctx.prometheus.root),
+        ctx.prometheus
+            .check(some_device)
+            .await
+            .map(|series| format!("{series} job count series")),
+    ));
+    checks.push((
+        "managed devices".to_string(),
+        devices.map(|devices| format!("{} devices", devices.len())),
+    ));
+
+    let pass = anstyle::AnsiColor::Green.on_default();
+    let fail = anstyle::AnsiColor::Red.on_default();
+    let mut failures = 0;
+    for (what, result) in checks.iter() {
+        match result {
+            Ok(detail) => anstream::println!("{pass}PASS{pass:#} {what}: {detail}"),
+            Err(e) => {
+                failures += 1;
+                // reqwest's errors already repeat their causes, and are described above them.
+                let causes: Vec<String> = e
+                    .chain()
+                    .take_while(|cause| cause.downcast_ref::<reqwest::Error>().is_none())
+                    .map(|cause| cause.to_string())
+                    .collect();
+                anstream::println!(
+                    "{fail}FAIL{fail:#} {what}: {}",
+                    http::redact(&causes.join(": "))
+                );
+            }
+        }
+    }
+
+    if failures == 0 {
+        println!("All {} checks passed", checks.len());
+        Ok(())
+    } else {
+        Err(eyre!("{} of {} checks failed", failures, checks.len()))
+    }
+}
+
+/// Spot instances in the projects that aren't managed, and aren't excluded.
+async fn unmanaged_spot_instances(
+    ctx: &Context,
+    tags: &[String],
+    known_tags: &BTreeSet<String>,
+    config_exclude: &[String],
+) -> Result<Vec<device::Device>> {
+    Ok(ctx
+        .provider
+        .list_devices(None)
+        .await?
+        .into_iter()
+        .filter(|device| ctx.is_unmanaged_spot_instance(device, tags, known_tags, config_exclude))
+        .collect())
+}
+
+impl Context {
+    /// Whether the device carries all of `tags`, or was spawned by one of `spot_market_devices`,
+    /// and is either a spot instance or an on-demand fallback.
+    fn is_managed(
+        &self,
+        device: &device::Device,
+        tags: &[String],
+        known_tags: &BTreeSet<String>,
+        spot_market_devices: &BTreeSet<String>,
+    ) -> bool {
+        (self.tags_match(device, tags, known_tags) || spot_market_devices.contains(&device.id))
+            && (device.spot_instance || device.tags.iter().any(|tag| tag == device::ON_DEMAND_TAG))
+    }
+
+    /// Whether the device is a spot instance that isn't managed, and isn't excluded.
+    fn is_unmanaged_spot_instance(
+        &self,
+        device: &device::Device,
+        tags: &[String],
+        known_tags: &BTreeSet<String>,
+        config_exclude: &[String],
+    ) -> bool {
+        device.spot_instance
+            && !self.tags_match(device, tags, known_tags)
+            && !self.is_excluded(device, config_exclude)
+    }
+
+    /// Whether the device's tags make it ours, by `--tag-match-mode`. Draining devices may have
+    /// the drain tag, too.
+    fn tags_match(
+        &self,
+        device: &device::Device,
+        tags: &[String],
+        known_tags: &BTreeSet<String>,
+    ) -> bool {
+        let mut known_tags = known_tags.clone();
+        known_tags.insert(self.drain_tag.clone());
+        self.tag_match_mode.matches(device, tags, &known_tags)
+    }
+
+    /// When to destroy devices regardless of their jobs, if `config` allows it at all.
+    fn urgent_termination(&self, config: &hardware::Config) -> reconcile::UrgentTermination {
+        reconcile::UrgentTermination {
+            before: self.urgently_terminate,
+            allowed: config.allow_urgent_termination,
+            max: self.max_urgent_destroys,
+        }
+    }
+
+    /// Whether the device is excluded from management, by the flags or by `config_exclude`.
+    fn is_excluded(&self, device: &device::Device, config_exclude: &[String]) -> bool {
+        self.exclude_devices
+            .iter()
+            .chain(config_exclude.iter())
+            .any(|excluded| {
+                *excluded == device.id
+                    || *excluded == device.short_id
+                    || *excluded == device.hostname
+            })
+    }
+
+    fn load_state(&self) -> Result<state::State> {
+        match &self.state_file {
+            Some(path) => state::State::load(path),
+            None => Ok(state::State::default()),
+        }
+    }
+
+    fn save_state(&self, state: &mut state::State) -> Result<()> {
+        match &self.state_file {
+            Some(path) => state.save(path, OffsetDateTime::now_utc()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The devices carrying all of `tags`, or spawned by one of `spot_market_devices`.
+async fn managed_devices(
+    ctx: &Context,
+    tags: &[String],
+    known_tags: &BTreeSet<String>,
+    spot_market_devices: &BTreeSet<String>,
+) -> Result<Vec<device::Device>> {
+    // Only ask Equinix for devices with one of the tags, unless a spot market request's devices
+    // might not have them, or any of the tags will do. Spot instances aren't filtered for
+    // server-side, since on-demand fallbacks are managed too.
+    let server_tag = tags
+        .first()
+        .filter(|_| spot_market_devices.is_empty() && ctx.tag_match_mode != device::TagMatch::AnyOf)
+        .map(|tag| tag.as_str());
+
+    Ok(ctx
+        .provider
+        .list_devices(server_tag)
+        .await?
+        .into_iter()
+        .filter(|device| ctx.is_managed(device, tags, known_tags, spot_market_devices))
+        .collect())
+}
+
+/// Work out which devices to keep, destroy, and create, within the limits in `args`.
+async fn plan(
+    ctx: &Context,
+    args: &PlanArgs,
+    state: &mut state::State,
+) -> Result<reconcile::Reconciliation> {
+    let http_client = &ctx.http_client;
+    let equinix_auth_token = &ctx.provider.auth_token;
+
+    if let Some(alpha) = args.smoothing_alpha {
+        if ctx.state_file.is_none() {
+            return Err(eyre!(
+                "--smoothing-alpha needs a --state-file to remember averages in"
+            ));
+        }
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(eyre!(
+                "--smoothing-alpha must be above 0 and at most 1, not {alpha}"
+            ));
         }
     }
 
+    let mut desired_hardware = hardware::get_desired_hardware(
+        http_client,
+        &ctx.hydra_roots,
+        ctx.hydra_bearer_token.as_deref(),
+        ctx.require_all_hydras,
+        &ctx.config_file,
+        args.smoothing_alpha,
+        &mut state.runnable_averages,
+    )
+    .await?;
+    let desired_tags = BTreeSet::from_iter(desired_hardware.tags.iter());
     for desired in desired_hardware.plans.iter() {
-        println!("Creating: {:#?}", desired);
-        device::create_device(
-            &http_client,
-            &equinix_auth_token,
-            &equinix_project_id,
-            desired.clone(),
-            &desired_hardware.tags,
-            &desired_hardware.facilities,
-        )
-        .await?;
+        if let Some(project_id) = &desired.project_id {
+            if !ctx.provider.project_ids.contains(project_id) {
+                return Err(eyre!(
+                    "{}'s project {} isn't one of --project-id",
+                    hardware::category_name(&desired.system, &desired.size),
+                    project_id
+                ));
+            }
+        }
+    }
+
+    let known_metros: BTreeSet<String> = device::get_metros(http_client, equinix_auth_token)
+        .await?
+        .into_iter()
+        .map(|metro| metro.code)
+        .collect();
+    for metro in desired_hardware.metros.iter() {
+        if metro != "any" && !known_metros.contains(metro) {
+            return Err(eyre!(
+                "unknown metro {:?}, Equinix knows about: {:?}",
+                metro,
+                known_metros
+            ));
+        }
     }
 
-    for device in to_delete.iter() {
-        if !device.tags.contains(&"skip-hydra".to_string()) {
-            println!("Giving {} a skip-hydra tag", device.id);
-            let mut tags = device.tags.clone();
-            tags.push("skip-hydra".to_string());
+    if args.bid_strategy == BidStrategy::Market {
+        let mut spot_prices: HashMap<String, Option<f64>> = HashMap::new();
+        for desired in desired_hardware.plans.iter_mut() {
+            if !spot_prices.contains_key(&desired.plan.plan) {
+                let price = device::get_spot_price(
+                    http_client,
+                    equinix_auth_token,
+                    &desired.plan.plan,
+                    &desired.metros,
+                )
+                .await?;
+                spot_prices.insert(desired.plan.plan.clone(), price);
+            }
 
-            device::add_device_tag(&http_client, &equinix_auth_token, device, tags).await?;
+            match spot_prices[&desired.plan.plan] {
+                Some(price) => desired.plan.bid = desired.plan.bid.min(price * args.bid_multiplier),
+                None => println!(
+                    "WARNING: no spot price for {}, bidding the configured {}",
+                    desired.plan.plan, desired.plan.bid
+                ),
+            }
         }
     }
 
-    for device in to_delete.iter() {
-        let jobs = if device.created_at < urgently_terminate {
-            println!("Disregarding the device's in progress jobs: it has exceeded the urgent termination date");
-            0
-        } else {
-            device::get_current_jobs(&http_client, device, &prometheus_root).await?
+    // Devices spawned by our spot market requests are ours, even if they don't have our tags yet.
+    let mut spot_market_devices: BTreeSet<String> = BTreeSet::new();
+    if args.spot_market_requests {
+        for project_id in ctx.provider.project_ids.iter() {
+            for request in
+                device::get_spot_market_requests(http_client, equinix_auth_token, project_id)
+                    .await?
+            {
+                if BTreeSet::from_iter(request.instance_parameters.tags.iter())
+                    .is_superset(&desired_tags)
+                {
+                    spot_market_devices.extend(request.devices.into_iter().map(|device| device.id));
+                }
+            }
+        }
+    }
+
+    // A run that crashed between creating devices and tagging them leaves devices nothing would
+    // manage, so adopt the ones that look like ours. Those can't be found by tag, so every device
+    // is listed, once.
+    let tags = &desired_hardware.tags;
+    let known_tags = &desired_hardware.known_tags;
+    let (mut managed_devices, untagged): (Vec<device::Device>, Vec<device::Device>) = ctx
+        .provider
+        .list_devices(None)
+        .await?
+        .into_iter()
+        .filter(|device| {
+            ctx.is_managed(device, tags, known_tags, &spot_market_devices)
+                || ctx.is_unmanaged_spot_instance(
+                    device,
+                    tags,
+                    known_tags,
+                    &desired_hardware.exclude,
+                )
+        })
+        .partition(|device| ctx.tags_match(device, tags, known_tags));
+    let to_adopt = reconcile::adoptable(
+        untagged,
+        &ctx.provider.hostname_template,
+        &desired_hardware.known_plans,
+        &desired_hardware.known_metros,
+        &spot_market_devices,
+        tags,
+    );
+    managed_devices.extend(to_adopt.iter().cloned());
+    state.observe_devices(&managed_devices, OffsetDateTime::now_utc());
+
+    let mut plan = reconcile::reconcile(
+        managed_devices,
+        desired_hardware.plans,
+        &ctx.drain_tag,
+        ctx.older_than,
+        ctx.young_after,
+        &desired_hardware.minimums,
+        args.missing_ipxe_url,
+    );
+    plan.to_adopt = to_adopt;
+    plan.sizing = desired_hardware.sizing;
+
+    // Excluded devices are still part of the fleet, they're just never drained or destroyed.
+    let (excluded, to_delete): (Vec<device::Device>, Vec<device::Device>) =
+        std::mem::take(&mut plan.to_delete)
+            .into_iter()
+            .partition(|device| ctx.is_excluded(device, &desired_hardware.exclude));
+    for device in excluded.iter() {
+        println!("Not draining or destroying {}: it's excluded", device.id);
+    }
+    plan.to_delete = to_delete;
+    plan.to_keep.extend(excluded);
+    plan.on_demand
+        .retain(|(device, _)| !ctx.is_excluded(device, &desired_hardware.exclude));
+
+    if let Some(max) = args.max_total_devices {
+        // Old devices kept to hold a category's minimum are on their way out, so their
+        // replacements mustn't be starved by them.
+        let budget = max.saturating_sub(plan.to_keep.len() - plan.deferred);
+        let starved =
+            hardware::trim_to_budget(&mut plan.to_create, budget, &desired_hardware.priorities);
+        for ((system, size), count) in starved.iter() {
+            println!(
+                "WARNING: {:?}/{:?} is {} devices short: the fleet is capped at {} devices",
+                system, size, count, max
+            );
+        }
+    }
+
+    // Leave the rest for the next run to converge on, in case something upstream went wrong.
+    hardware::sort_by_priority(&mut plan.to_create, &desired_hardware.priorities);
+    if let Some(max) = args.max_creates_per_run {
+        if plan.to_create.len() > max {
+            println!(
+                "WARNING: wanted to create {} devices, only creating {} this run",
+                plan.to_create.len(),
+                max
+            );
+            let mut starved: BTreeMap<String, usize> = BTreeMap::new();
+            for desired in plan.to_create.split_off(max) {
+                *starved
+                    .entry(hardware::category_name(&desired.system, &desired.size))
+                    .or_default() += 1;
+            }
+            for (category, count) in starved.iter() {
+                println!("WARNING: {category} is {count} devices short until the next run");
+            }
+        }
+    }
+    match args.teardown_order {
+        TeardownOrder::Unordered => (),
+        TeardownOrder::Oldest => plan.to_delete.sort_by_key(|device| device.created_at),
+        TeardownOrder::LeastLoaded => {
+            let jobs = ctx.prometheus.current_jobs_bulk(&plan.to_delete).await?;
+            let mut loaded: Vec<_> = std::mem::take(&mut plan.to_delete)
+                .into_iter()
+                .zip(jobs)
+                .collect();
+            loaded.sort_by_key(|(_, jobs)| jobs.unwrap_or(u64::MAX));
+            plan.to_delete = loaded.into_iter().map(|(device, _)| device).collect();
+        }
+    }
+
+    if let Some(max) = args.max_destroys_per_run {
+        if plan.to_delete.len() > max {
+            println!(
+                "WARNING: wanted to destroy {} devices, only destroying {} this run",
+                plan.to_delete.len(),
+                max
+            );
+            let spared = plan.to_delete.split_off(max);
+            plan.to_keep.extend(spared);
+        }
+    }
+
+    if let Some(max) = args.max_hourly_spend {
+        let dropped = hardware::trim_to_spend(
+            &mut plan.to_create,
+            (max - plan.kept_spend).max(0.0),
+            &desired_hardware.priorities,
+        );
+        for desired in dropped.iter() {
+            println!(
+                "WARNING: not creating {} for {:?}/{:?}: it would exceed ${:.2}/hour",
+                desired.plan.plan, desired.system, desired.size, max
+            );
+        }
+    }
+
+    println!(
+        "Projected spend: ${:.2}/hour (${:.2} kept, ${:.2} created)",
+        plan.projected_spend(),
+        plan.kept_spend,
+        plan.create_spend()
+    );
+
+    let to_tag = plan
+        .to_delete
+        .iter()
+        .filter(|device| !device.tags.contains(&ctx.drain_tag))
+        .count();
+    println!(
+        "Will create {}, destroy up to {}, tag {}",
+        plan.to_create.len(),
+        plan.to_delete.len(),
+        to_tag
+    );
+    if args.explain {
+        explain(ctx, &plan).await?;
+    }
+
+    Ok(plan)
+}
+
+async fn explain(ctx: &Context, plan: &reconcile::Reconciliation) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+    let keep = plan.to_keep.iter().map(|device| ("Keeping", device));
+    let delete = plan.to_delete.iter().map(|device| ("Deleting", device));
+    for (verdict, device) in keep.chain(delete) {
+        let reason = match plan.decisions.get(&device.id) {
+            Some(decision) if decision.is_delete() == (verdict == "Deleting") => {
+                decision.to_string()
+            }
+            Some(decision) => format!("{decision}, but it's excluded or over a limit this run"),
+            None => "it wasn't part of the plan".to_string(),
         };
+        let jobs = ctx.prometheus.current_jobs(device).await?;
 
-        if jobs == 0 {
-            if device.state != device::DeviceState::Active {
-                println!("Would destroy but it isn't active ({:?})", device.state);
+        println!("{verdict} {} ({}): {reason}", device.id, device.hostname);
+        println!(
+            "    created {} ago at {}; cycled out before {}, past the urgent termination date \
+             before {}, too young to destroy after {}",
+            format_age((now - device.created_at).whole_seconds()),
+            device.created_at,
+            ctx.older_than,
+            ctx.urgently_terminate,
+            ctx.young_after
+        );
+        println!(
+            "    {} jobs, {}",
+            format_jobs(jobs),
+            if device.tags.contains(&ctx.drain_tag) {
+                "draining"
             } else {
-                println!("Destroying...");
-                device::destroy_device(&http_client, &equinix_auth_token, device).await?;
+                "not draining"
+            }
+        );
+    }
+
+    Ok(())
+}
+
+async fn scale(ctx: &Context, args: &ScaleArgs, summary: &mut notify::Summary) -> Result<()> {
+    if args.plan.spot_market_requests && !ctx.provider.hostname_template.contains("{short_random}")
+    {
+        return Err(eyre!(
+            "--spot-market-requests needs a --hostname-template with {{short_random}}, or all of a \
+             request's devices would have the same hostname"
+        ));
+    }
+    let mut state = ctx.load_state()?;
+    let mut plan = plan(ctx, &args.plan, &mut state).await?;
+    summary.projected_spend = plan.projected_spend();
+    summary.sized(&plan.sizing);
+    summary.had(plan.to_keep.iter().chain(plan.to_delete.iter()));
+    if in_maintenance(ctx)? {
+        print_report(ctx, &plan, &mut state).await?;
+        return ctx.save_state(&mut state);
+    }
+    if let Some(verify) = args.verify_netboot {
+        let unreachable =
+            hardware::unreachable_netboot_urls(&ctx.http_client, &plan.to_create).await;
+        for (url, problem) in unreachable.iter() {
+            println!("WARNING: the netboot URL {url} isn't reachable: {problem}");
+        }
+
+        if verify == VerifyNetboot::Refuse && !unreachable.is_empty() {
+            let wanted = plan.to_create.len();
+            plan.to_create.retain(|desired| {
+                desired
+                    .plan
+                    .ipxe_script_url()
+                    .is_none_or(|url| !unreachable.contains_key(&url))
+            });
+            outcome::warn(format!(
+                "not creating {} devices: their netboot URL isn't reachable",
+                wanted - plan.to_create.len()
+            ));
+        }
+    }
+    print_diff(ctx, &plan, args.plan.diff_output)?;
+    if !args.yes && std::io::stdin().is_terminal() && !confirm("Continue?")? {
+        println!("Aborting.");
+        return ctx.save_state(&mut state);
+    }
+    // From here on, a signal lets the device operation in progress finish and still saves the
+    // state, rather than leaving a half-applied plan behind.
+    shutdown::listen()?;
+
+    let provider = &ctx.provider;
+    reconcile::adopt(provider, &plan.to_adopt).await?;
+    if args.reinstall_drifted {
+        reconcile::reinstall_drifted(provider, &ctx.prometheus, &mut plan).await?;
+    }
+
+    let mut breaker = reconcile::CircuitBreaker {
+        threshold: args.halt_creates_after_failures,
+        cooldown: time::Duration::minutes(args.create_halt_cooldown_mins),
+        failures: std::mem::take(&mut state.create_failures),
+    };
+    // Spot market requests ask for all the identical devices at once, rather than one at a time.
+    let result = reconcile::create(
+        provider,
+        &plan.to_create,
+        &mut breaker,
+        args.plan.spot_market_requests,
+    )
+    .await;
+    let mut created = result?;
+    created.extend(reconcile::replace_on_demand(provider, &mut plan, &breaker).await?);
+    state.create_failures = breaker.failures;
+    summary.created(&created);
+    summary.unfilled = reconcile::unfilled(&plan, &created);
+    for (category, unfilled) in summary.unfilled.iter() {
+        println!(
+            "WARNING: {}: wanted {}, filled {}",
+            category, unfilled.wanted, unfilled.filled
+        );
+    }
+
+    if args.rolling {
+        let is_cycling = |device: &device::Device| {
+            device.created_at < ctx.older_than
+                && device.created_at >= ctx.urgently_terminate
+                && !device.tags.contains(&ctx.drain_tag)
+        };
+
+        // Only the replacements created for the categories being cycled are waited for, so that
+        // a device stuck elsewhere doesn't hold up the whole fleet.
+        let cycling_categories: BTreeSet<String> = plan
+            .to_delete
+            .iter()
+            .filter(|device| is_cycling(device))
+            .map(hardware::device_category_name)
+            .collect();
+        let mut held: BTreeSet<String> = BTreeSet::new();
+        for device in created.iter() {
+            let category = hardware::device_category_name(device);
+            if !cycling_categories.contains(&category)
+                || held.contains(&category)
+                || device.state == device::DeviceState::Active
+            {
+                continue;
+            }
+
+            let active = provider::wait_for_active(provider, &device.id, ROLLING_TIMEOUT)
+                .await
+                .unwrap_or_else(|e| {
+                    println!(
+                        "WARNING: failed to check whether {} is active: {:?}",
+                        device.id, e
+                    );
+                    false
+                });
+            if !active {
+                held.insert(category);
             }
         }
+
+        for category in held.iter() {
+            println!(
+                "Not cycling out {category}'s old devices yet: their replacements aren't active"
+            );
+        }
+        let cycling: Vec<device::Device>;
+        (cycling, plan.to_delete) =
+            std::mem::take(&mut plan.to_delete)
+                .into_iter()
+                .partition(|device| {
+                    is_cycling(device) && held.contains(&hardware::device_category_name(device))
+                });
+        plan.to_keep.extend(cycling);
+    }
+
+    if args.reboot_before_destroy {
+        reconcile::reboot_idle(
+            provider,
+            &ctx.prometheus,
+            &mut plan,
+            &ctx.drain_tag,
+            |device| {
+                state
+                    .devices
+                    .get(&device.id)
+                    .is_some_and(|record| record.idle_runs >= WEDGED_AFTER_IDLE_RUNS)
+            },
+        )
+        .await?;
+    }
+
+    let drifted: BTreeSet<&str> = plan
+        .drifted
+        .iter()
+        .map(|(device, _)| device.id.as_str())
+        .collect();
+    for device in plan.to_delete.iter() {
+        let reason = if device.tags.contains(&ctx.drain_tag) {
+            "draining"
+        } else if device.created_at < ctx.older_than {
+            "cycling"
+        } else if drifted.contains(device.id.as_str()) {
+            "netboot changed"
+        } else {
+            "scale-down"
+        };
+        audit::explain(&device.id, reason);
+    }
+    let torn_down = if args.no_destroy {
+        if provider.device_ttl.is_none() {
+            println!(
+                "WARNING: --no-destroy without --device-ttl-hours leaves drained devices running \
+                 until they're destroyed by hand"
+            );
+        }
+        reconcile::Teardown {
+            drained: reconcile::drain(provider, &ctx.hydra_drain, &plan.to_delete, &ctx.drain_tag)
+                .await?,
+            destroyed: vec![],
+        }
+    } else {
+        let config = hardware::parse_config_file(&ctx.config_file)?;
+        reconcile::teardown(
+            provider,
+            &ctx.prometheus,
+            &ctx.hydra_drain,
+            &plan.to_delete,
+            &ctx.drain_tag,
+            ctx.urgent_termination(&config),
+            ctx.young_after,
+        )
+        .await?
+    };
+    summary.kept(&plan.to_keep);
+    summary.drained(&torn_down.drained);
+    summary.destroyed(&torn_down.destroyed);
+
+    print_report(ctx, &plan, &mut state).await?;
+    match args.category_report {
+        Some(Output::Json) => println!("{}", serde_json::to_string_pretty(&summary.categories)?),
+        Some(Output::Table) => println!("{}", summary.category_report()),
+        None => (),
+    }
+    ctx.save_state(&mut state)?;
+    if shutdown::requested() {
+        println!(
+            "Stopped early because of a signal, the next run will pick up where this left off"
+        );
+    }
+
+    Ok(())
+}
+
+fn in_maintenance(ctx: &Context) -> Result<bool> {
+    let config = hardware::parse_config_file(&ctx.config_file)?;
+    let in_maintenance = config.in_maintenance(OffsetDateTime::now_utc());
+    if in_maintenance {
+        println!("In a maintenance window: not creating, draining, or destroying anything");
+    }
+
+    Ok(in_maintenance)
+}
+
+/// Plan every `interval` and print the report and diff, without acting on them or saving the
+/// state, until a signal or the run deadline stops us.
+async fn watch(ctx: &Context, args: &PlanArgs, interval: std::time::Duration) -> Result<()> {
+    println!("Watching every {interval:?} without changing anything");
+    shutdown::listen()?;
+
+    while shutdown::sleep(interval).await {
+        let result = async {
+            let mut state = ctx.load_state()?;
+            let plan = plan(ctx, args, &mut state).await?;
+            print_report(ctx, &plan, &mut state).await?;
+            print_diff(ctx, &plan, args.diff_output)
+        }
+        .await;
+        if let Err(e) = result {
+            println!("WARNING: failed to plan while watching: {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn print_report(
+    ctx: &Context,
+    plan: &reconcile::Reconciliation,
+    state: &mut state::State,
+) -> Result<()> {
+    let destroy = anstyle::AnsiColor::Red.on_default();
+    let keep = anstyle::Style::new().dimmed();
+    let create = anstyle::AnsiColor::Green.on_default();
+
+    for dev in plan.to_delete.iter() {
+        let jobs = ctx.prometheus.current_jobs(dev).await?;
+        state.record_jobs(&dev.id, jobs);
+
+        anstream::println!(
+            "{destroy}- {:<8} {:>4} jobs {:<16} {}{destroy:#}",
+            dev.short_id,
+            format_jobs(jobs),
+            dev.plan.class,
+            dev.ipxe_script_url.as_deref().unwrap_or("?")
+        );
     }
+    for dev in plan.to_keep.iter() {
+        let jobs = ctx.prometheus.current_jobs(dev).await?;
+        state.record_jobs(&dev.id, jobs);
 
-    for dev in to_delete.iter() {
-        let jobs = device::get_current_jobs(&http_client, dev, &prometheus_root).await?;
+        anstream::println!(
+            "{keep}  {:<8} {:>4} jobs {:<16} {}{keep:#}",
+            dev.short_id,
+            format_jobs(jobs),
+            dev.plan.class,
+            dev.ipxe_script_url.as_deref().unwrap_or("?")
+        );
+    }
+    for dev in plan.to_create.iter() {
+        anstream::println!(
+            "{create}+ {:<8} {:>4} jobs {:<16} {}{create:#}",
+            "--------",
+            0,
+            dev.plan.plan,
+            dev.plan.netboot_url
+        );
+    }
 
+    if plan
+        .to_keep
+        .iter()
+        .any(|dev| dev.device_type == device::DeviceType::SpotInstance)
+    {
+        let prices = spot_prices(ctx).await;
+        let priced: Vec<(f64, f64)> = plan
+            .to_keep
+            .iter()
+            .filter_map(|dev| Some((dev.spot_price_max?, dev.spot_price(&prices)?)))
+            .collect();
         println!(
-            "-{} {} jobs {} {:?}",
-            dev.short_id, jobs, dev.plan.class, dev.ipxe_script_url
+            "Kept spot instances bid ${:.2}/hour, and cost ${:.2}/hour at current prices",
+            priced.iter().map(|(bid, _)| bid).sum::<f64>(),
+            priced.iter().map(|(_, price)| price).sum::<f64>()
         );
+        let overbid = priced
+            .iter()
+            .filter(|(bid, price)| *bid > 2.0 * price)
+            .count();
+        if overbid > 0 {
+            println!("{overbid} kept spot instances bid more than twice their current price");
+        }
+    }
+
+    Ok(())
+}
+
+/// The current spot prices, or none if they can't be fetched, since they're only reported.
+async fn spot_prices(ctx: &Context) -> device::SpotPrices {
+    device::get_spot_prices(&ctx.http_client, &ctx.provider.auth_token)
+        .await
+        .unwrap_or_else(|e| {
+            println!("WARNING: failed to fetch the current spot prices: {:?}", e);
+            device::SpotPrices::new()
+        })
+}
+
+fn print_diff(ctx: &Context, plan: &reconcile::Reconciliation, output: Output) -> Result<()> {
+    let diff = reconcile::Diff::new(plan, &ctx.drain_tag, ctx.older_than);
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+        Output::Table => println!("{}", diff.to_text()),
+    }
+
+    Ok(())
+}
+
+/// A managed device, as `list` prints it.
+#[derive(serde::Serialize)]
+struct ListedDevice {
+    short_id: String,
+    plan: String,
+    metro: Option<String>,
+    state: device::DeviceState,
+    age_secs: i64,
+    jobs: Option<u64>,
+    draining: bool,
+    /// What a spot instance bids, in dollars per hour.
+    bid: Option<f64>,
+    /// What a spot instance currently costs, in dollars per hour.
+    spot_price: Option<f64>,
+}
+
+async fn list(ctx: &Context, args: ListArgs) -> Result<()> {
+    let config = hardware::parse_config_file(&ctx.config_file)?;
+    let devices = if args.untagged {
+        unmanaged_spot_instances(ctx, &config.tags(), &config.known_tags(), &config.exclude).await?
+    } else {
+        managed_devices(ctx, &config.tags(), &config.known_tags(), &BTreeSet::new()).await?
+    };
+    let jobs = ctx.prometheus.current_jobs_bulk(&devices).await?;
+    let prices = spot_prices(ctx).await;
+
+    let now = OffsetDateTime::now_utc();
+    let listed: Vec<ListedDevice> = devices
+        .into_iter()
+        .zip(jobs)
+        .map(|(dev, jobs)| ListedDevice {
+            draining: dev.tags.contains(&ctx.drain_tag),
+            bid: dev.spot_price_max,
+            spot_price: dev.spot_price(&prices),
+            short_id: dev.short_id,
+            plan: dev.plan.class,
+            metro: dev.metro.map(|metro| metro.code),
+            state: dev.state,
+            age_secs: (now - dev.created_at).whole_seconds(),
+            jobs,
+        })
+        .collect();
+
+    match args.output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(&listed)?),
+        Output::Table => {
+            println!(
+                "{:<8} {:<16} {:<5} {:<12} {:>6} {:>4} {:>6} {:>6} DRAINING",
+                "ID", "PLAN", "METRO", "STATE", "AGE", "JOBS", "BID", "$/HR"
+            );
+            for dev in listed.iter() {
+                println!(
+                    "{:<8} {:<16} {:<5} {:<12} {:>6} {:>4} {:>6} {:>6} {}",
+                    dev.short_id,
+                    dev.plan,
+                    dev.metro.as_deref().unwrap_or("?"),
+                    format!("{:?}", dev.state),
+                    format_age(dev.age_secs),
+                    format_jobs(dev.jobs),
+                    format_price(dev.bid),
+                    format_price(dev.spot_price),
+                    if dev.draining { "yes" } else { "no" }
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn gc(ctx: &Context, args: GcArgs) -> Result<()> {
+    let config = hardware::parse_valid_config_file(&ctx.config_file)?;
+    let category_hashes = config.category_hashes();
+    let stuck_before = OffsetDateTime::now_utc() - time::Duration::minutes(args.stuck_after_mins);
+
+    let mut stuck: Vec<device::Device> = vec![];
+    let mut orphaned: Vec<device::Device> = vec![];
+    for device in
+        managed_devices(ctx, &config.tags(), &config.known_tags(), &BTreeSet::new()).await?
+    {
+        if ctx.is_excluded(&device, &config.exclude) {
+            println!("Skipping {}: it's excluded", device.id);
+            continue;
+        }
+
+        // Devices from before category tags can't be told apart from ones we still want.
+        let category_hash = device
+            .tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(hardware::CATEGORY_HASH_TAG_PREFIX));
+
+        if device.state != device::DeviceState::Active && device.created_at < stuck_before {
+            println!(
+                "{} has been {:?} since {}",
+                device.id, device.state, device.created_at
+            );
+            stuck.push(device);
+        } else if category_hash.is_some_and(|hash| !category_hashes.contains(hash)) {
+            println!(
+                "{} is a {} no longer in the config",
+                device.id, device.plan.class
+            );
+            orphaned.push(device);
+        }
     }
-    for dev in to_keep.iter() {
-        let jobs = device::get_current_jobs(&http_client, dev, &prometheus_root).await?;
 
+    let untagged = if args.adopt {
+        unmanaged_spot_instances(ctx, &config.tags(), &config.known_tags(), &config.exclude).await?
+    } else {
+        vec![]
+    };
+    for device in untagged.iter() {
         println!(
-            " {} {} jobs {} {:?}",
-            dev.short_id, jobs, dev.plan.class, dev.ipxe_script_url
+            "{} is a {} without our tags: {:?}",
+            device.id, device.plan.class, device.tags
+        );
+    }
+
+    println!(
+        "Will destroy {} stuck, drain {} orphaned, adopt {} untagged",
+        stuck.len(),
+        orphaned.len(),
+        untagged.len()
+    );
+    if stuck.is_empty() && orphaned.is_empty() && untagged.is_empty() || in_maintenance(ctx)? {
+        return Ok(());
+    }
+    if !args.yes && std::io::stdin().is_terminal() && !confirm("Continue?")? {
+        println!("Aborting.");
+        return Ok(());
+    }
+    shutdown::listen()?;
+
+    for device in stuck.iter() {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        println!("Destroying {}...", device.id);
+        audit::explain(&device.id, "stuck");
+        ctx.provider.destroy_device(device).await?;
+    }
+
+    for device in untagged.iter() {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        let mut tags = device.tags.clone();
+        tags.extend(
+            config
+                .tags()
+                .into_iter()
+                .filter(|tag| !device.tags.contains(tag)),
         );
+        println!("Adopting {}...", device.id);
+        ctx.provider.set_tags(device, tags).await?;
+    }
+
+    for device in orphaned.iter() {
+        audit::explain(&device.id, "orphaned");
+    }
+
+    reconcile::teardown(
+        &ctx.provider,
+        &ctx.prometheus,
+        &ctx.hydra_drain,
+        &orphaned,
+        &ctx.drain_tag,
+        ctx.urgent_termination(&config),
+        ctx.young_after,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn scale_to_zero(ctx: &Context, args: ScaleToZeroArgs) -> Result<()> {
+    let config = hardware::parse_config_file(&ctx.config_file)?;
+
+    let mut inactive: Vec<device::Device> = vec![];
+    let mut active: Vec<device::Device> = vec![];
+    for device in
+        managed_devices(ctx, &config.tags(), &config.known_tags(), &BTreeSet::new()).await?
+    {
+        if ctx.is_excluded(&device, &config.exclude) {
+            println!("Skipping {}: it's excluded", device.id);
+        } else if device.state == device::DeviceState::Active {
+            active.push(device);
+        } else {
+            inactive.push(device);
+        }
+    }
+
+    println!(
+        "Will destroy {} inactive devices, and drain and destroy {} active devices{}",
+        inactive.len(),
+        active.len(),
+        if args.force {
+            " even if they're running jobs"
+        } else {
+            " once they're idle"
+        }
+    );
+    if inactive.is_empty() && active.is_empty() {
+        return Ok(());
+    }
+    if !args.yes && std::io::stdin().is_terminal() && !confirm("Continue?")? {
+        println!("Aborting.");
+        return Ok(());
+    }
+    shutdown::listen()?;
+
+    for device in inactive.iter().chain(active.iter()) {
+        audit::explain(&device.id, "scale-to-zero");
+    }
+
+    // Devices that aren't active yet can't be running jobs.
+    for device in inactive.iter() {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        println!("Destroying {}...", device.id);
+        ctx.provider.destroy_device(device).await?;
+    }
+
+    // Every device is old enough to destroy, and with --force, past the urgent termination date
+    // whatever the config says.
+    let now = OffsetDateTime::now_utc();
+    let mut urgent = ctx.urgent_termination(&config);
+    if args.force {
+        urgent.before = now + time::Duration::DAY;
+        urgent.allowed = true;
     }
-    for dev in desired_hardware.plans.iter() {
-        println!("+-------- 0 jobs {} {:?}", dev.plan, dev.netboot_url);
+    let torn_down = reconcile::teardown(
+        &ctx.provider,
+        &ctx.prometheus,
+        &ctx.hydra_drain,
+        &active,
+        &ctx.drain_tag,
+        urgent,
+        now,
+    )
+    .await?;
+
+    let remaining = active.len() - torn_down.destroyed.len();
+    if remaining > 0 {
+        println!(
+            "{remaining} devices are left, running jobs or with unknown job counts: run this again \
+             later to destroy them"
+        );
     }
 
     Ok(())
 }
+
+fn format_jobs(jobs: Option<u64>) -> String {
+    jobs.map_or_else(|| "?".to_string(), |jobs| jobs.to_string())
+}
+
+fn format_price(price: Option<f64>) -> String {
+    price.map_or_else(|| "-".to_string(), |price| format!("${price:.2}"))
+}
+
+/// Roughly how long ago something was, like `3d4h` or `25m`.
+fn format_age(secs: i64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{days}d{hours}h")
+    } else if hours > 0 {
+        format!("{hours}h{mins}m")
+    } else {
+        format!("{mins}m")
+    }
+}
+
+/// Read a secret from `file` if given, falling back to the `var` environment variable.
+fn read_secret(file: Option<&Path>, var: &str) -> Result<String> {
+    match file {
+        Some(file) => Ok(std::fs::read_to_string(file)
+            .wrap_err_with(|| format!("failed to read {}", file.display()))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string()),
+        None => std::env::var(var).wrap_err_with(|| format!("Please set {var}")),
+    }
+}
+
+/// Use `value` if given, otherwise read it from `file` if given.
+fn optional_secret(value: Option<String>, file: Option<&Path>) -> Result<Option<String>> {
+    match (value, file) {
+        (Some(value), _) => Ok(Some(value)),
+        (None, Some(file)) => Ok(Some(
+            std::fs::read_to_string(file)
+                .wrap_err_with(|| format!("failed to read {}", file.display()))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
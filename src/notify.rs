@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+
+use crate::device::Device;
+use crate::hardware::{device_category_name, CategorySizing};
+use crate::http::{self, CallStats};
+use crate::reconcile::Unfilled;
+
+/// What a `scale` run did, for `--notify-webhook`.
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub created: Vec<String>,
+    pub kept: Vec<String>,
+    pub drained: Vec<String>,
+    pub destroyed: Vec<String>,
+    /// Why parts of the run failed, followed by the error that ended it, if one did.
+    pub errors: Vec<String>,
+    /// How many devices were created, kept, drained, and destroyed for each system and size.
+    pub categories: BTreeMap<String, CategoryCounts>,
+    /// In dollars per hour.
+    pub projected_spend: f64,
+    /// The API requests the run made, by host.
+    pub api_calls: BTreeMap<String, CallStats>,
+    /// The categories left with fewer devices than they wanted, like when bids are too low.
+    pub unfilled: BTreeMap<String, Unfilled>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct CategoryCounts {
+    /// How the category was sized, if it's in the config.
+    #[serde(flatten)]
+    pub sizing: Option<CategorySizing>,
+    /// How many managed devices it had before the run acted.
+    pub had: usize,
+    pub created: usize,
+    pub kept: usize,
+    pub drained: usize,
+    pub destroyed: usize,
+}
+
+impl Summary {
+    pub fn sized(&mut self, sizing: &BTreeMap<String, CategorySizing>) {
+        for (category, sizing) in sizing.iter() {
+            self.categories.entry(category.clone()).or_default().sizing = Some(sizing.clone());
+        }
+    }
+
+    pub fn had<'a>(&mut self, devices: impl IntoIterator<Item = &'a Device>) {
+        for device in devices {
+            self.categories
+                .entry(device_category_name(device))
+                .or_default()
+                .had += 1;
+        }
+    }
+
+    pub fn created(&mut self, devices: &[Device]) {
+        for device in devices.iter() {
+            self.categories
+                .entry(device_category_name(device))
+                .or_default()
+                .created += 1;
+            self.created.push(device.id.clone());
+        }
+    }
+
+    pub fn kept(&mut self, devices: &[Device]) {
+        for device in devices.iter() {
+            self.categories
+                .entry(device_category_name(device))
+                .or_default()
+                .kept += 1;
+            self.kept.push(device.id.clone());
+        }
+    }
+
+    pub fn drained(&mut self, devices: &[Device]) {
+        for device in devices.iter() {
+            self.categories
+                .entry(device_category_name(device))
+                .or_default()
+                .drained += 1;
+            self.drained.push(device.id.clone());
+        }
+    }
+
+    pub fn destroyed(&mut self, devices: &[Device]) {
+        for device in devices.iter() {
+            self.categories
+                .entry(device_category_name(device))
+                .or_default()
+                .destroyed += 1;
+            self.destroyed.push(device.id.clone());
+        }
+    }
+
+    /// Whether the run changed the fleet or went wrong, and so is worth telling anyone about.
+    pub fn is_eventful(&self) -> bool {
+        !(self.created.is_empty()
+            && self.drained.is_empty()
+            && self.destroyed.is_empty()
+            && self.errors.is_empty()
+            && self.unfilled.is_empty())
+    }
+
+    /// A table of each category's sizing and what was done to it.
+    pub fn category_report(&self) -> String {
+        let mut text = format!(
+            "{:<28} {:>8} {:>7} {:>4} {:>4} {:>7} {:>4} {:>7} {:>4} {:>7} {:>9}",
+            "CATEGORY",
+            "RUNNABLE",
+            "DIVISOR",
+            "MIN",
+            "MAX",
+            "DESIRED",
+            "HAD",
+            "CREATED",
+            "KEPT",
+            "DRAINED",
+            "DESTROYED"
+        );
+        for (category, counts) in self.categories.iter() {
+            let sized = |field: fn(&CategorySizing) -> usize| {
+                counts
+                    .sizing
+                    .as_ref()
+                    .map_or_else(|| "-".to_string(), |sizing| field(sizing).to_string())
+            };
+            text.push_str(&format!(
+                "\n{:<28} {:>8} {:>7} {:>4} {:>4} {:>7} {:>4} {:>7} {:>4} {:>7} {:>9}",
+                category,
+                sized(|sizing| sizing.runnable),
+                sized(|sizing| sizing.divisor),
+                sized(|sizing| sizing.minimum),
+                sized(|sizing| sizing.maximum),
+                sized(|sizing| sizing.desired),
+                counts.had,
+                counts.created,
+                counts.kept,
+                counts.drained,
+                counts.destroyed
+            ));
+        }
+
+        text
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "Hydra scaler: created {}, kept {}, drained {}, destroyed {}; projected spend \
+             ${:.2}/hour",
+            self.created.len(),
+            self.kept.len(),
+            self.drained.len(),
+            self.destroyed.len(),
+            self.projected_spend
+        );
+        for (category, counts) in self.categories.iter() {
+            text.push_str(&format!(
+                "\n{}: +{} ={} ~{} -{}",
+                category, counts.created, counts.kept, counts.drained, counts.destroyed
+            ));
+        }
+        for (category, unfilled) in self.unfilled.iter() {
+            text.push_str(&format!(
+                "\n{}: wanted {}, filled {}",
+                category, unfilled.wanted, unfilled.filled
+            ));
+        }
+        for error in self.errors.iter() {
+            text.push_str(&format!("\nError: {error}"));
+        }
+
+        text
+    }
+}
+
+/// Somewhere to POST run summaries to.
+pub struct Webhook {
+    pub http_client: reqwest::Client,
+    pub url: String,
+    /// Send a Slack-style `{"text": ...}` message instead of the summary itself.
+    pub slack: bool,
+}
+
+impl Webhook {
+    pub async fn send(&self, summary: &Summary) -> Result<()> {
+        let request = self.http_client.post(&self.url);
+        let request = if self.slack {
+            request.json(&serde_json::json!({ "text": summary.to_text() }))
+        } else {
+            request.json(summary)
+        };
+
+        let (status, body) = http::send(request)
+            .await
+            .wrap_err("failed to notify the webhook")?;
+        http::check_status(&self.url, status, &body)
+    }
+}
+
+#[cfg(test)]
+pub mod notify_tests {
+    use super::*;
+    use crate::hardware::DesiredDevice;
+    use crate::machine_type::JobSize;
+    use crate::provider::mock::{desired, device_for};
+
+    #[test]
+    fn test_summary_categories() {
+        let desired = DesiredDevice {
+            size: JobSize::BigParallel,
+            ..desired("c3.small.x86")
+        };
+        let now = time::OffsetDateTime::now_utc();
+        let mut untagged = device_for("c", &desired, now);
+        untagged.tags.clear();
+
+        let mut summary = Summary::default();
+        assert!(!summary.is_eventful());
+        summary.kept(&[device_for("a", &desired, now)]);
+        assert!(!summary.is_eventful());
+        summary.created(&[device_for("b", &desired, now)]);
+        summary.destroyed(&[untagged]);
+        assert!(summary.is_eventful());
+
+        assert_eq!(
+            summary.categories["x86_64-linux/BigParallel"],
+            CategoryCounts {
+                sizing: None,
+                had: 0,
+                created: 1,
+                kept: 1,
+                drained: 0,
+                destroyed: 0,
+            }
+        );
+        assert_eq!(summary.categories["unknown/unknown"].destroyed, 1);
+
+        summary.sized(&BTreeMap::from([(
+            "x86_64-linux/BigParallel".to_string(),
+            CategorySizing {
+                runnable: 25,
+                divisor: 10,
+                minimum: 1,
+                maximum: 4,
+                desired: 2,
+            },
+        )]));
+        summary.had([&device_for("a", &desired, now)]);
+        let json = serde_json::to_value(&summary.categories).unwrap();
+        assert_eq!(json["x86_64-linux/BigParallel"]["runnable"], 25);
+        assert_eq!(json["x86_64-linux/BigParallel"]["had"], 1);
+        assert!(json["unknown/unknown"].get("runnable").is_none());
+        let report = summary.category_report();
+        let row = report
+            .lines()
+            .find(|line| line.starts_with("x86_64-linux/BigParallel"))
+            .unwrap();
+        assert_eq!(
+            row.split_whitespace().skip(1).collect::<Vec<_>>(),
+            vec!["25", "10", "1", "4", "2", "1", "1", "1", "0", "0"]
+        );
+        assert!(summary
+            .to_text()
+            .contains("x86_64-linux/BigParallel: +1 =1 ~0 -0"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use eyre::Result;
+
+/// The run succeeded without changing anything.
+pub const UNCHANGED: u8 = 0;
+/// The run failed before changing anything.
+pub const FATAL: u8 = 1;
+/// The run succeeded, and created, retagged, reinstalled, rebooted, or destroyed devices.
+pub const CHANGED: u8 = 10;
+/// The run changed something and then failed, or carried on past failing device operations,
+/// Hydras, or notifications.
+pub const PARTIAL_FAILURE: u8 = 20;
+
+static DID_CHANGE: AtomicBool = AtomicBool::new(false);
+static DID_FAIL: AtomicBool = AtomicBool::new(false);
+/// What [`warn`] was told, for the run summary.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Note that the run changed the fleet.
+pub fn changed() {
+    DID_CHANGE.store(true, Ordering::SeqCst);
+}
+
+/// Note that part of the run failed, but it carried on with the rest.
+pub fn failed() {
+    DID_FAIL.store(true, Ordering::SeqCst);
+}
+
+/// Note that part of the run failed, printing why, and remember it for [`warnings`].
+pub fn warn(message: String) {
+    failed();
+    println!("WARNING: {message}");
+    WARNINGS.lock().unwrap().push(message);
+}
+
+/// Everything [`warn`] was told so far.
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// The process exit code for a run that ended with `result`.
+pub fn exit_code(result: &Result<()>) -> u8 {
+    code(
+        result.is_err(),
+        DID_CHANGE.load(Ordering::SeqCst),
+        DID_FAIL.load(Ordering::SeqCst),
+    )
+}
+
+fn code(fatal: bool, changed: bool, failed: bool) -> u8 {
+    match (fatal, changed, failed) {
+        (true, false, _) => FATAL,
+        (true, true, _) | (false, _, true) => PARTIAL_FAILURE,
+        (false, true, false) => CHANGED,
+        (false, false, false) => UNCHANGED,
+    }
+}
+
+#[cfg(test)]
+pub mod outcome_tests {
+    use super::*;
+
+    #[test]
+    fn test_code() {
+        assert_eq!(code(false, false, false), UNCHANGED);
+        assert_eq!(code(false, true, false), CHANGED);
+        assert_eq!(code(false, false, true), PARTIAL_FAILURE);
+        assert_eq!(code(true, true, false), PARTIAL_FAILURE);
+        assert_eq!(code(true, false, false), FATAL);
+        assert_eq!(code(true, false, true), FATAL);
+    }
+
+    #[test]
+    fn test_warn() {
+        warn("failed to destroy outcome-test".into());
+        assert!(DID_FAIL.load(Ordering::SeqCst));
+        assert!(warnings().contains(&"failed to destroy outcome-test".to_string()));
+    }
+}
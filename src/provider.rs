@@ -0,0 +1,312 @@
+use eyre::Result;
+
+use crate::device::{Device, DeviceState};
+use crate::hardware::DesiredDevice;
+use crate::shutdown;
+
+/// The operations the scaler needs from wherever its devices run.
+pub trait CloudProvider {
+    /// Every device in the project, whether or not we manage it, or just those tagged `tag`.
+    /// The filtering may be loose, so callers should still check the tags themselves.
+    async fn list_devices(&self, tag: Option<&str>) -> Result<Vec<Device>>;
+
+    async fn get_device(&self, device_id: &str) -> Result<Device>;
+
+    async fn create_device(&self, desired: DesiredDevice) -> Result<Device>;
+
+    /// Create `count` of the desired device in a single request, like an Equinix spot market
+    /// request, getting either all of them or none.
+    async fn request_devices(&self, desired: DesiredDevice, count: usize) -> Result<Vec<Device>>;
+
+    /// Destroy the device, succeeding if it is already gone.
+    async fn destroy_device(&self, device: &Device) -> Result<()>;
+
+    /// Replace the device's tags with `tags`.
+    async fn set_tags(&self, device: &Device, tags: Vec<String>) -> Result<Device>;
+
+    /// Reinstall the device to boot `desired`'s netboot URL, which must be of the same plan, and
+    /// retag it as `desired`'s category.
+    async fn reinstall_device(&self, device: &Device, desired: &DesiredDevice) -> Result<Device>;
+
+    async fn reboot_device(&self, device: &Device) -> Result<()>;
+}
+
+/// Somewhere to find out how busy a device is.
+pub trait JobCounter {
+    /// How many jobs the device is running, or `None` if that isn't known.
+    async fn current_jobs(&self, device: &Device) -> Result<Option<u64>>;
+}
+
+/// Somewhere that hands out work to devices, and can be told to stop.
+pub trait Drainer {
+    /// Stop scheduling new jobs on the device. Called on every run until the device is destroyed,
+    /// so it should be idempotent.
+    async fn drain(&self, device: &Device) -> Result<()>;
+}
+
+impl<T: Drainer> Drainer for Option<T> {
+    async fn drain(&self, device: &Device) -> Result<()> {
+        match self {
+            Some(drainer) => drainer.drain(device).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Poll the device until it is active, returning `false` if it isn't active by the deadline or
+/// we're asked to stop.
+pub async fn wait_for_active(
+    provider: &impl CloudProvider,
+    device_id: &str,
+    timeout: std::time::Duration,
+) -> Result<bool> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let device = provider.get_device(device_id).await?;
+        if device.state == DeviceState::Active {
+            return Ok(true);
+        }
+
+        if tokio::time::Instant::now() >= deadline || shutdown::requested() {
+            if device.state == DeviceState::Queued {
+                println!(
+                    "WARNING: {} is still queued, its bid may be too low for Equinix to fill",
+                    device.id
+                );
+            }
+            return Ok(false);
+        }
+
+        println!(
+            "Waiting for {} to become active ({:?})",
+            device.id, device.state
+        );
+        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+
+    use eyre::{eyre, Result};
+    use time::OffsetDateTime;
+
+    use super::{CloudProvider, Drainer, JobCounter};
+    use crate::device::{Device, DeviceState, DeviceType, Plan, Unfillable};
+    use crate::hardware::{BillingCycle, DesiredDevice, HardwarePlan};
+    use crate::machine_type::{JobSize, System};
+
+    /// A provider and job counter backed by a programmable device list, which records every
+    /// mutation made through it.
+    #[derive(Default)]
+    pub struct MockProvider {
+        pub devices: Mutex<Vec<Device>>,
+        pub jobs: HashMap<String, u64>,
+        /// Devices whose jobs fail to be counted.
+        pub failing_jobs: HashSet<String>,
+        /// Plans that fail to be created as spot devices.
+        pub failing_plans: HashSet<String>,
+        pub created: Mutex<Vec<DesiredDevice>>,
+        /// How many times `request_devices` was called.
+        pub requests: Mutex<usize>,
+        pub destroyed: Mutex<Vec<String>>,
+        pub tagged: Mutex<Vec<(String, Vec<String>)>>,
+        pub drained: Mutex<Vec<String>>,
+        pub reinstalled: Mutex<Vec<String>>,
+        pub rebooted: Mutex<Vec<String>>,
+    }
+
+    impl MockProvider {
+        pub fn new(devices: Vec<Device>) -> Self {
+            MockProvider {
+                devices: Mutex::new(devices),
+                ..Default::default()
+            }
+        }
+    }
+
+    /// A small x86_64 spot device of `plan`, booting a netboot URL named after it.
+    pub fn desired(plan: &str) -> DesiredDevice {
+        DesiredDevice {
+            system: System("x86_64-linux".into()),
+            size: JobSize::Small,
+            plan: HardwarePlan {
+                bid: 1.0,
+                plan: plan.into(),
+                equivalent_classes: vec![],
+                netboot_url: format!("https://netboot.example/{plan}"),
+                always_pxe: true,
+                operating_system: "custom_ipxe".into(),
+                weight: 1,
+                userdata: None,
+                slots: 1,
+            },
+            tags: vec!["hydra".into()],
+            metros: vec!["da".into()],
+            allow_on_demand: false,
+            on_demand: false,
+            ssh_key_ids: vec![],
+            project_id: None,
+            billing_cycle: BillingCycle::Hourly,
+        }
+    }
+
+    /// An active spot instance booting `desired`'s plan, created at `created_at`.
+    pub fn device_for(id: &str, desired: &DesiredDevice, created_at: OffsetDateTime) -> Device {
+        Device {
+            hostname: desired.plan.plan.clone(),
+            id: id.to_string(),
+            short_id: id.to_string(),
+            created_at,
+            device_type: DeviceType::SpotInstance,
+            state: DeviceState::Active,
+            ipxe_script_url: desired.plan.ipxe_script_url(),
+            spot_instance: true,
+            spot_price_max: Some(desired.plan.bid),
+            plan: Plan {
+                class: desired.plan.plan.clone(),
+            },
+            metro: None,
+            tags: desired
+                .tags
+                .iter()
+                .cloned()
+                .chain(desired.category_tags())
+                .collect(),
+            project_id: desired.project_id.clone().unwrap_or_default(),
+        }
+    }
+
+    impl CloudProvider for MockProvider {
+        async fn list_devices(&self, tag: Option<&str>) -> Result<Vec<Device>> {
+            Ok(self
+                .devices
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|device| tag.is_none_or(|tag| device.tags.iter().any(|t| t == tag)))
+                .cloned()
+                .collect())
+        }
+
+        async fn get_device(&self, device_id: &str) -> Result<Device> {
+            self.devices
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|device| device.id == device_id)
+                .cloned()
+                .ok_or(eyre!("no device {}", device_id))
+        }
+
+        async fn create_device(&self, desired: DesiredDevice) -> Result<Device> {
+            if !desired.on_demand && self.failing_plans.contains(&desired.plan.plan) {
+                return Err(eyre!(Unfillable::NoCapacity));
+            }
+
+            let mut created = self.created.lock().unwrap();
+            let mut device = device_for(
+                &format!("created-{}", created.len()),
+                &desired,
+                OffsetDateTime::now_utc(),
+            );
+            if desired.on_demand {
+                device.device_type = DeviceType::OnDemand;
+                device.spot_instance = false;
+            }
+            created.push(desired);
+            self.devices.lock().unwrap().push(device.clone());
+
+            Ok(device)
+        }
+
+        async fn request_devices(
+            &self,
+            desired: DesiredDevice,
+            count: usize,
+        ) -> Result<Vec<Device>> {
+            *self.requests.lock().unwrap() += 1;
+            if self.failing_plans.contains(&desired.plan.plan) {
+                return Err(eyre!(Unfillable::NoCapacity));
+            }
+
+            let mut devices = vec![];
+            for _ in 0..count {
+                devices.push(self.create_device(desired.clone()).await?);
+            }
+
+            Ok(devices)
+        }
+
+        async fn destroy_device(&self, device: &Device) -> Result<()> {
+            self.destroyed.lock().unwrap().push(device.id.clone());
+            self.devices
+                .lock()
+                .unwrap()
+                .retain(|existing| existing.id != device.id);
+
+            Ok(())
+        }
+
+        async fn set_tags(&self, device: &Device, tags: Vec<String>) -> Result<Device> {
+            self.tagged
+                .lock()
+                .unwrap()
+                .push((device.id.clone(), tags.clone()));
+
+            let mut devices = self.devices.lock().unwrap();
+            let existing = devices
+                .iter_mut()
+                .find(|existing| existing.id == device.id)
+                .ok_or(eyre!("no device {}", device.id))?;
+            existing.tags = tags;
+
+            Ok(existing.clone())
+        }
+
+        async fn reinstall_device(
+            &self,
+            device: &Device,
+            desired: &DesiredDevice,
+        ) -> Result<Device> {
+            self.reinstalled.lock().unwrap().push(device.id.clone());
+
+            let mut devices = self.devices.lock().unwrap();
+            let existing = devices
+                .iter_mut()
+                .find(|existing| existing.id == device.id)
+                .ok_or(eyre!("no device {}", device.id))?;
+            let reinstalled = device_for(&device.id, desired, device.created_at);
+            existing.ipxe_script_url = reinstalled.ipxe_script_url;
+            existing.tags = reinstalled.tags;
+
+            Ok(existing.clone())
+        }
+
+        async fn reboot_device(&self, device: &Device) -> Result<()> {
+            self.rebooted.lock().unwrap().push(device.id.clone());
+
+            Ok(())
+        }
+    }
+
+    impl Drainer for MockProvider {
+        async fn drain(&self, device: &Device) -> Result<()> {
+            self.drained.lock().unwrap().push(device.id.clone());
+
+            Ok(())
+        }
+    }
+
+    impl JobCounter for MockProvider {
+        async fn current_jobs(&self, device: &Device) -> Result<Option<u64>> {
+            if self.failing_jobs.contains(&device.id) {
+                return Err(eyre!("no job count for {}", device.id));
+            }
+
+            Ok(Some(self.jobs.get(&device.id).copied().unwrap_or(0)))
+        }
+    }
+}
@@ -0,0 +1,2318 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use eyre::Result;
+use futures::StreamExt;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::device::{self, Device, DeviceState, DeviceType};
+use crate::hardware::{self, DesiredDevice};
+use crate::provider::{CloudProvider, Drainer, JobCounter};
+use crate::state::FailureRecord;
+use crate::{audit, outcome, shutdown};
+
+pub const DEFAULT_MAX_CONCURRENCY: usize = 8;
+
+/// How many creates, tags, or destroys to have in flight at once.
+static MAX_CONCURRENCY: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_CONCURRENCY);
+
+/// Have at most `max` creates, tags, or destroys in flight at once from now on.
+pub fn limit_concurrency(max: usize) {
+    MAX_CONCURRENCY.store(max.max(1), Ordering::SeqCst);
+}
+
+/// Run `op` on each item, with at most [`limit_concurrency`]'s limit running at once, returning
+/// their outputs in order.
+async fn bounded<T, F: Future>(
+    items: impl IntoIterator<Item = T>,
+    op: impl FnMut(T) -> F,
+) -> Vec<F::Output> {
+    bounded_by(MAX_CONCURRENCY.load(Ordering::SeqCst), items, op).await
+}
+
+async fn bounded_by<T, F: Future>(
+    limit: usize,
+    items: impl IntoIterator<Item = T>,
+    op: impl FnMut(T) -> F,
+) -> Vec<F::Output> {
+    futures::stream::iter(items)
+        .map(op)
+        .buffered(limit)
+        .collect()
+        .await
+}
+
+/// What to do with the managed fleet to get it to the desired hardware.
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    pub to_keep: Vec<Device>,
+    pub to_delete: Vec<Device>,
+    pub to_create: Vec<DesiredDevice>,
+    /// The bids of the devices we're keeping, in dollars per hour.
+    pub kept_spend: f64,
+    /// The on-demand devices in `to_keep`, and the hardware to replace each with if spot devices
+    /// can be created again.
+    pub on_demand: Vec<(Device, DesiredDevice)>,
+    /// How many of `to_keep` are old devices being kept until their replacements are active.
+    pub deferred: usize,
+    /// The devices in `to_delete` whose only problem is their netboot URL, and the hardware in
+    /// `to_create` that would replace each.
+    pub drifted: Vec<(Device, DesiredDevice)>,
+    /// How many devices each category wants, by [`hardware::category_name`], before any limits.
+    pub wanted: BTreeMap<String, usize>,
+    /// Why each device was kept or deleted, by device ID, before any limits or exclusions.
+    pub decisions: BTreeMap<String, Decision>,
+    /// Devices among `to_keep` and `to_delete` that are missing our tags, with the tags to give
+    /// them. See [`adoptable`].
+    pub to_adopt: Vec<Device>,
+    /// How each category was sized, by [`hardware::category_name`].
+    pub sizing: BTreeMap<String, hardware::CategorySizing>,
+}
+
+/// Why [`reconcile`] kept or deleted a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Deleted: it has the drain tag.
+    Draining,
+    /// Deleted: it was created before `older_than`.
+    Cycling,
+    /// Kept: it's old enough to cycle out, but holds its category at its minimum.
+    Deferred,
+    /// Kept: it matched desired hardware of this category.
+    Matched(String),
+    /// Kept: nothing wants it, but it was created after `young_after`.
+    Young,
+    /// Deleted: its plan is still wanted, but it boots a netboot URL that isn't.
+    Drifted,
+    /// Deleted: nothing wants it.
+    Unwanted,
+}
+
+impl Decision {
+    pub fn is_delete(&self) -> bool {
+        matches!(
+            self,
+            Decision::Draining | Decision::Cycling | Decision::Drifted | Decision::Unwanted
+        )
+    }
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Decision::Draining => write!(f, "it has the drain tag"),
+            Decision::Cycling => write!(f, "it's old enough to cycle out"),
+            Decision::Deferred => write!(
+                f,
+                "it's old enough to cycle out, but its category needs it to stay at its minimum"
+            ),
+            Decision::Matched(category) => write!(f, "it matched the desired {category}"),
+            Decision::Young => write!(
+                f,
+                "nothing wants it, but it hasn't reached the minimum device lifetime"
+            ),
+            Decision::Drifted => write!(
+                f,
+                "its plan is still wanted, but it boots a netboot URL that isn't"
+            ),
+            Decision::Unwanted => write!(f, "nothing wants it"),
+        }
+    }
+}
+
+/// A category with fewer devices than it wants.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Unfilled {
+    pub wanted: usize,
+    /// Queued devices, which Equinix hasn't found capacity for at their bid, don't count.
+    pub filled: usize,
+}
+
+/// The categories that `plan` and the devices `created` for it leave short, by
+/// [`hardware::category_name`].
+pub fn unfilled(plan: &Reconciliation, created: &[Device]) -> BTreeMap<String, Unfilled> {
+    let mut filled: HashMap<String, usize> = HashMap::new();
+    for device in plan.to_keep.iter().chain(created.iter()) {
+        if device.state != DeviceState::Queued {
+            *filled
+                .entry(hardware::device_category_name(device))
+                .or_default() += 1;
+        }
+    }
+
+    plan.wanted
+        .iter()
+        .filter_map(|(category, wanted)| {
+            let filled = filled.get(category).copied().unwrap_or(0);
+            (filled < *wanted).then(|| {
+                (
+                    category.clone(),
+                    Unfilled {
+                        wanted: *wanted,
+                        filled,
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+impl Reconciliation {
+    /// What the kept and created devices will bid, in dollars per hour.
+    pub fn projected_spend(&self) -> f64 {
+        self.kept_spend + self.create_spend()
+    }
+
+    pub fn create_spend(&self) -> f64 {
+        self.to_create.iter().map(|desired| desired.plan.bid).sum()
+    }
+}
+
+/// What to make of a device with no iPXE script URL, when its plan boots one. Equinix doesn't
+/// report the URL for some devices, like ones created by hand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingIpxeUrl {
+    /// Assume it boots something else, so replace it.
+    Replace,
+    /// Assume it boots the plan's netboot URL, so keep it if it's wanted.
+    Keep,
+}
+
+/// A reconciliation's changes, grouped by what's happening to which plan where and why, for
+/// reviewing before they're applied.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+    pub create: usize,
+    pub replace: usize,
+    pub destroy: usize,
+    pub keep: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub action: Action,
+    pub count: usize,
+    pub plan: String,
+    pub metros: Vec<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Replace,
+    Destroy,
+}
+
+impl Diff {
+    /// Group `plan`'s changes. Drifted devices whose replacement is still to be created are
+    /// shown as replacements rather than as a destroy and a create.
+    pub fn new(plan: &Reconciliation, drain_tag: &str, older_than: OffsetDateTime) -> Self {
+        let mut diff = Diff {
+            keep: plan.to_keep.len(),
+            ..Default::default()
+        };
+
+        let mut to_create: Vec<&DesiredDevice> = plan.to_create.iter().collect();
+        let mut replaced: BTreeSet<&str> = BTreeSet::new();
+        for (device, desired) in plan.drifted.iter() {
+            let Some(idx) = to_create
+                .iter()
+                .position(|create| create.category_hash() == desired.category_hash())
+            else {
+                continue;
+            };
+            if !plan.to_delete.iter().any(|deleted| deleted.id == device.id) {
+                continue;
+            }
+
+            to_create.remove(idx);
+            replaced.insert(&device.id);
+            diff.add(
+                Action::Replace,
+                &device.plan.class,
+                device
+                    .metro
+                    .iter()
+                    .map(|metro| metro.code.clone())
+                    .collect(),
+                Some("netboot changed"),
+            );
+        }
+
+        for desired in to_create {
+            diff.add(
+                Action::Create,
+                &desired.plan.plan,
+                desired.metros.clone(),
+                None,
+            );
+        }
+
+        for device in plan.to_delete.iter() {
+            if replaced.contains(device.id.as_str()) {
+                continue;
+            }
+
+            let reason = if device.tags.iter().any(|tag| tag == drain_tag) {
+                "draining"
+            } else if device.created_at < older_than {
+                "too old"
+            } else {
+                "not wanted"
+            };
+            diff.add(
+                Action::Destroy,
+                &device.plan.class,
+                device
+                    .metro
+                    .iter()
+                    .map(|metro| metro.code.clone())
+                    .collect(),
+                Some(reason),
+            );
+        }
+
+        diff
+    }
+
+    fn add(&mut self, action: Action, plan: &str, metros: Vec<String>, reason: Option<&str>) {
+        match action {
+            Action::Create => self.create += 1,
+            Action::Replace => self.replace += 1,
+            Action::Destroy => self.destroy += 1,
+        }
+
+        let reason = reason.map(|reason| reason.to_string());
+        match self.changes.iter_mut().find(|change| {
+            change.action == action
+                && change.plan == plan
+                && change.metros == metros
+                && change.reason == reason
+        }) {
+            Some(change) => change.count += 1,
+            None => self.changes.push(Change {
+                action,
+                count: 1,
+                plan: plan.to_string(),
+                metros,
+                reason,
+            }),
+        }
+    }
+
+    /// The changes, one group per line like `+ create 5x c3.large.arm64 (da)`, then the totals.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for change in self.changes.iter() {
+            let (symbol, verb) = match change.action {
+                Action::Create => ('+', "create"),
+                Action::Replace => ('~', "replace"),
+                Action::Destroy => ('-', "destroy"),
+            };
+            let details: Vec<String> = [
+                change.metros.join(","),
+                change.reason.clone().unwrap_or_default(),
+            ]
+            .into_iter()
+            .filter(|detail| !detail.is_empty())
+            .collect();
+            text.push_str(&format!(
+                "{} {} {}x {}",
+                symbol, verb, change.count, change.plan
+            ));
+            if !details.is_empty() {
+                text.push_str(&format!(" ({})", details.join(", ")));
+            }
+            text.push('\n');
+        }
+        text.push_str(&format!(
+            "Plan: {} to create, {} to replace, {} to destroy, {} to keep.",
+            self.create, self.replace, self.destroy, self.keep
+        ));
+
+        text
+    }
+}
+
+/// The devices [`teardown`] newly drained, and the ones it destroyed.
+#[derive(Debug, Default)]
+pub struct Teardown {
+    pub drained: Vec<Device>,
+    pub destroyed: Vec<Device>,
+}
+
+/// Match the managed devices up with the desired hardware. Devices that are old, draining, or
+/// not wanted are deleted, and desired hardware without a device is created. Unwanted devices
+/// created after `young_after` are kept, in case demand comes back before they've paid off, and
+/// old devices are kept while their category has fewer active devices than its entry in
+/// `minimums`.
+pub fn reconcile(
+    devices: Vec<Device>,
+    mut desired: Vec<DesiredDevice>,
+    drain_tag: &str,
+    older_than: OffsetDateTime,
+    young_after: OffsetDateTime,
+    minimums: &HashMap<String, usize>,
+    missing_ipxe_url: MissingIpxeUrl,
+) -> Reconciliation {
+    let mut wanted: BTreeMap<String, usize> = BTreeMap::new();
+    for desired in desired.iter() {
+        *wanted
+            .entry(hardware::category_name(&desired.system, &desired.size))
+            .or_default() += 1;
+    }
+
+    // Take out all the old devices that we want to cycle out anyway,
+    // and devices which are already in drain
+    let (mut to_delete, mut devices): (Vec<Device>, Vec<Device>) =
+        devices.into_iter().partition(|device| {
+            (device.created_at < older_than) || device.tags.iter().any(|tag| tag == drain_tag)
+        });
+    let mut decisions: BTreeMap<String, Decision> = to_delete
+        .iter()
+        .map(|device| {
+            let decision = if device.tags.iter().any(|tag| tag == drain_tag) {
+                Decision::Draining
+            } else {
+                Decision::Cycling
+            };
+            (device.id.clone(), decision)
+        })
+        .collect();
+    // Match spot devices first, so that on-demand devices are the ones left over.
+    devices.sort_by_key(|device| device.device_type == DeviceType::OnDemand);
+
+    let desired_urls: BTreeSet<String> = desired
+        .iter()
+        .filter_map(|desired| desired.plan.ipxe_script_url())
+        .collect();
+
+    for device in to_delete.iter().chain(devices.iter()) {
+        if let Some(tagged) = device.tagged_created_at() {
+            if (tagged - device.created_at).abs() > time::Duration::HOUR {
+                println!(
+                    "WARNING: {} was created at {}, but its tag says {}",
+                    device.id, device.created_at, tagged
+                );
+            }
+        }
+    }
+
+    let mut to_keep: Vec<Device> = vec![];
+    let mut kept_spend: f64 = 0.0;
+    let mut on_demand: Vec<(Device, DesiredDevice)> = vec![];
+    let mut drifted: Vec<(Device, DesiredDevice)> = vec![];
+    for device in devices.into_iter() {
+        // See if desired_hardware has a matching device, preferring the category tag we gave it
+        // at creation time over comparing its plan.
+        let category_hash = device
+            .tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(hardware::CATEGORY_HASH_TAG_PREFIX));
+        if device.ipxe_script_url.is_none()
+            && desired.iter().any(|desired| {
+                desired.plan.uses_ipxe() && desired.plan.is_class(&device.plan.class)
+            })
+        {
+            println!(
+                "WARNING: {} has no iPXE script URL, assuming it boots {}",
+                device.id,
+                match missing_ipxe_url {
+                    MissingIpxeUrl::Replace => "something else",
+                    MissingIpxeUrl::Keep => "its plan's netboot URL",
+                }
+            );
+        }
+        let boots_desired_url = |desired: &DesiredDevice| match (
+            &device.ipxe_script_url,
+            desired.plan.ipxe_script_url(),
+        ) {
+            (None, Some(_)) => missing_ipxe_url == MissingIpxeUrl::Keep,
+            (url, desired_url) => *url == desired_url,
+        };
+        if let Some(idx) = desired.iter().position(|desired| {
+            let same_category = match category_hash {
+                Some(hash) => desired.has_category_hash(hash),
+                None => desired.plan.is_class(&device.plan.class),
+            };
+
+            // A device can't be moved between projects, so one in another project is replaced.
+            let same_project = desired
+                .project_id
+                .as_ref()
+                .is_none_or(|project_id| *project_id == device.project_id);
+
+            same_category && same_project && boots_desired_url(desired)
+        }) {
+            let matched = desired.swap_remove(idx);
+            decisions.insert(
+                device.id.clone(),
+                Decision::Matched(hardware::category_name(&matched.system, &matched.size)),
+            );
+            kept_spend += matched.plan.bid;
+            if device.device_type == DeviceType::OnDemand {
+                on_demand.push((device.clone(), matched));
+            }
+            to_keep.push(device);
+        } else if device.created_at > young_after {
+            println!(
+                "Keeping {} for now: it was only created at {}",
+                device.id, device.created_at
+            );
+            decisions.insert(device.id.clone(), Decision::Young);
+            to_keep.push(device);
+        } else {
+            // A device of a plan we still want, but booting a netboot URL we no longer use, gets
+            // replaced: we'll create a new one in its place and drain this one.
+            decisions.insert(device.id.clone(), Decision::Unwanted);
+            let known_url = device
+                .ipxe_script_url
+                .as_ref()
+                .is_none_or(|url| desired_urls.contains(url));
+            if !known_url {
+                if let Some(replacement) = desired
+                    .iter()
+                    .find(|desired| desired.plan.is_class(&device.plan.class))
+                {
+                    println!(
+                        "Replacing {}: it boots {:?}, but {} devices now boot {:?}",
+                        device.id,
+                        device.ipxe_script_url,
+                        device.plan.class,
+                        replacement.plan.netboot_url
+                    );
+                    decisions.insert(device.id.clone(), Decision::Drifted);
+                    drifted.push((device.clone(), replacement.clone()));
+                }
+            }
+            to_delete.push(device);
+        }
+    }
+
+    // Cycling out old devices shouldn't leave a category below its minimum, so hold on to enough
+    // of them until their replacements are active.
+    let mut active: HashMap<String, usize> = HashMap::new();
+    for device in to_keep
+        .iter()
+        .filter(|device| device.state == DeviceState::Active)
+    {
+        *active
+            .entry(hardware::device_category_name(device))
+            .or_default() += 1;
+    }
+    let mut deferred = 0;
+    let (cycled, mut to_delete): (Vec<Device>, Vec<Device>) =
+        to_delete.into_iter().partition(|device| {
+            device.created_at < older_than
+                && device.state == DeviceState::Active
+                && !device.tags.iter().any(|tag| tag == drain_tag)
+        });
+    for device in cycled.into_iter() {
+        let category = hardware::device_category_name(&device);
+        let minimum = minimums.get(&category).copied().unwrap_or(0);
+        let active = active.entry(category.clone()).or_default();
+        if *active < minimum {
+            println!(
+                "Not cycling out {} yet: {} only has {} other active devices, below its minimum \
+                 of {}",
+                device.id, category, active, minimum
+            );
+            *active += 1;
+            deferred += 1;
+            decisions.insert(device.id.clone(), Decision::Deferred);
+            to_keep.push(device);
+        } else {
+            to_delete.push(device);
+        }
+    }
+
+    Reconciliation {
+        to_keep,
+        to_delete,
+        to_create: desired,
+        kept_spend,
+        on_demand,
+        deferred,
+        drifted,
+        wanted,
+        decisions,
+        to_adopt: vec![],
+        sizing: BTreeMap::new(),
+    }
+}
+
+/// The devices among `unmanaged` that a run which crashed before tagging them left behind: ones
+/// that one of our spot market requests created, or whose hostname `hostname_template` could have
+/// made from one of `plans` and `metros`. A hostname alone isn't enough for a device with tags
+/// other than `tags`, since those would be another deployment's. Each is returned with `tags`
+/// added, for [`adopt`] to give it.
+pub fn adoptable(
+    unmanaged: Vec<Device>,
+    hostname_template: &str,
+    plans: &BTreeSet<String>,
+    metros: &BTreeSet<String>,
+    spot_market_devices: &BTreeSet<String>,
+    tags: &[String],
+) -> Vec<Device> {
+    unmanaged
+        .into_iter()
+        .filter(|device| {
+            spot_market_devices.contains(&device.id)
+                || device.tags.iter().all(|tag| tags.contains(tag))
+                    && device::matches_hostname_template(
+                        hostname_template,
+                        &device.hostname,
+                        plans,
+                        metros,
+                    )
+        })
+        .filter_map(|mut device| {
+            let missing: Vec<String> = tags
+                .iter()
+                .filter(|tag| !device.tags.contains(tag))
+                .cloned()
+                .collect();
+            // It has our tags, but not in a way `--tag-match-mode` accepts.
+            if missing.is_empty() {
+                return None;
+            }
+
+            println!(
+                "Adopting {} ({}): it looks like ours, but is missing the tags {:?}",
+                device.id, device.hostname, missing
+            );
+            device.tags.extend(missing);
+            Some(device)
+        })
+        .collect()
+}
+
+/// Give each device the tags [`adoptable`] added, so later runs see it as managed.
+pub async fn adopt(provider: &impl CloudProvider, to_adopt: &[Device]) -> Result<()> {
+    for device in to_adopt.iter() {
+        if shutdown::requested() {
+            println!("Not adopting the remaining devices: stopping");
+            break;
+        }
+
+        println!("Tagging {}...", device.id);
+        provider.set_tags(device, device.tags.clone()).await?;
+    }
+
+    Ok(())
+}
+
+/// How many times a plan may fail to be created in a run before its remaining creates go to the
+/// category's other plans.
+const PLAN_FAILURES_BEFORE_FALLBACK: usize = 2;
+
+/// Group identical desired hardware, in the order each first appears, with how many of it to
+/// create.
+pub fn batch(to_create: &[DesiredDevice]) -> Vec<(DesiredDevice, usize)> {
+    let mut batches: Vec<(DesiredDevice, usize)> = vec![];
+    for desired in to_create.iter() {
+        match batches.iter_mut().find(|(batch, _)| {
+            batch.category_hash() == desired.category_hash()
+                && batch.on_demand == desired.on_demand
+                && batch.project_id == desired.project_id
+        }) {
+            Some((_, count)) => *count += 1,
+            None => batches.push((desired.clone(), 1)),
+        }
+    }
+
+    batches
+}
+
+/// Stops creating a category's devices once `threshold` creates in a row have failed, so that a
+/// doomed config doesn't hammer Equinix every run. A failed spot market request counts as a
+/// failed create for each device it asked for. After `cooldown`, a single create is tried
+/// again, and the rest follow if it succeeds.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    pub threshold: usize,
+    pub cooldown: time::Duration,
+    /// By [`hardware::category_name`], remembered across runs in the state file.
+    pub failures: HashMap<String, FailureRecord>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Breaker {
+    /// Creating as usual.
+    Closed,
+    /// Halted until the cooldown has passed.
+    Open(OffsetDateTime),
+    /// Trying one create to see whether creates work again.
+    HalfOpen,
+}
+
+impl Default for CircuitBreaker {
+    /// One that never halts creates.
+    fn default() -> Self {
+        CircuitBreaker {
+            threshold: usize::MAX,
+            cooldown: time::Duration::ZERO,
+            failures: HashMap::new(),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    fn state(&self, category: &str, now: OffsetDateTime) -> Breaker {
+        match self
+            .failures
+            .get(category)
+            .and_then(|record| record.halted_at)
+        {
+            None => Breaker::Closed,
+            Some(halted_at) if now < halted_at + self.cooldown => {
+                Breaker::Open(halted_at + self.cooldown)
+            }
+            Some(_) => Breaker::HalfOpen,
+        }
+    }
+
+    /// Note whether a create for the category succeeded, returning whether that halted its
+    /// creates.
+    fn record(&mut self, category: &str, succeeded: bool, now: OffsetDateTime) -> bool {
+        if succeeded {
+            self.failures.remove(category);
+            return false;
+        }
+
+        let cooldown = self.cooldown;
+        let record = self.failures.entry(category.to_string()).or_default();
+        let halted = record
+            .halted_at
+            .is_some_and(|halted_at| now < halted_at + cooldown);
+        record.consecutive += 1;
+        // A failed retry after the cooldown halts creates again straight away.
+        if record.consecutive >= self.threshold || record.halted_at.is_some() {
+            record.halted_at = Some(now);
+        }
+        record.halted_at.is_some() && !halted
+    }
+
+    /// Whether the category's creates may be attempted, printing why not if they can't.
+    fn allows(&self, category: &str, count: usize, now: OffsetDateTime) -> bool {
+        match self.state(category, now) {
+            Breaker::Open(until) => {
+                outcome::warn(format!(
+                    "not creating {count} devices for {category}: its creates kept \
+                     failing, so they're halted until {until}"
+                ));
+                false
+            }
+            Breaker::Closed | Breaker::HalfOpen => true,
+        }
+    }
+
+    /// Note the outcome of each of the category's creates, warning if they've been halted.
+    fn record_all(&mut self, category: &str, results: &[bool], now: OffsetDateTime) {
+        for succeeded in results.iter() {
+            if self.record(category, *succeeded, now) {
+                outcome::warn(format!(
+                    "{} creates in a row failed for {}, halting its creates until {}",
+                    self.failures[category].consecutive,
+                    category,
+                    now + self.cooldown
+                ));
+            }
+        }
+    }
+}
+
+/// Create a device for each of the desired hardware, creating identical ones concurrently, or in
+/// one spot market request if `spot_market_requests`. Creates that fail are skipped, so that one
+/// capacity-constrained plan doesn't hold up the rest, or retried with another of the category's
+/// plans once their plan keeps failing. Categories that `breaker` has halted are skipped too.
+pub async fn create(
+    provider: &impl CloudProvider,
+    to_create: &[DesiredDevice],
+    breaker: &mut CircuitBreaker,
+    spot_market_requests: bool,
+) -> Result<Vec<Device>> {
+    let mut created: Vec<Device> = vec![];
+    let mut failures: HashMap<String, usize> = HashMap::new();
+    let failing = |failures: &HashMap<String, usize>, plan: &str| {
+        failures.get(plan).copied().unwrap_or(0) >= PLAN_FAILURES_BEFORE_FALLBACK
+    };
+    let fallback = |failures: &HashMap<String, usize>, desired: &DesiredDevice| {
+        to_create
+            .iter()
+            .find(|other| {
+                other.system == desired.system
+                    && other.size == desired.size
+                    && !failing(failures, &other.plan.plan)
+            })
+            .map(|other| other.plan.clone())
+    };
+
+    let mut batches: VecDeque<(DesiredDevice, usize)> = batch(to_create).into();
+    while let Some((mut desired, mut count)) = batches.pop_front() {
+        if shutdown::requested() {
+            println!("Not creating the remaining devices: stopping");
+            break;
+        }
+
+        let category = hardware::category_name(&desired.system, &desired.size);
+        let now = OffsetDateTime::now_utc();
+        if !breaker.allows(&category, count, now) {
+            continue;
+        }
+        if breaker.state(&category, now) == Breaker::HalfOpen && count > 1 {
+            println!(
+                "Trying one create for {category} before the rest, since its creates were halted"
+            );
+            batches.push_front((desired.clone(), count - 1));
+            count = 1;
+        }
+
+        if failing(&failures, &desired.plan.plan) {
+            if let Some(plan) = fallback(&failures, &desired) {
+                println!(
+                    "{} keeps failing to create, using {} instead",
+                    desired.plan.plan, plan.plan
+                );
+                desired.plan = plan;
+            }
+        }
+
+        println!("Creating {}x {} for {}", count, desired.plan.plan, category);
+        let (devices, errors) = create_batch(provider, &desired, count, spot_market_requests).await;
+        let failed = if errors.is_empty() {
+            0
+        } else {
+            count.saturating_sub(devices.len())
+        };
+        breaker.record_all(
+            &category,
+            &(0..count)
+                .map(|idx| idx < count - failed)
+                .collect::<Vec<_>>(),
+            OffsetDateTime::now_utc(),
+        );
+        created.extend(devices);
+        for e in errors {
+            match e.downcast_ref::<device::Unfillable>() {
+                Some(unfillable) => println!(
+                    "WARNING: Equinix won't fill {} at ${}/hour: {}; consider raising its bid",
+                    desired.plan.plan, desired.plan.bid, unfillable
+                ),
+                None => println!("WARNING: failed to create {}: {:?}", desired.plan.plan, e),
+            }
+        }
+        *failures.entry(desired.plan.plan.clone()).or_default() += failed;
+        if failed == 0 {
+            continue;
+        }
+
+        if failing(&failures, &desired.plan.plan) && fallback(&failures, &desired).is_some() {
+            batches.push_front((desired, failed));
+        } else if desired.allow_on_demand
+            && breaker.allows(&category, failed, OffsetDateTime::now_utc())
+        {
+            println!(
+                "Creating {}x on-demand {} instead",
+                failed, desired.plan.plan
+            );
+            let on_demand = DesiredDevice {
+                on_demand: true,
+                ..desired.clone()
+            };
+            let results = bounded(0..failed, |_| provider.create_device(on_demand.clone())).await;
+            breaker.record_all(
+                &category,
+                &results.iter().map(Result::is_ok).collect::<Vec<_>>(),
+                OffsetDateTime::now_utc(),
+            );
+            for result in results {
+                match result {
+                    Ok(device) => created.push(device),
+                    Err(e) => {
+                        outcome::warn(format!(
+                            "failed to create an on-demand {}: {:?}",
+                            desired.plan.plan, e
+                        ));
+                    }
+                }
+            }
+        } else {
+            outcome::warn(format!(
+                "failed to create {}x {} for {}",
+                failed, desired.plan.plan, category
+            ));
+        }
+    }
+
+    Ok(created)
+}
+
+/// Create `count` of the desired device, returning those that were created and why the rest
+/// weren't. A spot market request creates all of them or none.
+async fn create_batch(
+    provider: &impl CloudProvider,
+    desired: &DesiredDevice,
+    count: usize,
+    spot_market_request: bool,
+) -> (Vec<Device>, Vec<eyre::Report>) {
+    if spot_market_request {
+        return match provider.request_devices(desired.clone(), count).await {
+            Ok(devices) => (devices, vec![]),
+            Err(e) => (vec![], vec![e]),
+        };
+    }
+
+    let mut devices = vec![];
+    let mut errors = vec![];
+    for result in bounded(0..count, |_| provider.create_device(desired.clone())).await {
+        match result {
+            Ok(device) => devices.push(device),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (devices, errors)
+}
+
+/// Try to create a spot device in place of a kept on-demand device, moving the on-demand devices
+/// whose replacement was created from `to_keep` to `to_delete`. Only one is tried per category
+/// each run, and none for categories whose creates `breaker` hasn't left closed, so that
+/// replacements can't add more creates than the spot capacity coming back is worth.
+pub async fn replace_on_demand(
+    provider: &impl CloudProvider,
+    plan: &mut Reconciliation,
+    breaker: &CircuitBreaker,
+) -> Result<Vec<Device>> {
+    let mut created: Vec<Device> = vec![];
+    let mut tried: BTreeSet<String> = BTreeSet::new();
+    for (device, desired) in std::mem::take(&mut plan.on_demand) {
+        let category = hardware::category_name(&desired.system, &desired.size);
+        if shutdown::requested()
+            || tried.contains(&category)
+            || breaker.state(&category, OffsetDateTime::now_utc()) != Breaker::Closed
+        {
+            plan.on_demand.push((device, desired));
+            continue;
+        }
+        tried.insert(category);
+
+        match provider.create_device(desired.clone()).await {
+            Ok(replacement) => {
+                println!(
+                    "Replacing on-demand {} with spot {}",
+                    device.id, replacement.id
+                );
+                created.push(replacement);
+                plan.to_keep.retain(|kept| kept.id != device.id);
+                plan.to_delete.push(device);
+            }
+            Err(e) => {
+                println!(
+                    "Keeping on-demand {}: still can't create a spot {}: {:?}",
+                    device.id, desired.plan.plan, e
+                );
+                plan.on_demand.push((device, desired));
+            }
+        }
+    }
+
+    Ok(created)
+}
+
+/// Reinstall each idle drifted device that's still to be deleted with its replacement's netboot
+/// URL, instead of creating the replacement, moving it from `to_delete` to `to_keep`.
+pub async fn reinstall_drifted(
+    provider: &impl CloudProvider,
+    jobs: &impl JobCounter,
+    plan: &mut Reconciliation,
+) -> Result<Vec<Device>> {
+    let mut reinstalled: Vec<Device> = vec![];
+    for (device, desired) in std::mem::take(&mut plan.drifted) {
+        if shutdown::requested() {
+            break;
+        }
+
+        // Exclusions and limits may have taken it out of `to_delete`, or its replacement out of
+        // `to_create`, since it was found.
+        if !plan.to_delete.iter().any(|deleted| deleted.id == device.id) {
+            continue;
+        }
+        let Some(idx) = plan
+            .to_create
+            .iter()
+            .position(|create| create.category_hash() == desired.category_hash())
+        else {
+            continue;
+        };
+
+        match jobs.current_jobs(&device).await {
+            Ok(Some(0)) => {}
+            Ok(_) => {
+                println!("Not reinstalling {}: it's busy", device.id);
+                continue;
+            }
+            Err(e) => {
+                println!(
+                    "WARNING: not reinstalling {}: failed to count its jobs: {:?}",
+                    device.id, e
+                );
+                continue;
+            }
+        }
+
+        println!(
+            "Reinstalling {} to boot {:?}",
+            device.id, desired.plan.netboot_url
+        );
+        match provider.reinstall_device(&device, &desired).await {
+            Ok(device) => {
+                plan.to_create.remove(idx);
+                plan.to_delete.retain(|deleted| deleted.id != device.id);
+                plan.to_keep.push(device.clone());
+                reinstalled.push(device);
+            }
+            Err(e) => {
+                outcome::warn(format!("failed to reinstall {}: {:?}", device.id, e));
+            }
+        }
+    }
+
+    Ok(reinstalled)
+}
+
+/// Reboot, rather than destroy, each active, undrained device in `to_delete` that is idle and
+/// `long_idle`, in case it's wedged rather than idle, moving it to `to_keep` so that it's
+/// checked again next run. Each device is only rebooted once.
+pub async fn reboot_idle(
+    provider: &impl CloudProvider,
+    jobs: &impl JobCounter,
+    plan: &mut Reconciliation,
+    drain_tag: &str,
+    long_idle: impl Fn(&Device) -> bool,
+) -> Result<Vec<Device>> {
+    let mut rebooted: Vec<Device> = vec![];
+    for device in std::mem::take(&mut plan.to_delete) {
+        let eligible = !shutdown::requested()
+            && device.state == DeviceState::Active
+            && !device
+                .tags
+                .iter()
+                .any(|tag| tag == drain_tag || tag == device::REBOOTED_TAG)
+            && long_idle(&device);
+        let idle = eligible
+            && match jobs.current_jobs(&device).await {
+                Ok(running) => running == Some(0),
+                Err(e) => {
+                    println!(
+                        "WARNING: not rebooting {}: failed to count its jobs: {:?}",
+                        device.id, e
+                    );
+                    false
+                }
+            };
+        if !idle {
+            plan.to_delete.push(device);
+            continue;
+        }
+
+        println!(
+            "Rebooting {} instead of destroying it: it's been idle for a while, so it may be \
+             wedged",
+            device.id
+        );
+        let mut tags = device.tags.clone();
+        tags.push(device::REBOOTED_TAG.to_string());
+        let tagged = match provider.set_tags(&device, tags).await {
+            Ok(tagged) => tagged,
+            Err(e) => {
+                outcome::warn(format!("failed to tag {}: {:?}", device.id, e));
+                plan.to_delete.push(device);
+                continue;
+            }
+        };
+
+        match provider.reboot_device(&tagged).await {
+            Ok(()) => {
+                plan.to_keep.push(tagged.clone());
+                rebooted.push(tagged);
+            }
+            Err(e) => {
+                outcome::warn(format!("failed to reboot {}: {:?}", device.id, e));
+                plan.to_delete.push(tagged);
+            }
+        }
+    }
+
+    Ok(rebooted)
+}
+
+/// When to destroy devices even though they're running jobs.
+#[derive(Clone, Copy, Debug)]
+pub struct UrgentTermination {
+    /// Devices created before this have exceeded the urgent termination date.
+    pub before: OffsetDateTime,
+    /// Whether to destroy them regardless of their jobs, or wait for them to be idle like any
+    /// other device.
+    pub allowed: bool,
+    /// The most devices to destroy regardless of their jobs in one run.
+    pub max: Option<usize>,
+}
+
+/// Drain every device in `to_delete`, by tagging it and telling `drainer`, and destroy the ones
+/// that are idle or that have exceeded the urgent termination date. Devices created after
+/// `young_after` are only destroyed if urgent.
+pub async fn teardown(
+    provider: &impl CloudProvider,
+    jobs: &impl JobCounter,
+    drainer: &impl Drainer,
+    to_delete: &[Device],
+    drain_tag: &str,
+    urgent: UrgentTermination,
+    young_after: OffsetDateTime,
+) -> Result<Teardown> {
+    let drained = drain(provider, drainer, to_delete, drain_tag).await?;
+    if shutdown::requested() {
+        return Ok(Teardown {
+            drained,
+            destroyed: vec![],
+        });
+    }
+
+    let destroyed = destroy_idle(provider, jobs, to_delete, urgent, young_after).await?;
+    Ok(Teardown { drained, destroyed })
+}
+
+/// Drain every device, by tagging it and telling `drainer`, returning the ones that weren't
+/// tagged already. Devices that fail to be tagged are skipped.
+pub async fn drain(
+    provider: &impl CloudProvider,
+    drainer: &impl Drainer,
+    to_delete: &[Device],
+    drain_tag: &str,
+) -> Result<Vec<Device>> {
+    let results = bounded(to_delete.iter(), |device| async move {
+        if shutdown::requested() {
+            return None;
+        }
+
+        let mut tagged = None;
+        if !device.tags.iter().any(|tag| tag == drain_tag) {
+            println!("Giving {} a {} tag", device.id, drain_tag);
+            let mut tags = device.tags.clone();
+            tags.push(drain_tag.to_string());
+
+            if let Err(e) = provider.set_tags(device, tags).await {
+                outcome::warn(format!("failed to tag {}: {:?}", device.id, e));
+                return None;
+            }
+            tagged = Some(device.clone());
+        }
+
+        if let Err(e) = drainer.drain(device).await {
+            outcome::warn(format!("failed to drain {}: {:?}", device.id, e));
+        }
+        tagged
+    })
+    .await;
+    if shutdown::requested() {
+        println!("Not draining or destroying the remaining devices: stopping");
+    }
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Destroy the devices that are idle or that have exceeded the urgent termination date. Devices
+/// created after `young_after` are only destroyed if urgent. Devices that fail to be destroyed
+/// are skipped.
+pub async fn destroy_idle(
+    provider: &impl CloudProvider,
+    jobs: &impl JobCounter,
+    to_delete: &[Device],
+    urgent: UrgentTermination,
+    young_after: OffsetDateTime,
+) -> Result<Vec<Device>> {
+    let mut to_destroy = vec![];
+    let mut urgently_destroyed = 0;
+    for device in to_delete.iter() {
+        if shutdown::requested() {
+            break;
+        }
+
+        let is_urgent = device.created_at < urgent.before
+            && if !urgent.allowed {
+                println!(
+                    "WARNING: {} has exceeded the urgent termination date, but the config doesn't \
+                     set allow_urgent_termination: only destroying it once it's idle",
+                    device.id
+                );
+                false
+            } else if urgent.max.is_some_and(|max| urgently_destroyed >= max) {
+                println!(
+                    "WARNING: {} has exceeded the urgent termination date, but {} devices were \
+                     already destroyed regardless of their jobs this run: only destroying it once \
+                     it's idle",
+                    device.id, urgently_destroyed
+                );
+                false
+            } else {
+                true
+            };
+
+        let running = if is_urgent {
+            audit::explain(&device.id, "urgent");
+            jobs.current_jobs(device).await.unwrap_or_else(|e| {
+                println!("WARNING: failed to count {}'s jobs: {:?}", device.id, e);
+                None
+            })
+        } else if device.created_at > young_after {
+            println!(
+                "Not destroying {} yet: it hasn't reached the minimum device lifetime",
+                device.id
+            );
+            continue;
+        } else {
+            match jobs.current_jobs(device).await {
+                Ok(running) => running,
+                Err(e) => {
+                    outcome::warn(format!(
+                        "not destroying {}: failed to count its jobs: {:?}",
+                        device.id, e
+                    ));
+                    continue;
+                }
+            }
+        };
+        audit::observe_jobs(&device.id, running);
+
+        if is_urgent || running == Some(0) {
+            if device.state != DeviceState::Active {
+                println!("Would destroy but it isn't active ({:?})", device.state);
+            } else {
+                if is_urgent {
+                    let abandoned = running
+                        .map_or("an unknown number of".into(), |running| running.to_string());
+                    println!(
+                        "WARNING: destroying {} regardless of its jobs, abandoning {} in progress \
+                         jobs: it has exceeded the urgent termination date",
+                        device.id, abandoned
+                    );
+                    urgently_destroyed += 1;
+                }
+                to_destroy.push(device);
+            }
+        }
+    }
+
+    let results = bounded(to_destroy, |device| async move {
+        if shutdown::requested() {
+            return None;
+        }
+
+        println!("Destroying {}...", device.id);
+        match provider.destroy_device(device).await {
+            Ok(()) => Some(device.clone()),
+            Err(e) => {
+                outcome::warn(format!("failed to destroy {}: {:?}", device.id, e));
+                None
+            }
+        }
+    })
+    .await;
+    if shutdown::requested() {
+        println!("Not destroying the remaining devices: stopping");
+    }
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+pub mod reconcile_tests {
+    use super::*;
+    use crate::machine_type::JobSize;
+    use crate::provider::mock::{desired, device_for, MockProvider};
+
+    const DRAIN_TAG: &str = "skip-hydra";
+
+    fn cutoffs() -> (OffsetDateTime, UrgentTermination) {
+        let older_than = OffsetDateTime::now_utc() - time::Duration::DAY;
+        (
+            older_than,
+            UrgentTermination {
+                before: older_than - time::Duration::DAY,
+                allowed: true,
+                max: None,
+            },
+        )
+    }
+
+    /// No minimum device lifetime.
+    fn young_after() -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+
+    #[tokio::test]
+    async fn test_no_creates_when_satisfied() {
+        let (older_than, urgently_terminate) = cutoffs();
+        let wanted = vec![desired("c3.small.x86"), desired("m3.large.x86")];
+        let provider = MockProvider::new(
+            wanted
+                .iter()
+                .enumerate()
+                .map(|(i, desired)| device_for(&i.to_string(), desired, OffsetDateTime::now_utc()))
+                .collect(),
+        );
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let plan = reconcile(
+            devices,
+            wanted,
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        create(
+            &provider,
+            &plan.to_create,
+            &mut CircuitBreaker::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        teardown(
+            &provider,
+            &provider,
+            &provider,
+            &plan.to_delete,
+            DRAIN_TAG,
+            urgently_terminate,
+            young_after(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(plan.to_keep.len(), 2);
+        assert_eq!(
+            plan.decisions["0"],
+            Decision::Matched("x86_64-linux/Small".into())
+        );
+        assert!(provider.created.lock().unwrap().is_empty());
+        assert!(provider.tagged.lock().unwrap().is_empty());
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plan_class_case_is_ignored() {
+        let (older_than, _) = cutoffs();
+        let wanted = desired("c3.large.arm64");
+        let mut device = device_for("upper", &wanted, OffsetDateTime::now_utc());
+        device
+            .tags
+            .retain(|tag| !tag.starts_with(hardware::CATEGORY_HASH_TAG_PREFIX));
+        device.plan.class = " C3.large.ARM64".into();
+
+        let plan = reconcile(
+            vec![device],
+            vec![wanted],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.to_keep.len(), 1);
+        assert!(plan.to_create.is_empty());
+    }
+
+    #[test]
+    fn test_equivalent_plan_classes_are_kept() {
+        let (older_than, _) = cutoffs();
+        let old = desired("c3.large.arm64");
+        let created_at = OffsetDateTime::now_utc();
+        let mut untagged = device_for("untagged", &old, created_at);
+        untagged
+            .tags
+            .retain(|tag| !tag.starts_with(hardware::CATEGORY_HASH_TAG_PREFIX));
+        untagged.plan.class = "c4.large.arm64".into();
+
+        // The successor is now created first, and devices tagged as the old class still match.
+        let mut wanted = desired("c4.large.arm64");
+        wanted.plan.netboot_url = old.plan.netboot_url.clone();
+        wanted.plan.equivalent_classes = vec!["c3.large.arm64".into()];
+        let plan = reconcile(
+            vec![device_for("tagged", &old, created_at), untagged],
+            vec![wanted.clone(), wanted],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.to_keep.len(), 2);
+        assert!(plan.to_create.is_empty());
+    }
+
+    #[test]
+    fn test_devices_in_another_project_are_replaced() {
+        let (older_than, _) = cutoffs();
+        let wanted = DesiredDevice {
+            project_id: Some("new".into()),
+            ..desired("c3.small.x86")
+        };
+        let created_at = older_than + time::Duration::HOUR;
+        let mut moved = device_for("moved", &wanted, created_at);
+        moved.project_id = "old".into();
+
+        let plan = reconcile(
+            vec![moved, device_for("stayed", &wanted, created_at)],
+            vec![wanted.clone(), wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.to_keep[0].id, "stayed");
+        assert_eq!(plan.to_delete[0].id, "moved");
+        assert_eq!((plan.to_keep.len(), plan.to_delete.len()), (1, 1));
+        assert_eq!(plan.to_create.len(), 1);
+        assert!(plan.drifted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unfilled_categories() {
+        let (older_than, _) = cutoffs();
+        let wanted = desired("c3.small.x86");
+        let created_at = older_than + time::Duration::HOUR;
+        let mut queued = device_for("queued", &wanted, created_at);
+        queued.state = DeviceState::Queued;
+        let mut provider =
+            MockProvider::new(vec![device_for("active", &wanted, created_at), queued]);
+        provider.failing_plans.insert("c3.small.x86".into());
+
+        let plan = reconcile(
+            provider.list_devices(None).await.unwrap(),
+            vec![wanted.clone(); 4],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        let created = create(
+            &provider,
+            &plan.to_create,
+            &mut CircuitBreaker::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(created.is_empty());
+        assert_eq!(
+            unfilled(&plan, &created),
+            BTreeMap::from([(
+                "x86_64-linux/Small".to_string(),
+                Unfilled {
+                    wanted: 4,
+                    filled: 1
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_missing_ipxe_url() {
+        let (older_than, _) = cutoffs();
+        let wanted = desired("c3.small.x86");
+        let mut device = device_for("manual", &wanted, older_than + time::Duration::HOUR);
+        device.ipxe_script_url = None;
+
+        let reconcile_with = |missing_ipxe_url| {
+            reconcile(
+                vec![device.clone()],
+                vec![wanted.clone()],
+                DRAIN_TAG,
+                older_than,
+                young_after(),
+                &HashMap::new(),
+                missing_ipxe_url,
+            )
+        };
+        let replaced = reconcile_with(MissingIpxeUrl::Replace);
+        assert_eq!(replaced.to_delete.len(), 1);
+        assert_eq!(replaced.to_create.len(), 1);
+        let kept = reconcile_with(MissingIpxeUrl::Keep);
+        assert_eq!(kept.to_keep.len(), 1);
+        assert!(kept.to_create.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_creates_only_the_shortfall() {
+        let (older_than, _) = cutoffs();
+        let wanted = vec![
+            desired("c3.small.x86"),
+            desired("c3.small.x86"),
+            desired("c3.small.x86"),
+        ];
+        let provider = MockProvider::new(vec![device_for(
+            "existing",
+            &wanted[0],
+            OffsetDateTime::now_utc(),
+        )]);
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let plan = reconcile(
+            devices,
+            wanted,
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        create(
+            &provider,
+            &plan.to_create,
+            &mut CircuitBreaker::default(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(provider.created.lock().unwrap().len(), 2);
+        assert_eq!(provider.devices.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_young_devices_are_kept() {
+        let (older_than, urgently_terminate) = cutoffs();
+        let young_after = OffsetDateTime::now_utc() - time::Duration::HOUR;
+        let unwanted = desired("c3.small.x86");
+        let mut drained = device_for("drained", &unwanted, OffsetDateTime::now_utc());
+        drained.tags.push(DRAIN_TAG.into());
+        let provider = MockProvider::new(vec![
+            device_for("young", &unwanted, OffsetDateTime::now_utc()),
+            device_for("grown", &unwanted, young_after - time::Duration::HOUR),
+            drained,
+        ]);
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let plan = reconcile(
+            devices,
+            vec![],
+            DRAIN_TAG,
+            older_than,
+            young_after,
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        let kept: Vec<&str> = plan.to_keep.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(kept, vec!["young"]);
+        assert_eq!(
+            plan.decisions,
+            BTreeMap::from([
+                ("drained".to_string(), Decision::Draining),
+                ("grown".to_string(), Decision::Unwanted),
+                ("young".to_string(), Decision::Young),
+            ])
+        );
+
+        teardown(
+            &provider,
+            &provider,
+            &provider,
+            &plan.to_delete,
+            DRAIN_TAG,
+            urgently_terminate,
+            young_after,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *provider.destroyed.lock().unwrap(),
+            vec!["grown".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_spot_market_requests_are_skipped() {
+        let failing = desired("c3.small.x86");
+        let working = DesiredDevice {
+            size: JobSize::BigParallel,
+            ..desired("m3.large.x86")
+        };
+        let mut provider = MockProvider::new(vec![]);
+        provider.failing_plans.insert("c3.small.x86".into());
+
+        let created = create(
+            &provider,
+            &[failing.clone(), failing, working.clone(), working],
+            &mut CircuitBreaker::default(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(*provider.requests.lock().unwrap(), 2);
+        assert_eq!(created.len(), 2);
+        assert!(created
+            .iter()
+            .all(|device| device.plan.class == "m3.large.x86"));
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_fallback_and_replacement() {
+        let (older_than, _) = cutoffs();
+        let mut wanted = desired("c3.small.x86");
+        wanted.allow_on_demand = true;
+        wanted.ssh_key_ids = vec!["key".into()];
+        let mut provider = MockProvider::new(vec![]);
+        provider.failing_plans.insert("c3.small.x86".into());
+
+        let created = create(
+            &provider,
+            &[wanted.clone()],
+            &mut CircuitBreaker::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].device_type, DeviceType::OnDemand);
+        let on_demand = provider.created.lock().unwrap()[0].clone();
+        assert!(on_demand.on_demand);
+        assert_eq!(on_demand.ssh_key_ids, vec!["key".to_string()]);
+
+        // While spot capacity is still missing, the on-demand device stays.
+        let devices = provider.list_devices(None).await.unwrap();
+        let mut plan = reconcile(
+            devices,
+            vec![wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert!(plan.to_create.is_empty());
+        let breaker = CircuitBreaker::default();
+        replace_on_demand(&provider, &mut plan, &breaker)
+            .await
+            .unwrap();
+        assert_eq!(plan.to_keep.len(), 1);
+        assert!(plan.to_delete.is_empty());
+
+        provider.failing_plans.clear();
+        replace_on_demand(&provider, &mut plan, &breaker)
+            .await
+            .unwrap();
+        assert!(plan.to_keep.is_empty());
+        assert_eq!(plan.to_delete[0].device_type, DeviceType::OnDemand);
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_replacements_are_bounded() {
+        let (older_than, _) = cutoffs();
+        let mut wanted = desired("c3.small.x86");
+        wanted.allow_on_demand = true;
+        let provider = MockProvider::new(
+            ["a", "b"]
+                .into_iter()
+                .map(|id| Device {
+                    device_type: DeviceType::OnDemand,
+                    spot_instance: false,
+                    ..device_for(id, &wanted, OffsetDateTime::now_utc())
+                })
+                .collect(),
+        );
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let mut plan = reconcile(
+            devices,
+            vec![wanted.clone(), wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.on_demand.len(), 2);
+
+        // Halted creates aren't bypassed.
+        let category = hardware::category_name(&wanted.system, &wanted.size);
+        let mut breaker = CircuitBreaker {
+            threshold: 1,
+            cooldown: time::Duration::HOUR,
+            failures: HashMap::new(),
+        };
+        breaker.record(&category, false, OffsetDateTime::now_utc());
+        let created = replace_on_demand(&provider, &mut plan, &breaker)
+            .await
+            .unwrap();
+        assert!(created.is_empty());
+        assert_eq!(plan.on_demand.len(), 2);
+
+        // Once they aren't, one is replaced per run.
+        let created = replace_on_demand(&provider, &mut plan, &CircuitBreaker::default())
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(plan.on_demand.len(), 1);
+        assert_eq!(plan.to_delete.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_drifted_devices_are_reinstalled() {
+        let (older_than, _) = cutoffs();
+        let mut old_netboot = desired("c3.small.x86");
+        old_netboot.plan.netboot_url = "https://netboot.example/old".into();
+        let mut provider = MockProvider::new(vec![
+            device_for(
+                "idle",
+                &old_netboot,
+                OffsetDateTime::now_utc() - time::Duration::HOUR,
+            ),
+            device_for(
+                "busy",
+                &old_netboot,
+                OffsetDateTime::now_utc() - time::Duration::HOUR,
+            ),
+        ]);
+        provider.jobs.insert("busy".into(), 2);
+        let wanted = vec![desired("c3.small.x86"), desired("c3.small.x86")];
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let mut plan = reconcile(
+            devices,
+            wanted,
+            DRAIN_TAG,
+            older_than,
+            OffsetDateTime::now_utc() - time::Duration::MINUTE,
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.drifted.len(), 2);
+
+        reinstall_drifted(&provider, &provider, &mut plan)
+            .await
+            .unwrap();
+        assert_eq!(
+            *provider.reinstalled.lock().unwrap(),
+            vec!["idle".to_string()]
+        );
+        assert_eq!(plan.to_keep[0].id, "idle");
+        assert_eq!(
+            plan.to_keep[0].ipxe_script_url,
+            desired("c3.small.x86").plan.ipxe_script_url()
+        );
+        assert_eq!(plan.to_delete[0].id, "busy");
+        assert_eq!(plan.to_create.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_idle_devices_are_rebooted_once() {
+        let (older_than, _) = cutoffs();
+        let wanted = desired("c3.small.x86");
+        let created_at = OffsetDateTime::now_utc() - time::Duration::HOUR;
+        let mut rebooted_before = device_for("rebooted-before", &wanted, created_at);
+        rebooted_before.tags.push(device::REBOOTED_TAG.into());
+        let mut provider = MockProvider::new(vec![
+            device_for("wedged", &wanted, created_at),
+            device_for("recently-idle", &wanted, created_at),
+            device_for("busy", &wanted, created_at),
+            rebooted_before,
+        ]);
+        provider.jobs.insert("busy".into(), 1);
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let mut plan = reconcile(
+            devices,
+            vec![],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        let rebooted = reboot_idle(&provider, &provider, &mut plan, DRAIN_TAG, |device| {
+            device.id != "recently-idle"
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *provider.rebooted.lock().unwrap(),
+            vec!["wedged".to_string()]
+        );
+        assert!(rebooted[0].tags.contains(&device::REBOOTED_TAG.to_string()));
+        assert_eq!(
+            plan.to_keep
+                .iter()
+                .map(|device| device.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["wedged"]
+        );
+        assert_eq!(plan.to_delete.len(), 3);
+    }
+
+    #[test]
+    fn test_diff() {
+        let (older_than, _) = cutoffs();
+        let young_after = OffsetDateTime::now_utc() - time::Duration::MINUTE;
+        let mut old_netboot = desired("c3.small.x86");
+        old_netboot.plan.netboot_url = "https://netboot.example/old".into();
+        let mut draining = device_for("draining", &desired("c3.small.x86"), older_than);
+        draining.tags.push(DRAIN_TAG.into());
+        let devices = vec![
+            device_for("drifted", &old_netboot, young_after - time::Duration::HOUR),
+            device_for(
+                "old",
+                &desired("m3.large.x86"),
+                older_than - time::Duration::HOUR,
+            ),
+            draining,
+        ];
+        let wanted = vec![
+            desired("c3.small.x86"),
+            desired("c3.small.x86"),
+            desired("c3.small.x86"),
+        ];
+
+        let plan = reconcile(
+            devices,
+            wanted,
+            DRAIN_TAG,
+            older_than,
+            young_after,
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        let diff = Diff::new(&plan, DRAIN_TAG, older_than);
+        assert_eq!(
+            (diff.create, diff.replace, diff.destroy, diff.keep),
+            (2, 1, 2, 0)
+        );
+        assert_eq!(
+            diff.to_text(),
+            "~ replace 1x c3.small.x86 (netboot changed)\n\
+             + create 2x c3.small.x86 (da)\n\
+             - destroy 1x c3.small.x86 (draining)\n\
+             - destroy 1x m3.large.x86 (too old)\n\
+             Plan: 2 to create, 1 to replace, 2 to destroy, 0 to keep."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_pool_is_kept_without_demand() {
+        let wanted = desired("c3.small.x86");
+        let categories = serde_json::from_value(serde_json::json!({
+            "x86_64-linux": {
+                "small": { "divisor": 10, "minimum": 2, "maximum": 4, "plans": [{
+                    "bid": 1.0,
+                    "plan": "c3.small.x86",
+                    "netboot_url": "https://netboot.example/c3.small.x86",
+                }] }
+            }
+        }))
+        .unwrap();
+        let status = serde_json::from_value(serde_json::json!({ "machineTypes": {} })).unwrap();
+        let pool = || {
+            hardware::compute_desired(
+                &hardware::bucket_runnable(&status),
+                &categories,
+                &wanted.tags,
+                &wanted.metros,
+            )
+        };
+        let minimums = HashMap::from([(hardware::category_name(&wanted.system, &wanted.size), 2)]);
+        let provider = MockProvider::new(vec![]);
+        let run = |older_than: OffsetDateTime| {
+            let provider = &provider;
+            let pool = pool();
+            let minimums = &minimums;
+            async move {
+                let plan = reconcile(
+                    provider.list_devices(None).await.unwrap(),
+                    pool,
+                    DRAIN_TAG,
+                    older_than,
+                    young_after(),
+                    minimums,
+                    MissingIpxeUrl::Replace,
+                );
+                create(
+                    provider,
+                    &plan.to_create,
+                    &mut CircuitBreaker::default(),
+                    false,
+                )
+                .await
+                .unwrap();
+                teardown(
+                    provider,
+                    provider,
+                    provider,
+                    &plan.to_delete,
+                    DRAIN_TAG,
+                    UrgentTermination {
+                        before: older_than - time::Duration::DAY,
+                        allowed: true,
+                        max: None,
+                    },
+                    young_after(),
+                )
+                .await
+                .unwrap();
+                plan
+            }
+        };
+
+        // The pool is created with nothing queued, and then left alone.
+        let (older_than, _) = cutoffs();
+        let plan = run(older_than).await;
+        assert_eq!(plan.to_create.len(), 2);
+        let plan = run(older_than).await;
+        assert_eq!((plan.to_keep.len(), plan.to_create.len()), (2, 0));
+
+        // Once it's due to be cycled out, it stays until its replacements are active.
+        let plan = run(OffsetDateTime::now_utc() + time::Duration::HOUR).await;
+        assert_eq!((plan.deferred, plan.to_create.len()), (2, 2));
+        assert!(plan.to_delete.is_empty());
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+        assert_eq!(provider.devices.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_failing_plan_falls_back() {
+        let mut provider = MockProvider::new(vec![]);
+        provider.failing_plans.insert("c3.small.x86".into());
+        let wanted = vec![
+            desired("c3.small.x86"),
+            desired("m3.large.x86"),
+            desired("c3.small.x86"),
+            desired("c3.small.x86"),
+        ];
+
+        let created = create(&provider, &wanted, &mut CircuitBreaker::default(), false)
+            .await
+            .unwrap();
+
+        // The three c3.small.x86s are tried together, then retried as m3.large.x86s.
+        let plans: Vec<&str> = created
+            .iter()
+            .map(|device| device.plan.class.as_str())
+            .collect();
+        assert_eq!(plans, vec!["m3.large.x86"; 4]);
+        assert_eq!(provider.created.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_cycling_keeps_the_minimum() {
+        let (older_than, _) = cutoffs();
+        let wanted = desired("c3.small.x86");
+        let old = || device_for("old", &wanted, older_than - time::Duration::HOUR);
+        let minimums = HashMap::from([(hardware::category_name(&wanted.system, &wanted.size), 1)]);
+
+        // Until the replacement is active, the old device stays.
+        let mut replacement = device_for("new", &wanted, OffsetDateTime::now_utc());
+        replacement.state = DeviceState::Provisioning;
+        let plan = reconcile(
+            vec![old(), replacement.clone()],
+            vec![wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &minimums,
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.to_keep.len(), 2);
+        assert_eq!(plan.deferred, 1);
+        assert!(plan.to_delete.is_empty());
+
+        replacement.state = DeviceState::Active;
+        let plan = reconcile(
+            vec![old(), replacement],
+            vec![wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &minimums,
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.deferred, 0);
+        assert_eq!(plan.to_delete[0].id, "old");
+    }
+
+    #[tokio::test]
+    async fn test_busy_devices_are_drained_not_destroyed() {
+        let (older_than, urgently_terminate) = cutoffs();
+        let old = desired("c3.small.x86");
+        let mut provider = MockProvider::new(vec![device_for(
+            "old",
+            &old,
+            older_than - time::Duration::HOUR,
+        )]);
+        provider.jobs.insert("old".into(), 3);
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let plan = reconcile(
+            devices,
+            vec![],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        teardown(
+            &provider,
+            &provider,
+            &provider,
+            &plan.to_delete,
+            DRAIN_TAG,
+            urgently_terminate,
+            young_after(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(provider.tagged.lock().unwrap().len(), 1);
+        assert_eq!(*provider.drained.lock().unwrap(), vec!["old".to_string()]);
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_urgent_terminations_are_capped_and_opt_in() {
+        let (_, mut urgent) = cutoffs();
+        let old = desired("c3.small.x86");
+        let ancient = urgent.before - time::Duration::HOUR;
+        let mut provider = MockProvider::new(vec![
+            device_for("a", &old, ancient),
+            device_for("b", &old, ancient),
+        ]);
+        provider.jobs.insert("a".into(), 3);
+        provider.jobs.insert("b".into(), 3);
+        let devices = provider.list_devices(None).await.unwrap();
+
+        urgent.allowed = false;
+        destroy_idle(&provider, &provider, &devices, urgent, young_after())
+            .await
+            .unwrap();
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+
+        urgent.allowed = true;
+        urgent.max = Some(1);
+        let destroyed = destroy_idle(&provider, &provider, &devices, urgent, young_after())
+            .await
+            .unwrap();
+        assert_eq!(destroyed.len(), 1);
+        assert_eq!(*provider.destroyed.lock().unwrap(), vec!["a".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_uncountable_devices_are_skipped() {
+        let (older_than, urgent) = cutoffs();
+        let old = desired("c3.small.x86");
+        let created_at = older_than - time::Duration::HOUR;
+        let mut provider = MockProvider::new(vec![
+            device_for("a", &old, created_at),
+            device_for("b", &old, created_at),
+        ]);
+        provider.failing_jobs.insert("a".into());
+        let devices = provider.list_devices(None).await.unwrap();
+
+        let destroyed = destroy_idle(&provider, &provider, &devices, urgent, young_after())
+            .await
+            .unwrap();
+        assert_eq!(destroyed.len(), 1);
+        assert_eq!(*provider.destroyed.lock().unwrap(), vec!["b".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_converges_after_crashing_before_tagging() {
+        let (older_than, _) = cutoffs();
+        let template = "{plan}-{metro}-{short_random}";
+        let wanted = desired("c3.small.x86");
+        let provider = MockProvider::new(vec![]);
+        create(
+            &provider,
+            std::slice::from_ref(&wanted),
+            &mut CircuitBreaker::default(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        // The crash: the device exists, but without our tags.
+        {
+            let mut devices = provider.devices.lock().unwrap();
+            devices[0].tags.clear();
+            devices[0].hostname =
+                device::render_hostname(template, &wanted, "da", OffsetDateTime::now_utc());
+        }
+        let mut handmade = device_for("handmade", &wanted, OffsetDateTime::now_utc());
+        handmade.tags.clear();
+        handmade.hostname = "my-builder".into();
+        provider.devices.lock().unwrap().push(handmade);
+
+        let unmanaged = provider.list_devices(None).await.unwrap();
+        let to_adopt = adoptable(
+            unmanaged,
+            template,
+            &BTreeSet::from([wanted.plan.plan.clone()]),
+            &BTreeSet::from(["da".to_string()]),
+            &BTreeSet::new(),
+            &wanted.tags,
+        );
+        assert_eq!(to_adopt.len(), 1);
+        adopt(&provider, &to_adopt).await.unwrap();
+
+        let plan = reconcile(
+            to_adopt,
+            vec![wanted.clone()],
+            DRAIN_TAG,
+            older_than,
+            young_after(),
+            &HashMap::new(),
+            MissingIpxeUrl::Replace,
+        );
+        assert_eq!(plan.to_keep.len(), 1);
+        assert!(plan.to_create.is_empty());
+        let devices = provider.devices.lock().unwrap();
+        assert!(devices[0].tags.contains(&"hydra".to_string()));
+        assert!(devices[1].tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_foreign_devices_are_not_adopted() {
+        let template = "{plan}-{metro}-{short_random}";
+        let ours = desired("c3.small.x86");
+        let now = OffsetDateTime::now_utc();
+        // Another deployment sharing the project and hostname template, with its own tags.
+        let mut tagged = device_for("tagged", &ours, now);
+        tagged.tags = vec!["staging".into()];
+        tagged.hostname = device::render_hostname(template, &ours, "da", now);
+        // Or with no tags, but a plan and metro we never create.
+        let theirs = desired("m3.large.x86");
+        let mut untagged = device_for("untagged", &theirs, now);
+        untagged.tags.clear();
+        untagged.hostname = device::render_hostname(template, &theirs, "sv", now);
+        let provider = MockProvider::new(vec![tagged, untagged]);
+
+        let unmanaged = provider.list_devices(None).await.unwrap();
+        let to_adopt = adoptable(
+            unmanaged,
+            template,
+            &BTreeSet::from([ours.plan.plan.clone()]),
+            &BTreeSet::from(["da".to_string()]),
+            &BTreeSet::new(),
+            &ours.tags,
+        );
+        assert!(to_adopt.is_empty());
+        adopt(&provider, &to_adopt).await.unwrap();
+        assert!(provider.tagged.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_converges_after_crashing_mid_teardown() {
+        let (older_than, urgent) = cutoffs();
+        let mut busy = device_for("busy", &desired("c3.small.x86"), young_after());
+        busy.tags.push(DRAIN_TAG.into());
+        let mut provider = MockProvider::new(vec![busy]);
+        provider.jobs.insert("busy".into(), 2);
+
+        let run = |provider: &MockProvider| {
+            let devices = provider.devices.lock().unwrap().clone();
+            reconcile(
+                devices,
+                vec![],
+                DRAIN_TAG,
+                older_than,
+                young_after(),
+                &HashMap::new(),
+                MissingIpxeUrl::Replace,
+            )
+        };
+
+        // The crash left it drain-tagged but running jobs, so the next run leaves it be...
+        let plan = run(&provider);
+        teardown(
+            &provider,
+            &provider,
+            &provider,
+            &plan.to_delete,
+            DRAIN_TAG,
+            urgent,
+            young_after(),
+        )
+        .await
+        .unwrap();
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+        assert!(provider.tagged.lock().unwrap().is_empty());
+
+        // ...and the one after it's idle destroys it.
+        provider.jobs.insert("busy".into(), 0);
+        let plan = run(&provider);
+        assert_eq!(plan.decisions["busy"], Decision::Draining);
+        teardown(
+            &provider,
+            &provider,
+            &provider,
+            &plan.to_delete,
+            DRAIN_TAG,
+            urgent,
+            young_after(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *provider.destroyed.lock().unwrap(),
+            vec!["busy".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bounded_concurrency() {
+        let in_flight = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let outputs = bounded_by(3, 0..10, |i| {
+            let (in_flight, most) = (&in_flight, &most);
+            async move {
+                most.fetch_max(
+                    in_flight.fetch_add(1, Ordering::SeqCst) + 1,
+                    Ordering::SeqCst,
+                );
+                tokio::task::yield_now().await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        })
+        .await;
+
+        assert_eq!(outputs, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_halts_creates() {
+        let wanted = vec![desired("c3.small.x86"); 5];
+        let category = hardware::category_name(&wanted[0].system, &wanted[0].size);
+        let mut provider = MockProvider::new(vec![]);
+        provider.failing_plans.insert("c3.small.x86".into());
+        let mut breaker = CircuitBreaker {
+            threshold: 3,
+            cooldown: time::Duration::HOUR,
+            failures: HashMap::new(),
+        };
+
+        create(&provider, &wanted, &mut breaker, false)
+            .await
+            .unwrap();
+        let record = breaker.failures[&category].clone();
+        assert_eq!(record.consecutive, 5);
+        assert!(record.halted_at.is_some());
+
+        // Halted, so nothing is attempted.
+        create(&provider, &wanted, &mut breaker, false)
+            .await
+            .unwrap();
+        assert_eq!(breaker.failures[&category], record);
+
+        // After the cooldown, one create is tried, and the rest follow once it works.
+        provider.failing_plans.clear();
+        breaker.failures.get_mut(&category).unwrap().halted_at =
+            Some(OffsetDateTime::now_utc() - time::Duration::HOUR);
+        let created = create(&provider, &wanted, &mut breaker, false)
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 5);
+        assert!(breaker.failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_halts_spot_market_requests() {
+        let wanted = vec![desired("c3.small.x86"); 2];
+        let category = hardware::category_name(&wanted[0].system, &wanted[0].size);
+        let mut provider = MockProvider::new(vec![]);
+        provider.failing_plans.insert("c3.small.x86".into());
+        let mut breaker = CircuitBreaker {
+            threshold: 3,
+            cooldown: time::Duration::HOUR,
+            failures: HashMap::new(),
+        };
+
+        // A failed request counts as a failed create for each device it asked for.
+        create(&provider, &wanted, &mut breaker, true)
+            .await
+            .unwrap();
+        assert_eq!(breaker.failures[&category].consecutive, 2);
+        assert!(breaker.failures[&category].halted_at.is_none());
+        create(&provider, &wanted, &mut breaker, true)
+            .await
+            .unwrap();
+        assert!(breaker.failures[&category].halted_at.is_some());
+        assert_eq!(*provider.requests.lock().unwrap(), 2);
+
+        create(&provider, &wanted, &mut breaker, true)
+            .await
+            .unwrap();
+        assert_eq!(*provider.requests.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_circuit_breaker_reopens_after_failed_retry() {
+        let now = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let mut breaker = CircuitBreaker {
+            threshold: 2,
+            cooldown: time::Duration::HOUR,
+            failures: HashMap::new(),
+        };
+
+        assert!(!breaker.record("c", false, now));
+        assert!(breaker.record("c", false, now));
+        assert_eq!(
+            breaker.state("c", now),
+            Breaker::Open(now + time::Duration::HOUR)
+        );
+
+        let later = now + time::Duration::HOUR;
+        assert_eq!(breaker.state("c", later), Breaker::HalfOpen);
+        assert!(breaker.record("c", false, later));
+        assert_eq!(
+            breaker.state("c", later),
+            Breaker::Open(later + time::Duration::HOUR)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_never_destroys() {
+        let (older_than, _) = cutoffs();
+        let provider = MockProvider::new(vec![device_for(
+            "idle",
+            &desired("c3.small.x86"),
+            older_than - time::Duration::HOUR,
+        )]);
+
+        let devices = provider.list_devices(None).await.unwrap();
+        let drained = drain(&provider, &provider, &devices, DRAIN_TAG)
+            .await
+            .unwrap();
+        assert_eq!(drained.len(), 1);
+        assert_eq!(*provider.drained.lock().unwrap(), vec!["idle".to_string()]);
+        assert!(provider.destroyed.lock().unwrap().is_empty());
+
+        // Already tagged devices are told to drain again, but not retagged.
+        let devices = provider.list_devices(None).await.unwrap();
+        assert!(drain(&provider, &provider, &devices, DRAIN_TAG)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(provider.tagged.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,172 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+use time::format_description::FormatItem;
+use time::{OffsetDateTime, Time, UtcOffset, Weekday};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+/// A recurring stretch of time, like weekdays from 09:00 to 17:00 in Europe/Berlin, or at
+/// UTC+02:00. A `utc_offset` is fixed, so a window using one in a zone with daylight saving time
+/// shifts by an hour twice a year; a `timezone` follows the zone's clocks.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct TimeWindow {
+    /// The days the window starts on, or every day if empty.
+    #[serde(default)]
+    pub days: Vec<Day>,
+    /// Like `09:00`.
+    #[serde(deserialize_with = "deserialize_time")]
+    #[schemars(with = "String")]
+    pub start: Time,
+    /// If this is before `start`, the window runs past midnight into the next day.
+    #[serde(deserialize_with = "deserialize_time")]
+    #[schemars(with = "String")]
+    pub end: Time,
+    /// An IANA time zone, like `Europe/Berlin`, used instead of `utc_offset`.
+    #[serde(default, deserialize_with = "deserialize_timezone")]
+    #[schemars(with = "Option<String>")]
+    pub timezone: Option<&'static Tz>,
+    /// Like `+02:00`, for when there's no `timezone`.
+    #[serde(default = "utc", deserialize_with = "deserialize_offset")]
+    #[schemars(with = "String", default = "utc_str")]
+    pub utc_offset: UtcOffset,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Weekday> for Day {
+    fn from(weekday: Weekday) -> Self {
+        match weekday {
+            Weekday::Monday => Day::Mon,
+            Weekday::Tuesday => Day::Tue,
+            Weekday::Wednesday => Day::Wed,
+            Weekday::Thursday => Day::Thu,
+            Weekday::Friday => Day::Fri,
+            Weekday::Saturday => Day::Sat,
+            Weekday::Sunday => Day::Sun,
+        }
+    }
+}
+
+impl TimeWindow {
+    pub fn contains(&self, now: OffsetDateTime) -> bool {
+        let local = match self.timezone {
+            Some(timezone) => now.to_timezone(timezone),
+            None => now.to_offset(self.utc_offset),
+        };
+        let starts_on =
+            |weekday: Weekday| self.days.is_empty() || self.days.contains(&weekday.into());
+
+        if self.start <= self.end {
+            starts_on(local.weekday()) && self.start <= local.time() && local.time() < self.end
+        } else {
+            // Past midnight, the window belongs to the day it started on.
+            (starts_on(local.weekday()) && self.start <= local.time())
+                || (starts_on(local.weekday().previous()) && local.time() < self.end)
+        }
+    }
+}
+
+fn utc() -> UtcOffset {
+    UtcOffset::UTC
+}
+
+fn utc_str() -> &'static str {
+    "+00:00"
+}
+
+const TIME_FORMAT: &[FormatItem<'static>] = time::macros::format_description!("[hour]:[minute]");
+const OFFSET_FORMAT: &[FormatItem<'static>] =
+    time::macros::format_description!("[offset_hour sign:mandatory]:[offset_minute]");
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Time, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Time::parse(&value, TIME_FORMAT).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UtcOffset, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    UtcOffset::parse(&value, OFFSET_FORMAT).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_timezone<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static Tz>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    time_tz::timezones::get_by_name(&value)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown time zone {value:?}")))
+}
+
+#[cfg(test)]
+pub mod schedule_tests {
+    use super::*;
+
+    fn window(json: serde_json::Value) -> TimeWindow {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn at(rfc3339: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(rfc3339, &time::format_description::well_known::Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn test_offset_window() {
+        let window = window(serde_json::json!({
+            "days": ["mon", "tue", "wed", "thu", "fri"],
+            "start": "09:00",
+            "end": "17:00",
+            "utc_offset": "+02:00",
+        }));
+
+        // 2023-11-13 is a Monday.
+        assert!(window.contains(at("2023-11-13T07:00:00Z")));
+        assert!(!window.contains(at("2023-11-13T06:59:00Z")));
+        assert!(!window.contains(at("2023-11-13T15:00:00Z")));
+        assert!(!window.contains(at("2023-11-18T10:00:00Z")));
+    }
+
+    #[test]
+    fn test_timezone_window() {
+        let window = window(serde_json::json!({
+            "start": "09:00",
+            "end": "17:00",
+            "timezone": "Europe/Berlin",
+        }));
+
+        // Berlin is at UTC+01:00 until 2023-03-26, and at UTC+02:00 after.
+        assert!(window.contains(at("2023-03-24T08:00:00Z")));
+        assert!(!window.contains(at("2023-03-24T07:30:00Z")));
+        assert!(window.contains(at("2023-03-27T07:30:00Z")));
+        assert!(!window.contains(at("2023-03-27T15:30:00Z")));
+
+        assert!(serde_json::from_value::<TimeWindow>(serde_json::json!({
+            "start": "09:00",
+            "end": "17:00",
+            "timezone": "Europe/Nowhere",
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_overnight_window() {
+        let window = window(serde_json::json!({
+            "days": ["fri"],
+            "start": "22:00",
+            "end": "02:00",
+        }));
+
+        assert!(window.contains(at("2023-11-17T23:00:00Z")));
+        assert!(window.contains(at("2023-11-18T01:00:00Z")));
+        assert!(!window.contains(at("2023-11-18T23:00:00Z")));
+        assert!(!window.contains(at("2023-11-17T01:00:00Z")));
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use eyre::{eyre, Result};
+use tokio::signal::unix::{signal, SignalKind};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static DEADLINE_PASSED: AtomicBool = AtomicBool::new(false);
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Ask everything checking [`requested`] to stop after the device operation it's in the middle
+/// of.
+pub fn request(why: &str) {
+    if !REQUESTED.swap(true, Ordering::SeqCst) {
+        println!("{why}, stopping after the current device operation");
+    }
+}
+
+/// On SIGINT or SIGTERM, ask everything checking [`requested`] to stop after the device
+/// operation it's in the middle of. A second signal exits immediately. Listening again does
+/// nothing.
+pub fn listen() -> std::io::Result<()> {
+    if LISTENING.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        loop {
+            let name = tokio::select! {
+                _ = interrupt.recv() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            };
+
+            if requested() {
+                println!("Received another {name}, exiting now");
+                std::process::exit(130);
+            }
+            request(&format!("Received {name}"));
+        }
+    });
+
+    Ok(())
+}
+
+/// Stop once `deadline` has passed, as if we'd received a signal.
+pub fn stop_after(deadline: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(deadline).await;
+        DEADLINE_PASSED.store(true, Ordering::SeqCst);
+        request(&format!("The run deadline of {deadline:?} passed"));
+    });
+}
+
+/// Fail if [`stop_after`]'s deadline cut the run short.
+pub fn check_deadline() -> Result<()> {
+    if DEADLINE_PASSED.load(Ordering::SeqCst) {
+        Err(eyre!("the run deadline passed before the run finished"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether a signal or the deadline asked us to stop, so no new device operations should start.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, or until [`requested`]. Returns whether it slept the whole time.
+pub async fn sleep(duration: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + duration;
+    while !requested() {
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        tokio::time::sleep((deadline - now).min(Duration::from_secs(1))).await;
+    }
+
+    false
+}
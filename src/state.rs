@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::device::Device;
+use crate::hardware::RunnableAverages;
+
+/// What the scaler remembers between runs.
+#[derive(Default, Deserialize, Serialize, Debug)]
+pub struct State {
+    /// When the state was last saved.
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub last_run: Option<OffsetDateTime>,
+    /// See [`crate::hardware::smooth_runnable`].
+    #[serde(default)]
+    pub runnable_averages: RunnableAverages,
+    /// What we've seen of each managed device, by ID.
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceRecord>,
+    /// Each category's create failures, by [`crate::hardware::category_name`]. See
+    /// [`crate::reconcile::CircuitBreaker`].
+    #[serde(default)]
+    pub create_failures: HashMap<String, FailureRecord>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FailureRecord {
+    /// How many creates in a row have failed.
+    pub consecutive: usize,
+    /// When creates were halted, if they are.
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub halted_at: Option<OffsetDateTime>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRecord {
+    #[serde(with = "time::serde::iso8601")]
+    pub first_seen: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_seen: OffsetDateTime,
+    /// How many runs in a row the device has been seen running no jobs.
+    #[serde(default)]
+    pub idle_runs: u32,
+}
+
+impl State {
+    /// Read the state from `path`. A missing or unreadable state file is treated like a first
+    /// run, so that a bad state file never stops the scaler.
+    pub fn load(path: &Path) -> Result<State> {
+        let json_str = match std::fs::read_to_string(path) {
+            Ok(json_str) => json_str,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err(e).wrap_err_with(|| format!("failed to read {}", path.display())),
+        };
+
+        match serde_json::from_str(&json_str) {
+            Ok(state) => Ok(state),
+            Err(e) => {
+                println!(
+                    "WARNING: ignoring {}, it couldn't be parsed: {}",
+                    path.display(),
+                    e
+                );
+                Ok(State::default())
+            }
+        }
+    }
+
+    /// Write the state to `path`, replacing it atomically so an interrupted run can't leave it
+    /// half-written.
+    pub fn save(&mut self, path: &Path, now: OffsetDateTime) -> Result<()> {
+        self.last_run = Some(now);
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .wrap_err_with(|| format!("failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .wrap_err_with(|| format!("failed to replace {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Note that `devices` are the managed devices as of `now`, forgetting any others.
+    pub fn observe_devices(&mut self, devices: &[Device], now: OffsetDateTime) {
+        self.devices
+            .retain(|id, _| devices.iter().any(|device| device.id == *id));
+
+        for device in devices.iter() {
+            self.devices
+                .entry(device.id.clone())
+                .and_modify(|record| record.last_seen = now)
+                .or_insert(DeviceRecord {
+                    first_seen: now,
+                    last_seen: now,
+                    idle_runs: 0,
+                });
+        }
+    }
+
+    /// Note how many jobs the device is running, if that's known.
+    pub fn record_jobs(&mut self, device_id: &str, jobs: Option<u64>) {
+        if let (Some(record), Some(jobs)) = (self.devices.get_mut(device_id), jobs) {
+            record.idle_runs = if jobs == 0 { record.idle_runs + 1 } else { 0 };
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod state_tests {
+    use super::*;
+    use crate::provider::mock::{desired, device_for};
+
+    fn device(id: &str) -> Device {
+        let desired = desired("c3.small.x86");
+
+        device_for(id, &desired, OffsetDateTime::now_utc())
+    }
+
+    #[test]
+    fn test_observe_and_prune() {
+        let mut state = State::default();
+        let first = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let second = first + time::Duration::HOUR;
+
+        state.observe_devices(&[device("a"), device("b")], first);
+        state.record_jobs("a", Some(0));
+        state.record_jobs("b", Some(0));
+        state.observe_devices(&[device("a")], second);
+        state.record_jobs("a", Some(0));
+        state.record_jobs("a", None);
+
+        assert_eq!(
+            state.devices,
+            HashMap::from([(
+                "a".to_string(),
+                DeviceRecord {
+                    first_seen: first,
+                    last_seen: second,
+                    idle_runs: 2,
+                }
+            )])
+        );
+
+        state.record_jobs("a", Some(3));
+        assert_eq!(state.devices["a"].idle_runs, 0);
+    }
+
+    #[test]
+    fn test_corrupt_state_is_ignored() {
+        let path = std::env::temp_dir().join(format!("scale-state-{}.json", std::process::id()));
+        std::fs::write(&path, "{ not json").unwrap();
+        let mut state = State::load(&path).unwrap();
+        assert!(state.devices.is_empty());
+
+        state.observe_devices(&[device("a")], OffsetDateTime::now_utc());
+        state.save(&path, OffsetDateTime::now_utc()).unwrap();
+        let loaded = State::load(&path).unwrap();
+        assert!(loaded.devices.contains_key("a"));
+        assert!(loaded.last_run.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}